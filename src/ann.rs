@@ -0,0 +1,99 @@
+use hnsw_rs::prelude::{DistCosine, Hnsw};
+use rusqlite::Connection;
+
+use crate::indexer::blob_to_embedding;
+
+/// Below this many embedded chunks a brute-force scan takes a few tens of milliseconds
+/// and is exact, while building the graph takes minutes, so none is built.
+pub const MIN_CHUNKS: usize = 20_000;
+
+/// Neighbours kept per node and per layer of the graph.
+const MAX_CONNECTIONS: usize = 16;
+const MAX_LAYERS: usize = 16;
+/// Candidates explored while building and searching; higher is slower but finds the
+/// true nearest chunks more often.
+const EF_CONSTRUCTION: usize = 100;
+const EF_SEARCH: usize = 64;
+
+/// Approximate nearest-neighbour graph (HNSW) over the stored chunk embeddings of one
+/// dimension. It is a snapshot: chunks indexed after `build` are not in it until it
+/// is built again.
+pub struct AnnIndex {
+    dim: usize,
+    graph: Hnsw<'static, f32, DistCosine>,
+    /// `documents.id` of every point in the graph, by point id.
+    chunk_ids: Vec<i64>,
+}
+
+impl AnnIndex {
+    /// Build the graph from the embeddings of the most common dimension, or return
+    /// `None` when there are fewer than `MIN_CHUNKS` of them.
+    pub fn build(conn: &Connection) -> rusqlite::Result<Option<AnnIndex>> {
+        let dim: Option<i64> = conn
+            .query_row(
+                "SELECT embedding_dim FROM documents WHERE embedding IS NOT NULL
+                 GROUP BY embedding_dim HAVING COUNT(*) >= ?1
+                 ORDER BY COUNT(*) DESC LIMIT 1",
+                [MIN_CHUNKS as i64],
+                |row| row.get(0),
+            )
+            .map(Some)
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e),
+            })?;
+        let Some(dim) = dim else {
+            return Ok(None);
+        };
+
+        let mut stmt = conn.prepare(
+            "SELECT id, embedding FROM documents
+             WHERE embedding IS NOT NULL AND embedding_dim = ?1",
+        )?;
+        let rows = stmt.query_map([dim], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        let mut chunk_ids = Vec::new();
+        let mut embeddings = Vec::new();
+        for row in rows {
+            let (id, blob) = row?;
+            if let Some(embedding) = blob_to_embedding(&blob, dim as usize) {
+                chunk_ids.push(id);
+                embeddings.push(embedding);
+            }
+        }
+
+        let graph = Hnsw::new(
+            MAX_CONNECTIONS,
+            embeddings.len(),
+            MAX_LAYERS,
+            EF_CONSTRUCTION,
+            DistCosine {},
+        );
+        let points: Vec<(&Vec<f32>, usize)> = embeddings.iter().zip(0..).collect();
+        graph.parallel_insert(&points);
+        Ok(Some(AnnIndex {
+            dim: dim as usize,
+            graph,
+            chunk_ids,
+        }))
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunk_ids.len()
+    }
+
+    /// `documents.id` of (approximately) the `k` chunks closest to `query`, closest
+    /// first. `query` must have `dim()` components.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<i64> {
+        self.graph
+            .search(query, k, EF_SEARCH.max(k))
+            .into_iter()
+            .map(|neighbour| self.chunk_ids[neighbour.d_id])
+            .collect()
+    }
+}
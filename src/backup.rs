@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use age::secrecy::Secret;
+use rusqlite::{Connection, DatabaseName};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::error::AppError;
+
+/// First bytes of a decrypted backup, so a wrong file is told apart from a damaged one.
+const MAGIC: &[u8] = b"indexedRAG backup 1\n";
+
+/// What precedes the database in a backup.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Header {
+    /// Keyring secrets by keyring user name, when the user chose to include them.
+    #[serde(default)]
+    keys: BTreeMap<String, String>,
+}
+
+/// Write a consistent copy of the database behind `conn`, and `keys`, to `path`,
+/// encrypted with `passphrase` (age, scrypt).
+///
+/// Layout before encryption: `MAGIC`, the JSON header's length as a little-endian
+/// u32, the header, then the SQLite file up to the end.
+pub fn export(
+    conn: &Connection,
+    path: &Path,
+    passphrase: &str,
+    keys: BTreeMap<String, String>,
+) -> Result<(), AppError> {
+    let mut snapshot = staging_file(conn)?;
+    // Unlike copying the file, VACUUM INTO can't catch another connection mid-write.
+    // It accepts the staging file as it is still empty.
+    conn.execute(
+        "VACUUM INTO ?1",
+        [snapshot.path().to_string_lossy().into_owned()],
+    )?;
+    let header = serde_json::to_vec(&Header { keys })?;
+    let output = BufWriter::new(File::create(path)?);
+    let mut writer = age::Encryptor::with_user_passphrase(Secret::new(passphrase.to_owned()))
+        .wrap_output(output)
+        .map_err(|e| AppError::Backup(e.to_string()))?;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    writer.write_all(&header)?;
+    io::copy(snapshot.as_file_mut(), &mut writer)?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+/// Decrypt the backup at `path` and replace the contents of the database behind
+/// `conn` with it. Other connections to the same file see the restored data too.
+/// Returns the keyring secrets stored in the backup.
+pub fn restore(
+    conn: &mut Connection,
+    path: &Path,
+    passphrase: &str,
+) -> Result<BTreeMap<String, String>, AppError> {
+    let input = BufReader::new(File::open(path)?);
+    let decryptor = match age::Decryptor::new(input) {
+        Ok(age::Decryptor::Passphrase(decryptor)) => decryptor,
+        Ok(age::Decryptor::Recipients(_)) | Err(_) => {
+            return Err(AppError::Backup(
+                "the file is not an indexedRAG backup".to_string(),
+            ))
+        }
+    };
+    let mut reader = decryptor
+        .decrypt(&Secret::new(passphrase.to_owned()), None)
+        .map_err(|e| match e {
+            age::DecryptError::DecryptionFailed | age::DecryptError::NoMatchingKeys => {
+                AppError::Backup("wrong passphrase".to_string())
+            }
+            e => AppError::Backup(e.to_string()),
+        })?;
+
+    let mut magic = vec![0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(AppError::Backup(
+            "the file is not an indexedRAG backup".to_string(),
+        ));
+    }
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut header = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut header)?;
+    let header: Header = serde_json::from_slice(&header)?;
+
+    let mut snapshot = staging_file(conn)?;
+    io::copy(&mut reader, snapshot.as_file_mut())?;
+    conn.restore(
+        DatabaseName::Main,
+        snapshot.path(),
+        None::<fn(rusqlite::backup::Progress)>,
+    )?;
+    Ok(header.keys)
+}
+
+/// A new empty file to stage a plaintext copy of the database behind `conn` in,
+/// removed when dropped. It goes next to that database, which holds the same data
+/// anyway, rather than in a shared temp directory; like any `tempfile`, its name is
+/// random, it is only accessible to the current user and it never replaces an
+/// existing file or link.
+fn staging_file(conn: &Connection) -> io::Result<NamedTempFile> {
+    let dir = conn
+        .path()
+        .and_then(|db| Path::new(db).parent())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map_or_else(std::env::temp_dir, Path::to_path_buf);
+    tempfile::Builder::new()
+        .prefix(".indexedRAG-backup-")
+        .suffix(".db")
+        .tempfile_in(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_backup_restores_the_exported_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = Connection::open(dir.path().join("source.db")).unwrap();
+        source
+            .execute_batch("CREATE TABLE notes (text TEXT); INSERT INTO notes VALUES ('kept');")
+            .unwrap();
+        let keys = BTreeMap::from([("api_key".to_string(), "sk-test".to_string())]);
+        let backup = dir.path().join("notes.age");
+        export(&source, &backup, "passphrase", keys.clone()).unwrap();
+
+        let mut target = Connection::open(dir.path().join("target.db")).unwrap();
+        assert!(matches!(
+            restore(&mut target, &backup, "wrong"),
+            Err(AppError::Backup(_))
+        ));
+        assert_eq!(restore(&mut target, &backup, "passphrase").unwrap(), keys);
+        let text: String = target
+            .query_row("SELECT text FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(text, "kept");
+        // Nothing staged is left behind.
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["notes.age", "source.db", "target.db"]);
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::Message;
+
+/// One conversation of a ChatGPT data export (`conversations.json`).
+#[derive(Debug, Deserialize)]
+struct ExportedConversation {
+    #[serde(default)]
+    title: Option<String>,
+    /// Every message of the conversation, by node id. Edits and regenerated replies
+    /// branch off, so this is a tree rather than a list.
+    #[serde(default)]
+    mapping: HashMap<String, Node>,
+    /// The last message of the branch that was showing when the export was made.
+    #[serde(default)]
+    current_node: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Node {
+    #[serde(default)]
+    message: Option<ExportedMessage>,
+    #[serde(default)]
+    parent: Option<String>,
+    #[serde(default)]
+    children: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportedMessage {
+    author: Author,
+    #[serde(default)]
+    create_time: Option<f64>,
+    content: Content,
+}
+
+#[derive(Debug, Deserialize)]
+struct Author {
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Content {
+    #[serde(default)]
+    content_type: String,
+    /// Strings for text; images and other attachments are objects.
+    #[serde(default)]
+    parts: Vec<serde_json::Value>,
+}
+
+/// A conversation read from a ChatGPT export, ready to be stored.
+#[derive(Debug)]
+pub struct ImportedConversation {
+    pub title: String,
+    pub messages: Vec<Message>,
+}
+
+/// Read the conversations of a ChatGPT export: the whole `conversations.json` array,
+/// or a single conversation object from it. Only the branch that was showing is
+/// kept, and only the text of user, assistant and system messages; tool output,
+/// images and empty messages are left out.
+pub fn parse(json: &str) -> Result<Vec<ImportedConversation>, String> {
+    let exported: Vec<ExportedConversation> = match serde_json::from_str(json) {
+        Ok(conversations) => conversations,
+        Err(e) => match serde_json::from_str::<ExportedConversation>(json) {
+            Ok(conversation) if !conversation.mapping.is_empty() => vec![conversation],
+            _ => return Err(e.to_string()),
+        },
+    };
+    Ok(exported
+        .into_iter()
+        .map(|conversation| ImportedConversation {
+            title: conversation
+                .title
+                .clone()
+                .filter(|t| !t.trim().is_empty())
+                .unwrap_or_else(|| "Imported from ChatGPT".to_string()),
+            messages: branch(&conversation)
+                .into_iter()
+                .filter_map(to_message)
+                .collect(),
+        })
+        .filter(|conversation| !conversation.messages.is_empty())
+        .collect())
+}
+
+/// The messages from the root to `current_node`, oldest first. Without a current
+/// node, the latest child is followed down from the root.
+fn branch(conversation: &ExportedConversation) -> Vec<&ExportedMessage> {
+    let mapping = &conversation.mapping;
+    let mut nodes = Vec::new();
+    match conversation
+        .current_node
+        .as_deref()
+        .filter(|id| mapping.contains_key(*id))
+    {
+        Some(current) => {
+            let mut id = Some(current);
+            // The length bound guards against a parent cycle in a damaged file.
+            while let Some(node) = id.and_then(|id| mapping.get(id)) {
+                if nodes.len() > mapping.len() {
+                    break;
+                }
+                nodes.push(node);
+                id = node.parent.as_deref();
+            }
+            nodes.reverse();
+        }
+        None => {
+            let root = mapping.values().find(|node| {
+                node.parent
+                    .as_deref()
+                    .is_none_or(|parent| !mapping.contains_key(parent))
+            });
+            let mut next = root;
+            while let Some(node) = next {
+                if nodes.len() > mapping.len() {
+                    break;
+                }
+                nodes.push(node);
+                next = node.children.last().and_then(|id| mapping.get(id));
+            }
+        }
+    }
+    nodes
+        .into_iter()
+        .filter_map(|node| node.message.as_ref())
+        .collect()
+}
+
+/// Our message for an exported one, if it has text from a role we know.
+fn to_message(exported: &ExportedMessage) -> Option<Message> {
+    let role = exported.author.role.as_str();
+    if !matches!(role, "user" | "assistant" | "system") {
+        return None;
+    }
+    if !matches!(
+        exported.content.content_type.as_str(),
+        "text" | "multimodal_text"
+    ) {
+        return None;
+    }
+    let text = exported
+        .content
+        .parts
+        .iter()
+        .filter_map(serde_json::Value::as_str)
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if text.trim().is_empty() {
+        return None;
+    }
+    let mut message = Message::new(role, text);
+    message.created_at = exported.create_time.map(|t| t as i64);
+    Some(message)
+}
@@ -0,0 +1,238 @@
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+use serde::Serialize;
+
+use crate::indexer;
+use crate::llm::{LlmBackend, StreamEvent, ToolCall, Usage};
+use crate::{Conversation, IndexedragApp, Message, Source};
+
+/// Chat with an LLM about your indexed files. Without an action flag the GUI opens.
+#[derive(Debug, Parser)]
+#[command(name = "indexedRAG", version, about)]
+pub struct Cli {
+    /// Database file to use instead of the default one. INDEXEDRAG_DB_PATH does the
+    /// same when this is not given.
+    #[arg(long, value_name = "PATH")]
+    pub db_path: Option<PathBuf>,
+    /// Update the index from the configured root paths, then exit.
+    #[arg(long)]
+    pub reindex: bool,
+    /// Backend to use instead of the configured one: stub, openai, ollama, llamacpp
+    /// or anthropic.
+    #[arg(long, value_name = "BACKEND", value_parser = parse_backend)]
+    pub backend: Option<LlmBackend>,
+    /// Answer PROMPT with context from the index and print the reply to stdout,
+    /// without opening the GUI.
+    #[arg(long, value_name = "PROMPT")]
+    pub headless_send: Option<String>,
+    /// With --headless-send, print the reply and the sources it drew on as one JSON
+    /// object once the reply is complete.
+    #[arg(long, requires = "headless_send")]
+    pub json: bool,
+}
+
+/// What `--headless-send --json` prints.
+#[derive(Debug, Serialize)]
+struct HeadlessReply<'a> {
+    prompt: &'a str,
+    backend: &'static str,
+    reply: String,
+    sources: Vec<ScoredSource>,
+    /// Tokens billed for the reply, if the backend reported them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+    /// Functions the model asked to call; they are not executed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    /// Set when the backend failed; `reply` then holds whatever arrived before that.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScoredSource {
+    #[serde(flatten)]
+    source: Source,
+    score: f32,
+}
+
+impl Cli {
+    /// Whether the command line asks for something other than the GUI.
+    pub fn has_action(&self) -> bool {
+        self.reindex || self.headless_send.is_some()
+    }
+}
+
+fn parse_backend(key: &str) -> Result<LlmBackend, String> {
+    LlmBackend::from_key(key).ok_or_else(|| {
+        let keys: Vec<_> = LlmBackend::ALL.iter().map(|b| b.key()).collect();
+        format!("expected one of {}", keys.join(", "))
+    })
+}
+
+/// Carry out the actions requested on the command line against `app`, reporting
+/// progress on stderr.
+pub fn run(cli: &Cli, app: &mut IndexedragApp) -> ExitCode {
+    // Problems found while opening, which the GUI would show in its error window.
+    if let Some(message) = app.error_message.take() {
+        eprintln!("{}", message);
+    }
+    if cli.reindex && !reindex(app) {
+        return ExitCode::FAILURE;
+    }
+    if let Some(prompt) = &cli.headless_send {
+        if !headless_send(app, prompt, cli.json) {
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn reindex(app: &IndexedragApp) -> bool {
+    let settings = &app.settings;
+    let mut on_progress = |files_done, files_total| {
+        eprint!("\rIndexing {}/{} files", files_done, files_total);
+        ControlFlow::Continue(())
+    };
+    let result = indexer::index_paths(
+        &app.conn,
+        &settings.root_paths,
+        &settings.file_filter(),
+        settings.chunking(),
+        &settings.embedding_config(),
+        false,
+        &mut on_progress,
+    );
+    eprintln!();
+    match result {
+        Ok(Some(summary)) => {
+            eprintln!(
+                "Indexed {} files into {} chunks ({} unchanged, {} skipped)",
+                summary.files_indexed,
+                summary.chunks,
+                summary.files_unchanged,
+                summary.files_skipped
+            );
+            if summary.files_guessed_encoding > 0 {
+                eprintln!(
+                    "{} files weren't UTF-8 and were decoded with a guessed encoding",
+                    summary.files_guessed_encoding
+                );
+            }
+            for error in &summary.errors {
+                eprintln!("Could not index {}: {}", error.path, error.reason);
+            }
+            if let Some(err) = summary.embedding_error {
+                eprintln!("Embeddings unavailable: {}", err);
+            }
+            true
+        }
+        Ok(None) => true,
+        Err(e) => {
+            eprintln!("Indexing failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Ask the active backend about `prompt` in a conversation of its own, which is not
+/// stored, and stream the reply to stdout, or print it as JSON when `json` is set.
+/// Returns false if the backend failed.
+fn headless_send(app: &mut IndexedragApp, prompt: &str, json: bool) -> bool {
+    app.conversation = Conversation {
+        id: 0,
+        title: String::new(),
+        title_manual: true,
+        messages: Vec::new(),
+        model: None,
+        parent_id: None,
+        tags: Vec::new(),
+        scope: Vec::new(),
+        summary: None,
+    };
+    app.send_message(Message::new("user", prompt));
+    let Some(rx) = app.stream_rx.take() else {
+        return false;
+    };
+    let mut stdout = std::io::stdout();
+    let mut reply = String::new();
+    let mut usage = None;
+    let mut tool_calls = Vec::new();
+    let mut error = Some("The request ended without a reply".to_string());
+    let mut passages = Vec::new();
+    for event in rx {
+        match event {
+            StreamEvent::Context(Ok(retrieved)) => passages = retrieved,
+            StreamEvent::Context(Err(err)) => {
+                eprintln!("Retrieval failed, asking without context: {}", err);
+                app.log_error("retrieval", &err);
+            }
+            StreamEvent::Token(token) => {
+                if json {
+                    reply.push_str(&token);
+                } else {
+                    let _ = stdout.write_all(token.as_bytes());
+                    let _ = stdout.flush();
+                }
+            }
+            StreamEvent::Retrying(attempt) => eprintln!("Retrying ({})…", attempt),
+            StreamEvent::Done(outcome) => {
+                usage = outcome.usage;
+                tool_calls = outcome.tool_calls;
+                error = None;
+                break;
+            }
+            StreamEvent::Error(err) | StreamEvent::Unreachable(err) => {
+                error = Some(err);
+                break;
+            }
+        }
+    }
+
+    let succeeded = error.is_none();
+    if json {
+        if succeeded {
+            let mut message = Message::new("assistant", reply);
+            app.message_processor.post_receive(&mut message);
+            reply = message.content;
+        }
+        let output = HeadlessReply {
+            prompt,
+            backend: app.settings.backend.key(),
+            reply,
+            sources: passages
+                .into_iter()
+                .map(|p| ScoredSource {
+                    source: p.source,
+                    score: p.score,
+                })
+                .collect(),
+            usage,
+            tool_calls,
+            error,
+        };
+        match serde_json::to_string_pretty(&output) {
+            Ok(text) => println!("{}", text),
+            Err(e) => {
+                eprintln!("Could not serialize the reply: {}", e);
+                return false;
+            }
+        }
+    } else {
+        println!();
+        for call in tool_calls {
+            println!(
+                "[tool call, not executed] {}({})",
+                call.name, call.arguments
+            );
+        }
+        if let Some(err) = error {
+            eprintln!("{}", err);
+        }
+    }
+    succeeded
+}
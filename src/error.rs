@@ -0,0 +1,151 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Failures that can occur while opening or using the application database.
+#[derive(Debug)]
+pub enum AppError {
+    ConfigDir {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The directory meant to hold the database cannot be written to.
+    DataDirNotWritable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// Exporting or restoring an encrypted backup failed.
+    Backup(String),
+    Database(rusqlite::Error),
+    Io(std::io::Error),
+    /// An API key could not be written to the OS keychain.
+    Keyring(keyring::Error),
+    /// A file chosen for import does not hold a valid conversation.
+    InvalidImport(String),
+    /// Stored JSON could not be parsed; the original was copied to `path` and an empty
+    /// value used in its place.
+    Quarantined {
+        what: String,
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// Upgrading the schema failed. The database was put back from the copy at
+    /// `backup` taken just before, unless `restore_error` says why that failed too.
+    MigrationFailed {
+        backup: PathBuf,
+        source: Box<AppError>,
+        restore_error: Option<String>,
+    },
+    /// The database was written by a newer version of the application.
+    SchemaTooNew {
+        found: usize,
+        supported: usize,
+    },
+    Serialization(serde_json::Error),
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::ConfigDir { path, source } => write!(
+                f,
+                "Could not create the config directory {}: {}",
+                path.display(),
+                source
+            ),
+            AppError::DataDirNotWritable { path, source } => write!(
+                f,
+                "The database directory {} is not writable: {}. Choose another location with \
+                 --db-path or INDEXEDRAG_DB_PATH.",
+                path.display(),
+                source
+            ),
+            AppError::Backup(reason) => write!(f, "Backup failed: {}", reason),
+            AppError::Database(e) => write!(f, "Database error: {}", e),
+            AppError::Io(e) => write!(f, "I/O error: {}", e),
+            AppError::Keyring(e) => write!(f, "Could not store the API key in the keychain: {}", e),
+            AppError::InvalidImport(reason) => {
+                write!(f, "The file is not a valid conversation export: {}", reason)
+            }
+            AppError::Quarantined { what, path, source } => write!(
+                f,
+                "Could not read {} ({}), so it was left empty. The original text was saved \
+                 to {}.",
+                what,
+                source,
+                path.display()
+            ),
+            AppError::MigrationFailed {
+                backup,
+                source,
+                restore_error: None,
+            } => write!(
+                f,
+                "Upgrading the database failed: {}. It was restored as it was before the \
+                 upgrade; a copy is kept at {}.",
+                source,
+                backup.display()
+            ),
+            AppError::MigrationFailed {
+                backup,
+                source,
+                restore_error: Some(restore_error),
+            } => write!(
+                f,
+                "Upgrading the database failed: {}. Restoring it failed too ({}); a copy \
+                 from before the upgrade is at {}.",
+                source,
+                restore_error,
+                backup.display()
+            ),
+            AppError::SchemaTooNew { found, supported } => write!(
+                f,
+                "The database uses schema version {}, but this version of indexedRAG only \
+                 supports up to {}. Please upgrade the application.",
+                found, supported
+            ),
+            AppError::Serialization(e) => write!(f, "Serialization error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::ConfigDir { source, .. } => Some(source),
+            AppError::DataDirNotWritable { source, .. } => Some(source),
+            AppError::Backup(_) => None,
+            AppError::Database(e) => Some(e),
+            AppError::Io(e) => Some(e),
+            AppError::Keyring(e) => Some(e),
+            AppError::InvalidImport(_) => None,
+            AppError::Quarantined { source, .. } => Some(source),
+            AppError::MigrationFailed { source, .. } => Some(source.as_ref()),
+            AppError::SchemaTooNew { .. } => None,
+            AppError::Serialization(e) => Some(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        AppError::Database(e)
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::Io(e)
+    }
+}
+
+impl From<keyring::Error> for AppError {
+    fn from(e: keyring::Error) -> Self {
+        AppError::Keyring(e)
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        AppError::Serialization(e)
+    }
+}
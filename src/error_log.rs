@@ -0,0 +1,45 @@
+use rusqlite::{params, Connection};
+
+/// How many entries the log keeps; older ones are dropped as new ones arrive.
+const MAX_ENTRIES: i64 = 1000;
+
+/// A failure recorded in the `error_log` table.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub logged_at: i64,
+    /// Where the failure happened, e.g. the backend or the file being indexed.
+    pub context: String,
+    pub message: String,
+}
+
+/// Append a failure to the log, trimming it to the newest `MAX_ENTRIES` entries.
+pub fn write(conn: &Connection, context: &str, message: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO error_log (logged_at, context, message) VALUES (?1, ?2, ?3)",
+        params![chrono::Utc::now().timestamp(), context, message],
+    )?;
+    conn.execute(
+        "DELETE FROM error_log WHERE id <= (SELECT MAX(id) FROM error_log) - ?1",
+        [MAX_ENTRIES],
+    )?;
+    Ok(())
+}
+
+/// Return up to `limit` entries, newest first.
+pub fn recent(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<LogEntry>> {
+    let mut stmt = conn
+        .prepare("SELECT logged_at, context, message FROM error_log ORDER BY id DESC LIMIT ?1")?;
+    let rows = stmt.query_map([limit as i64], |row| {
+        Ok(LogEntry {
+            logged_at: row.get(0)?,
+            context: row.get(1)?,
+            message: row.get(2)?,
+        })
+    })?;
+    rows.collect()
+}
+
+pub fn clear(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM error_log", [])?;
+    Ok(())
+}
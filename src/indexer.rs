@@ -0,0 +1,1046 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+use encoding_rs::{Encoding, WINDOWS_1252};
+use rusqlite::{params, Connection, OptionalExtension, Statement};
+
+use crate::llm::{self, EmbeddingConfig, RateLimiter};
+use crate::Message;
+
+/// Default number of characters stored in a single document chunk.
+pub const DEFAULT_CHUNK_SIZE_CHARS: usize = 1000;
+/// Default number of characters a chunk shares with the one before it.
+pub const DEFAULT_CHUNK_OVERLAP_CHARS: usize = 200;
+
+/// Chunks sent to the embedding backend in one request during indexing.
+const EMBEDDING_BATCH_SIZE: usize = 64;
+
+/// How many leading bytes are inspected when deciding whether a file is binary.
+const BINARY_SNIFF_BYTES: usize = 8192;
+
+/// What an indexed conversation's `documents` path starts with; its id follows.
+const CONVERSATION_PATH_PREFIX: &str = "conversation:";
+
+#[derive(Debug, Default, Clone)]
+pub struct IndexSummary {
+    pub files_indexed: usize,
+    /// Files among `files_indexed` that hadn't changed since the last run and were kept
+    /// as they were.
+    pub files_unchanged: usize,
+    pub files_skipped: usize,
+    pub chunks: usize,
+    /// Chunks dropped because an earlier chunk of the same file has the same text.
+    pub duplicate_chunks: usize,
+    /// Chunks read in this run whose text the same model had already embedded, so the
+    /// stored embedding was kept instead of recomputed.
+    pub embeddings_reused: usize,
+    /// Set when the embedding backend failed; the remaining chunks were stored
+    /// without vectors.
+    pub embedding_error: Option<String>,
+    /// Files among `files_skipped` that could not be read or were too large.
+    pub errors: Vec<IndexError>,
+    /// Files read in this run that weren't UTF-8 and had their encoding guessed.
+    pub files_guessed_encoding: usize,
+}
+
+/// A file the last index run left out, and why.
+#[derive(Debug, Clone)]
+pub struct IndexError {
+    pub path: String,
+    pub reason: String,
+}
+
+/// Extensions indexed by default: plain text, documentation and common source code.
+pub const DEFAULT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "markdown", "rst", "org", "rs", "py", "js", "ts", "java", "kt", "go", "c", "h",
+    "cpp", "hpp", "cs", "rb", "php", "sh", "toml", "yaml", "yml", "json", "html", "css", "sql",
+    "pdf",
+];
+
+/// Default upper bound on the size of an indexed file: 1 MiB.
+pub const DEFAULT_MAX_FILE_SIZE_BYTES: u64 = 1024 * 1024;
+
+/// Which of the files found under the root paths are worth indexing, and whether the
+/// stored conversations are too.
+#[derive(Debug, Clone)]
+pub struct FileFilter {
+    /// Lowercase extensions without the dot. Empty means every extension.
+    pub allowed_extensions: Vec<String>,
+    pub max_file_size_bytes: u64,
+    /// Honour .gitignore and .ignore files and skip hidden files while walking.
+    pub respect_ignore_files: bool,
+    /// Index every stored conversation as a document of its own, under
+    /// `conversation_path`, so earlier answers can be retrieved.
+    pub index_conversations: bool,
+}
+
+/// How documents are cut into chunks: a window of `size_chars` characters that moves
+/// forward by `size_chars - overlap_chars` each step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunking {
+    pub size_chars: usize,
+    pub overlap_chars: usize,
+}
+
+impl Chunking {
+    /// Force the values into a usable window: at least one character, and an
+    /// overlap smaller than the chunk.
+    pub fn clamped(self) -> Self {
+        let size_chars = self.size_chars.max(1);
+        Chunking {
+            size_chars,
+            overlap_chars: self.overlap_chars.min(size_chars - 1),
+        }
+    }
+}
+
+impl FileFilter {
+    fn allows_extension(&self, path: &Path) -> bool {
+        if self.allowed_extensions.is_empty() {
+            return true;
+        }
+        let Some(ext) = path.extension().and_then(|e| e.to_str()) else {
+            return false;
+        };
+        self.allowed_extensions
+            .iter()
+            .any(|allowed| allowed.trim_start_matches('.').eq_ignore_ascii_case(ext))
+    }
+
+    /// Fails with the reason when `path` is over the size limit or can't be inspected.
+    fn check_size(&self, path: &Path) -> Result<(), String> {
+        let len = fs::metadata(path).map_err(|e| e.to_string())?.len();
+        if len > self.max_file_size_bytes {
+            return Err(format!(
+                "{} bytes, over the limit of {}",
+                len, self.max_file_size_bytes
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The files under `root_paths` that `filter` lets through by extension, each once,
+/// in path order. The size limit is checked separately.
+fn filtered_files(root_paths: &[String], filter: &FileFilter) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for root in root_paths.iter().filter(|p| !p.trim().is_empty()) {
+        if filter.respect_ignore_files {
+            collect_unignored_files(Path::new(root), &mut files);
+        } else {
+            collect_files(Path::new(root), &mut files);
+        }
+    }
+    files.retain(|file| filter.allows_extension(file));
+    // Overlapping root paths yield the same file twice.
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Sent from a manual index run on its own thread back to the UI.
+#[derive(Debug)]
+pub enum IndexEvent {
+    Progress {
+        files_done: usize,
+        files_total: usize,
+    },
+    /// The run ended; `Ok(None)` means it was cancelled and the index is unchanged.
+    Finished(Result<Option<IndexSummary>, String>),
+    /// The run didn't start because it would send about `tokens` tokens to a paid
+    /// embedding backend, more than may be spent without asking.
+    NeedsConfirmation { tokens: usize },
+}
+
+/// Recursively collect every regular file below `root`. Unreadable directories are
+/// skipped rather than aborting the walk.
+fn collect_files(root: &Path, out: &mut Vec<PathBuf>) {
+    if root.is_file() {
+        out.push(root.to_path_buf());
+        return;
+    }
+    let Ok(entries) = fs::read_dir(root) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => collect_files(&path, out),
+            Ok(t) if t.is_file() => out.push(path),
+            _ => {}
+        }
+    }
+}
+
+/// Collect the files below `root` that aren't excluded by .gitignore, .ignore or
+/// global git excludes, skipping hidden files and directories. Ignore files apply
+/// whether or not `root` is inside a git repository.
+fn collect_unignored_files(root: &Path, out: &mut Vec<PathBuf>) {
+    let walker = ignore::WalkBuilder::new(root).require_git(false).build();
+    for entry in walker.flatten() {
+        if entry.file_type().is_some_and(|t| t.is_file()) {
+            out.push(entry.into_path());
+        }
+    }
+}
+
+/// Text decoded to UTF-8 from a file's bytes.
+#[derive(Debug, Clone)]
+pub struct DecodedText {
+    pub text: String,
+    /// Set when the encoding wasn't certain: the file had no byte order mark and
+    /// wasn't UTF-8, or it didn't decode cleanly. Names the encoding used.
+    pub guessed_encoding: Option<String>,
+}
+
+/// Decode the contents of a text file. A byte order mark decides the encoding;
+/// otherwise UTF-8 is tried, then Windows-1252, which covers Latin-1 and most legacy
+/// Western text. Fails for binary files.
+pub fn decode_text(bytes: Vec<u8>) -> Result<DecodedText, String> {
+    // Checked first, as UTF-16 text is full of zero bytes.
+    if let Some((encoding, bom_len)) = Encoding::for_bom(&bytes) {
+        let (text, had_errors) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+        return Ok(DecodedText {
+            text: text.into_owned(),
+            guessed_encoding: had_errors
+                .then(|| format!("{}, undecodable bytes replaced", encoding.name())),
+        });
+    }
+    let sniff = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+    if sniff.contains(&0) {
+        return Err("binary file".to_string());
+    }
+    let bytes = match String::from_utf8(bytes) {
+        Ok(text) => {
+            return Ok(DecodedText {
+                text,
+                guessed_encoding: None,
+            })
+        }
+        Err(e) => e.into_bytes(),
+    };
+    let (text, had_errors) = WINDOWS_1252.decode_without_bom_handling(&bytes);
+    if had_errors {
+        return Ok(DecodedText {
+            text: String::from_utf8_lossy(&bytes).into_owned(),
+            guessed_encoding: Some("UTF-8, invalid bytes replaced".to_string()),
+        });
+    }
+    Ok(DecodedText {
+        text: text.into_owned(),
+        guessed_encoding: Some(WINDOWS_1252.name().to_string()),
+    })
+}
+
+/// Read `path` as text; see `decode_text`. Fails with the reason for unreadable or
+/// binary files.
+fn read_text_file(path: &Path) -> Result<DecodedText, String> {
+    decode_text(fs::read(path).map_err(|e| e.to_string())?)
+}
+
+/// Extract the text of each page of a PDF. Fails for files that can't be parsed; the
+/// extractor panics on some malformed input, which is treated the same.
+fn read_pdf_pages(path: &Path) -> Result<Vec<String>, String> {
+    std::panic::catch_unwind(|| pdf_extract::extract_text_by_pages(path))
+        .map_err(|_| "the PDF could not be parsed".to_string())?
+        .map_err(|e| format!("the PDF could not be parsed: {}", e))
+}
+
+/// The text of a file, ready to be chunked.
+struct Document {
+    /// (page number, text) pairs. Only paginated formats have page numbers;
+    /// everything else comes back as a single unnumbered piece.
+    pages: Vec<(Option<i64>, String)>,
+    guessed_encoding: Option<String>,
+}
+
+/// Where the text of a document to index comes from.
+enum Origin<'a> {
+    File(&'a Path),
+    /// A stored conversation, already turned into text.
+    Conversation(String),
+}
+
+/// The `documents` path of the conversation `id` once it is indexed.
+pub fn conversation_path(id: i64) -> String {
+    format!("{}{}", CONVERSATION_PATH_PREFIX, id)
+}
+
+/// The conversation a `documents` path belongs to, if it is one rather than a file.
+pub fn conversation_id(path: &str) -> Option<i64> {
+    path.strip_prefix(CONVERSATION_PATH_PREFIX)?.parse().ok()
+}
+
+/// Every stored conversation as (path, version, text). The text is the title followed
+/// by one "Role: content" paragraph per question and reply; system messages and
+/// empty replies are left out. Conversations have no modification time, so a hash
+/// of their text stands in for it and any edit gets them re-indexed. Conversations
+/// whose messages can't be parsed are skipped.
+fn conversation_documents(conn: &Connection) -> rusqlite::Result<Vec<(String, i64, String)>> {
+    let mut stmt = conn.prepare("SELECT id, title, messages FROM conversation ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    let mut documents = Vec::new();
+    for row in rows {
+        let (id, title, messages) = row?;
+        let Ok(messages) = serde_json::from_str::<Vec<Message>>(&messages) else {
+            continue;
+        };
+        let paragraphs: Vec<_> = messages
+            .iter()
+            .filter(|m| m.role != "system" && !m.content.trim().is_empty())
+            .map(|m| {
+                let role = if m.role == "user" {
+                    "User"
+                } else {
+                    "Assistant"
+                };
+                format!("{}: {}", role, m.content.trim())
+            })
+            .collect();
+        if paragraphs.is_empty() {
+            continue;
+        }
+        let text = format!("{}\n\n{}", title, paragraphs.join("\n\n"));
+        let hash = blake3::hash(text.as_bytes());
+        let version = i64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+        documents.push((conversation_path(id), version, text));
+    }
+    Ok(documents)
+}
+
+/// Read the text of `path`.
+fn read_document(path: &Path) -> Result<Document, String> {
+    let is_pdf = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("pdf"));
+    if is_pdf {
+        let pages = read_pdf_pages(path)?;
+        Ok(Document {
+            pages: pages
+                .into_iter()
+                .enumerate()
+                .map(|(i, text)| (Some(i as i64 + 1), text))
+                .collect(),
+            guessed_encoding: None,
+        })
+    } else {
+        let decoded = read_text_file(path)?;
+        Ok(Document {
+            pages: vec![(None, decoded.text)],
+            guessed_encoding: decoded.guessed_encoding,
+        })
+    }
+}
+
+/// The whole text of `path`, pages joined by blank lines, or why it can't be read as
+/// text.
+pub fn read_document_text(path: &Path) -> Result<String, String> {
+    let document = read_document(path)?;
+    Ok(document
+        .pages
+        .into_iter()
+        .map(|(_, text)| text)
+        .collect::<Vec<_>>()
+        .join("\n\n"))
+}
+
+/// Modification time of `path` in whole seconds since the Unix epoch.
+fn file_mtime(path: &Path) -> Option<i64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some(since_epoch.as_secs() as i64)
+}
+
+fn content_hash(content: &str) -> String {
+    blake3::hash(content.as_bytes()).to_hex().to_string()
+}
+
+/// Query for an embedding already in the index of the text with a given content
+/// hash, computed by a given model; see `stored_embedding`.
+const STORED_EMBEDDING_SQL: &str = "SELECT embedding, embedding_dim FROM documents
+     WHERE content_hash = ?1 AND embedding_model = ?2 AND embedding IS NOT NULL
+     LIMIT 1";
+
+/// The embedding `model` computed for some chunk of text with hash `hash`, in any
+/// file, looked up with a statement prepared from `STORED_EMBEDDING_SQL`.
+fn stored_embedding(
+    lookup: &mut Statement,
+    hash: &str,
+    model: &str,
+) -> rusqlite::Result<Option<Vec<f32>>> {
+    let row = lookup
+        .query_row(params![hash, model], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?))
+        })
+        .optional()?;
+    Ok(row.and_then(|(blob, dim)| blob_to_embedding(&blob, dim as usize)))
+}
+
+/// Modification times recorded for every file in the index by the previous run.
+fn indexed_mtimes(conn: &Connection) -> rusqlite::Result<HashMap<String, i64>> {
+    let mut stmt = conn.prepare("SELECT path, mtime FROM files")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Row ids and text of the stored chunks of `paths` that have no embedding, because
+/// the backend failed when they were indexed.
+fn unembedded_chunks(
+    conn: &Connection,
+    paths: &HashMap<String, i64>,
+) -> rusqlite::Result<Vec<(i64, String)>> {
+    let mut stmt =
+        conn.prepare("SELECT rowid, path, content FROM documents WHERE embedding IS NULL")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    let mut chunks = Vec::new();
+    for row in rows {
+        let (rowid, path, content) = row?;
+        if paths.contains_key(&path) {
+            chunks.push((rowid, content));
+        }
+    }
+    Ok(chunks)
+}
+
+/// Split `text` into overlapping pieces as described by `chunking`.
+pub fn chunk_text(text: &str, chunking: Chunking) -> Vec<String> {
+    let Chunking {
+        size_chars,
+        overlap_chars,
+    } = chunking.clamped();
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let end = (start + size_chars).min(chars.len());
+        chunks.push(chars[start..end].iter().collect::<String>());
+        if end == chars.len() {
+            break;
+        }
+        start += size_chars - overlap_chars;
+    }
+    chunks.retain(|c| !c.trim().is_empty());
+    chunks
+}
+
+/// Chunking used by the most recent index run, if there has been one.
+fn last_chunking(conn: &Connection) -> rusqlite::Result<Option<Chunking>> {
+    let mut stmt = conn.prepare(
+        "SELECT chunk_size_chars, chunk_overlap_chars FROM index_runs
+         ORDER BY finished_at DESC, id DESC LIMIT 1",
+    )?;
+    let mut rows = stmt.query([])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(Chunking {
+            size_chars: row.get::<_, i64>(0)?.max(0) as usize,
+            overlap_chars: row.get::<_, i64>(1)?.max(0) as usize,
+        })),
+        None => Ok(None),
+    }
+}
+
+/// Serialise an embedding as little-endian `f32`s for storage in a BLOB column.
+pub fn embedding_to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// Decode a BLOB written by [`embedding_to_blob`], checking it against the stored
+/// dimension so truncated or mismatched rows are rejected.
+pub fn blob_to_embedding(blob: &[u8], dim: usize) -> Option<Vec<f32>> {
+    if blob.len() != dim * 4 {
+        return None;
+    }
+    Some(
+        blob.chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+    )
+}
+
+/// A chunk ready to be written to the `documents` table.
+struct PreparedChunk {
+    path: String,
+    chunk_index: usize,
+    page: Option<i64>,
+    content: String,
+    content_hash: String,
+    file_mtime: Option<i64>,
+    embedding: Option<Vec<f32>>,
+    guessed_encoding: Option<String>,
+}
+
+/// Bring the `documents` table up to date with the text files found under
+/// `root_paths`, embedding new chunks with `embedder` in batches of
+/// `EMBEDDING_BATCH_SIZE`, or of its per-minute limit if that is lower, and within
+/// that limit. Files are read and embedded first and the results written in one short
+/// transaction, so the database isn't locked while waiting on the embedding backend
+/// and a failure leaves the previous index intact.
+///
+/// Files that aren't UTF-8 are transcoded first (see `decode_text`); their chunks
+/// record the encoding when it was guessed.
+///
+/// Files whose modification time matches the previous run are left alone unless
+/// `full` is set, except that their chunks stored without an embedding are embedded
+/// again; chunks of files that disappeared (or are now filtered out) are removed.
+///
+/// When `filter` asks for it the stored conversations are indexed alongside the
+/// files (see `conversation_documents`); otherwise their chunks are removed too.
+///
+/// Chunks repeating an earlier chunk of the same file are skipped, and chunks whose
+/// text the current embedding model has embedded before, in any file, reuse that
+/// embedding. The same text in different files is kept in each, so it stays indexed
+/// when one of them is deleted.
+///
+/// Files excluded by ignore rules are left out when `filter` asks for it, and only
+/// files whose extension `filter` allows are considered; those over its size
+/// limit are counted as skipped. Skipped files that were too large or couldn't be
+/// read replace the previous run's in the `index_errors` table, with the reason.
+///
+/// Documents are split according to `chunking`. When that differs from the chunking
+/// of the previous run every file is re-chunked, as if `full` were set.
+///
+/// `on_progress` is called with (files done, files total) as the walk proceeds;
+/// returning `ControlFlow::Break` cancels the run, leaving the index untouched, and
+/// makes this return `Ok(None)`.
+pub fn index_paths(
+    conn: &Connection,
+    root_paths: &[String],
+    filter: &FileFilter,
+    chunking: Chunking,
+    embedder: &EmbeddingConfig,
+    full: bool,
+    on_progress: &mut dyn FnMut(usize, usize) -> ControlFlow<()>,
+) -> rusqlite::Result<Option<IndexSummary>> {
+    let files = filtered_files(root_paths, filter);
+
+    let chunking = chunking.clamped();
+    let full = full || last_chunking(conn)?.is_some_and(|last| last != chunking);
+    let known_mtimes = if full {
+        HashMap::new()
+    } else {
+        indexed_mtimes(conn)?
+    };
+    let mut summary = IndexSummary::default();
+
+    let mut candidates = Vec::new();
+    for file in &files {
+        let path = file.to_string_lossy().into_owned();
+        if let Err(reason) = filter.check_size(file) {
+            summary.files_skipped += 1;
+            summary.errors.push(IndexError { path, reason });
+            continue;
+        }
+        candidates.push((Origin::File(file), path, file_mtime(file)));
+    }
+    if filter.index_conversations {
+        for (path, version, text) in conversation_documents(conn)? {
+            candidates.push((Origin::Conversation(text), path, Some(version)));
+        }
+    }
+
+    // Unchanged files keep their chunks.
+    let mut unchanged = HashMap::new();
+    let mut to_read = Vec::new();
+    for (origin, path, mtime) in candidates {
+        match mtime {
+            Some(mtime) if known_mtimes.get(&path) == Some(&mtime) => {
+                unchanged.insert(path, mtime);
+            }
+            _ => to_read.push((origin, path, mtime)),
+        }
+    }
+    summary.files_unchanged = unchanged.len();
+    summary.files_indexed = unchanged.len();
+
+    let model_id = embedder.model_id();
+    let mut lookup = conn.prepare(STORED_EMBEDDING_SQL)?;
+    let mut rate_limiter = RateLimiter::per_minute(embedder.max_per_minute);
+    // A batch is sent at once, so one larger than the limit would overrun it.
+    let batch_size = match embedder.max_per_minute {
+        0 => EMBEDDING_BATCH_SIZE,
+        limit => EMBEDDING_BATCH_SIZE.min(limit as usize),
+    };
+    let mut read_mtimes = Vec::new();
+    let mut prepared = Vec::new();
+    // Indices into `prepared` of the chunks waiting for the next embedding batch.
+    let mut pending = Vec::new();
+    let files_total = unchanged.len() + to_read.len();
+    for (i, (origin, path, mtime)) in to_read.into_iter().enumerate() {
+        if on_progress(unchanged.len() + i, files_total).is_break() {
+            return Ok(None);
+        }
+        let document = match origin {
+            Origin::File(file) => read_document(file),
+            Origin::Conversation(text) => Ok(Document {
+                pages: vec![(None, text)],
+                guessed_encoding: None,
+            }),
+        };
+        let document = match document {
+            Ok(document) => document,
+            Err(reason) => {
+                summary.files_skipped += 1;
+                summary.errors.push(IndexError { path, reason });
+                continue;
+            }
+        };
+        if document.guessed_encoding.is_some() {
+            summary.files_guessed_encoding += 1;
+        }
+        let chunks = document.pages.into_iter().flat_map(|(page, text)| {
+            chunk_text(&text, chunking)
+                .into_iter()
+                .map(move |content| (page, content))
+        });
+        let mut seen_hashes = HashSet::new();
+        for (chunk_index, (page, content)) in chunks.enumerate() {
+            let hash = content_hash(&content);
+            if !seen_hashes.insert(hash.clone()) {
+                summary.duplicate_chunks += 1;
+                continue;
+            }
+            let reusable = stored_embedding(&mut lookup, &hash, &model_id)?;
+            if reusable.is_some() {
+                summary.embeddings_reused += 1;
+            } else {
+                pending.push(prepared.len());
+            }
+            prepared.push(PreparedChunk {
+                path: path.clone(),
+                chunk_index,
+                page,
+                content,
+                content_hash: hash,
+                file_mtime: mtime,
+                embedding: reusable,
+                guessed_encoding: document.guessed_encoding.clone(),
+            });
+            if pending.len() >= batch_size {
+                embed_pending(
+                    &mut prepared,
+                    &mut pending,
+                    embedder,
+                    &mut rate_limiter,
+                    &mut summary,
+                );
+            }
+        }
+        // Without an mtime the file can't be recognised as unchanged next time.
+        if let Some(mtime) = mtime {
+            read_mtimes.push((path, mtime));
+        }
+        summary.files_indexed += 1;
+    }
+    embed_pending(
+        &mut prepared,
+        &mut pending,
+        embedder,
+        &mut rate_limiter,
+        &mut summary,
+    );
+
+    // Chunks of unchanged files the backend failed on last time.
+    let mut retried = Vec::new();
+    for batch in unembedded_chunks(conn, &unchanged)?.chunks(batch_size) {
+        if on_progress(files_total, files_total).is_break() {
+            return Ok(None);
+        }
+        let texts = batch.iter().map(|(_, content)| content.clone()).collect();
+        let Some(embeddings) = embed_batch(texts, embedder, &mut rate_limiter, &mut summary) else {
+            break;
+        };
+        retried.extend(batch.iter().map(|(rowid, _)| *rowid).zip(embeddings));
+    }
+    if on_progress(files_total, files_total).is_break() {
+        return Ok(None);
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    let stale_paths = {
+        let mut stmt = tx.prepare("SELECT DISTINCT path FROM documents")?;
+        let paths = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        paths
+            .filter(|path| !path.as_ref().is_ok_and(|p| unchanged.contains_key(p)))
+            .collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    for path in &stale_paths {
+        tx.execute("DELETE FROM documents WHERE path = ?1", [path])?;
+    }
+    {
+        let mut update = tx.prepare(
+            "UPDATE documents SET embedding = ?1, embedding_dim = ?2, embedding_model = ?3
+             WHERE rowid = ?4",
+        )?;
+        for (rowid, embedding) in &retried {
+            update.execute(params![
+                embedding_to_blob(embedding),
+                embedding.len() as i64,
+                model_id,
+                rowid
+            ])?;
+        }
+    }
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO documents (path, chunk_index, page, content, content_hash, file_mtime,
+                                    embedding, embedding_dim, embedding_model,
+                                    guessed_encoding)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        )?;
+        for chunk in &prepared {
+            insert.execute(params![
+                chunk.path,
+                chunk.chunk_index as i64,
+                chunk.page,
+                chunk.content,
+                chunk.content_hash,
+                chunk.file_mtime,
+                chunk.embedding.as_deref().map(embedding_to_blob),
+                chunk.embedding.as_ref().map(|e| e.len() as i64),
+                chunk.embedding.as_ref().map(|_| &model_id),
+                chunk.guessed_encoding
+            ])?;
+        }
+    }
+    tx.execute("DELETE FROM files", [])?;
+    {
+        let mut insert = tx.prepare("INSERT INTO files (path, mtime) VALUES (?1, ?2)")?;
+        for (path, mtime) in unchanged
+            .iter()
+            .chain(read_mtimes.iter().map(|(p, m)| (p, m)))
+        {
+            insert.execute(params![path, mtime])?;
+        }
+    }
+    tx.execute("DELETE FROM index_errors", [])?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT OR REPLACE INTO index_errors (path, reason, failed_at) VALUES (?1, ?2, ?3)",
+        )?;
+        let now = unix_now();
+        for error in &summary.errors {
+            insert.execute(params![error.path, error.reason, now])?;
+        }
+    }
+    summary.chunks = tx.query_row("SELECT COUNT(*) FROM documents", [], |row| {
+        row.get::<_, i64>(0)
+    })? as usize;
+    tx.execute(
+        "INSERT INTO index_runs (finished_at, files_indexed, chunks, chunk_size_chars,
+                                 chunk_overlap_chars)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            unix_now(),
+            summary.files_indexed as i64,
+            summary.chunks as i64,
+            chunking.size_chars as i64,
+            chunking.overlap_chars as i64
+        ],
+    )?;
+    tx.commit()?;
+    Ok(Some(summary))
+}
+
+/// Embed the chunks of `prepared` listed in `pending` in one request and empty the
+/// list. Once the backend has failed, recorded in `summary`, it isn't asked again, so
+/// the remaining chunks are stored without an embedding.
+fn embed_pending(
+    prepared: &mut [PreparedChunk],
+    pending: &mut Vec<usize>,
+    embedder: &EmbeddingConfig,
+    rate_limiter: &mut RateLimiter,
+    summary: &mut IndexSummary,
+) {
+    if !pending.is_empty() {
+        let texts = pending
+            .iter()
+            .map(|&i| prepared[i].content.clone())
+            .collect();
+        if let Some(embeddings) = embed_batch(texts, embedder, rate_limiter, summary) {
+            for (&i, embedding) in pending.iter().zip(embeddings) {
+                prepared[i].embedding = Some(embedding);
+            }
+        }
+    }
+    pending.clear();
+}
+
+/// Embed `texts` in one request once `rate_limiter` allows it, or return `None`
+/// without asking if the backend already failed in this run. A failure is recorded in
+/// `summary`.
+fn embed_batch(
+    texts: Vec<String>,
+    embedder: &EmbeddingConfig,
+    rate_limiter: &mut RateLimiter,
+    summary: &mut IndexSummary,
+) -> Option<Vec<Vec<f32>>> {
+    if summary.embedding_error.is_some() {
+        return None;
+    }
+    rate_limiter.wait(texts.len());
+    match embedder.embed_texts(&texts) {
+        Ok(embeddings) => Some(embeddings),
+        Err(e) => {
+            summary.embedding_error = Some(e);
+            None
+        }
+    }
+}
+
+/// Roughly how many tokens the next `index_paths` run with these arguments sends to
+/// the embedding backend, for estimating its cost beforehand. Counts every file and
+/// conversation that would be read again, by size and including chunk overlap, and
+/// the stored chunks of the others that are still waiting for an embedding;
+/// duplicate chunks and reused embeddings aren't known until then, and PDFs count by
+/// file size, so this errs on the high side.
+pub fn estimate_embedding_tokens(
+    conn: &Connection,
+    root_paths: &[String],
+    filter: &FileFilter,
+    chunking: Chunking,
+    full: bool,
+) -> rusqlite::Result<usize> {
+    let chunking = chunking.clamped();
+    let full = full || last_chunking(conn)?.is_some_and(|last| last != chunking);
+    let known_mtimes = if full {
+        HashMap::new()
+    } else {
+        indexed_mtimes(conn)?
+    };
+    let mut unchanged = HashMap::new();
+    let mut count = |path: String, mtime: Option<i64>, len: usize| match mtime {
+        Some(mtime) if known_mtimes.get(&path) == Some(&mtime) => {
+            unchanged.insert(path, mtime);
+            0
+        }
+        _ => len,
+    };
+
+    let mut chars = 0;
+    for file in filtered_files(root_paths, filter) {
+        let Ok(metadata) = fs::metadata(&file) else {
+            continue;
+        };
+        if metadata.len() <= filter.max_file_size_bytes {
+            let path = file.to_string_lossy().into_owned();
+            chars += count(path, file_mtime(&file), metadata.len() as usize);
+        }
+    }
+    if filter.index_conversations {
+        for (path, version, text) in conversation_documents(conn)? {
+            chars += count(path, Some(version), text.chars().count());
+        }
+    }
+    // Every chunk but the first repeats `overlap_chars` of the one before it.
+    let step = chunking.size_chars - chunking.overlap_chars;
+    let mut embedded_chars = chars as u64 * chunking.size_chars as u64 / step as u64;
+    for (_, content) in unembedded_chunks(conn, &unchanged)? {
+        embedded_chars += content.chars().count() as u64;
+    }
+    Ok((embedded_chars as usize).div_ceil(llm::CHARS_PER_TOKEN))
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Remove every indexed chunk, the index run history and the files that failed. The
+/// full-text index is emptied along with `documents` by its triggers.
+pub fn clear_index(conn: &Connection) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM documents", [])?;
+    tx.execute("DELETE FROM files", [])?;
+    tx.execute("DELETE FROM index_runs", [])?;
+    tx.execute("DELETE FROM index_errors", [])?;
+    tx.commit()
+}
+
+/// What the index currently holds.
+#[derive(Debug, Default, Clone)]
+pub struct IndexStats {
+    pub documents: i64,
+    pub chunks: i64,
+    /// Size of the stored chunk text in bytes.
+    pub bytes: i64,
+    pub last_indexed_at: Option<i64>,
+    /// (root path, documents, chunks) for every configured root path.
+    pub per_root: Vec<(String, i64, i64)>,
+    /// Files the last index run could not index, by path.
+    pub errors: Vec<IndexError>,
+    /// (path, encoding) of indexed files whose encoding was guessed, by path.
+    pub guessed_encodings: Vec<(String, String)>,
+}
+
+/// Count the documents and chunks in the index, overall and below each of
+/// `root_paths`.
+pub fn index_stats(conn: &Connection, root_paths: &[String]) -> rusqlite::Result<IndexStats> {
+    let (documents, chunks, bytes) = conn.query_row(
+        "SELECT COUNT(DISTINCT path), COUNT(*), COALESCE(SUM(LENGTH(CAST(content AS BLOB))), 0)
+         FROM documents",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    let mut per_root = Vec::new();
+    for root in root_paths.iter().filter(|p| !p.trim().is_empty()) {
+        let (root_documents, root_chunks) = conn.query_row(
+            "SELECT COUNT(DISTINCT path), COUNT(*) FROM documents
+             WHERE substr(path, 1, length(?1)) = ?1",
+            [root],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        per_root.push((root.clone(), root_documents, root_chunks));
+    }
+    Ok(IndexStats {
+        documents,
+        chunks,
+        bytes,
+        last_indexed_at: last_indexed_at(conn)?,
+        per_root,
+        errors: index_errors(conn)?,
+        guessed_encodings: guessed_encodings(conn)?,
+    })
+}
+
+/// (path, encoding) of the indexed files whose encoding was guessed, by path.
+pub fn guessed_encodings(conn: &Connection) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT path, guessed_encoding FROM documents
+         WHERE guessed_encoding IS NOT NULL ORDER BY path",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// The files the last index run could not index, by path.
+pub fn index_errors(conn: &Connection) -> rusqlite::Result<Vec<IndexError>> {
+    let mut stmt = conn.prepare("SELECT path, reason FROM index_errors ORDER BY path")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(IndexError {
+            path: row.get(0)?,
+            reason: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Unix timestamp of the most recent successful index run, if any.
+pub fn last_indexed_at(conn: &Connection) -> rusqlite::Result<Option<i64>> {
+    conn.query_row("SELECT MAX(finished_at) FROM index_runs", [], |row| {
+        row.get(0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunking(size_chars: usize, overlap_chars: usize) -> Chunking {
+        Chunking {
+            size_chars,
+            overlap_chars,
+        }
+    }
+
+    #[test]
+    fn chunk_text_overlaps_consecutive_chunks() {
+        let chunks = chunk_text("abcdefghij", chunking(4, 1));
+        assert_eq!(chunks, ["abcd", "defg", "ghij"]);
+    }
+
+    #[test]
+    fn chunk_text_stops_at_the_last_full_window() {
+        // The window reaching the end is the last one, even if another step would fit.
+        let chunks = chunk_text("abcdef", chunking(4, 2));
+        assert_eq!(chunks, ["abcd", "cdef"]);
+    }
+
+    #[test]
+    fn chunk_text_counts_characters_not_bytes() {
+        let chunks = chunk_text("żółwźćń", chunking(3, 0));
+        assert_eq!(chunks, ["żół", "wźć", "ń"]);
+    }
+
+    #[test]
+    fn chunk_text_drops_blank_chunks() {
+        let chunks = chunk_text("ab      ", chunking(2, 0));
+        assert_eq!(chunks, ["ab"]);
+        assert!(chunk_text("", chunking(10, 2)).is_empty());
+    }
+
+    #[test]
+    fn chunk_text_clamps_an_overlap_as_large_as_the_chunk() {
+        let chunks = chunk_text("abcd", chunking(2, 5));
+        assert_eq!(chunks, ["ab", "bc", "cd"]);
+    }
+
+    #[test]
+    fn decode_text_reads_utf8_as_is() {
+        let decoded = decode_text("zażółć".as_bytes().to_vec()).unwrap();
+        assert_eq!(decoded.text, "zażółć");
+        assert_eq!(decoded.guessed_encoding, None);
+    }
+
+    #[test]
+    fn decode_text_follows_a_byte_order_mark() {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend("hé".encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+        let decoded = decode_text(bytes).unwrap();
+        assert_eq!(decoded.text, "hé");
+        assert_eq!(decoded.guessed_encoding, None);
+
+        let decoded = decode_text(b"\xEF\xBB\xBFplain".to_vec()).unwrap();
+        assert_eq!(decoded.text, "plain");
+    }
+
+    #[test]
+    fn decode_text_falls_back_to_windows_1252() {
+        let decoded = decode_text(b"caf\xE9 \x80".to_vec()).unwrap();
+        assert_eq!(decoded.text, "café €");
+        assert_eq!(decoded.guessed_encoding.as_deref(), Some("windows-1252"));
+    }
+
+    #[test]
+    fn decode_text_rejects_binary_files() {
+        assert!(decode_text(vec![0x7F, b'E', b'L', b'F', 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn stored_embedding_is_found_by_text_and_model_in_any_file() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::migrations::migrate(&conn).unwrap();
+        let hash = content_hash("shared text");
+        conn.execute(
+            "INSERT INTO documents (path, chunk_index, content, content_hash, embedding,
+                                    embedding_dim, embedding_model)
+             VALUES ('/a.txt', 0, 'shared text', ?1, ?2, 2, 'ollama:nomic-embed-text')",
+            params![hash, embedding_to_blob(&[0.5, -1.0])],
+        )
+        .unwrap();
+        let mut lookup = conn.prepare(STORED_EMBEDDING_SQL).unwrap();
+
+        let found = stored_embedding(&mut lookup, &hash, "ollama:nomic-embed-text").unwrap();
+        assert_eq!(found, Some(vec![0.5, -1.0]));
+        let other_model = stored_embedding(&mut lookup, &hash, "openai:text-embedding-3-small");
+        assert_eq!(other_model.unwrap(), None);
+        let other_hash = content_hash("other text");
+        let other_text = stored_embedding(&mut lookup, &other_hash, "ollama:nomic-embed-text");
+        assert_eq!(other_text.unwrap(), None);
+    }
+}
@@ -0,0 +1,1309 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use reqwest::blocking::Response;
+use serde::{Deserialize, Serialize};
+
+use crate::retrieval::ScoredChunk;
+use crate::Message;
+
+/// Where the OpenAI backend sends requests unless another OpenAI-compatible provider
+/// is configured.
+pub const OPENAI_DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+pub const OLLAMA_DEFAULT_URL: &str = "http://localhost:11434";
+pub const LLAMACPP_DEFAULT_URL: &str = "http://localhost:8080";
+pub const ANTHROPIC_MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages";
+const ANTHROPIC_MODELS_URL: &str = "https://api.anthropic.com/v1/models";
+/// API version sent in the `anthropic-version` header.
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// llama.cpp serves whichever model it was started with, whatever the request names.
+const LLAMACPP_MODEL: &str = "default";
+
+/// Delay before the first retry of a failed request; doubled for every further one.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Upper bound for any single wait, including one asked for by a Retry-After header.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// The LLM provider that the send button dispatches to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmBackend {
+    Stub,
+    OpenAi,
+    Ollama,
+    LlamaCpp,
+    Anthropic,
+}
+
+impl LlmBackend {
+    pub const ALL: [LlmBackend; 5] = [
+        LlmBackend::Stub,
+        LlmBackend::OpenAi,
+        LlmBackend::Ollama,
+        LlmBackend::LlamaCpp,
+        LlmBackend::Anthropic,
+    ];
+
+    /// Stable identifier used to persist the backend in the settings table.
+    pub fn key(self) -> &'static str {
+        match self {
+            LlmBackend::Stub => "stub",
+            LlmBackend::OpenAi => "openai",
+            LlmBackend::Ollama => "ollama",
+            LlmBackend::LlamaCpp => "llamacpp",
+            LlmBackend::Anthropic => "anthropic",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|b| b.key() == key)
+    }
+
+    /// Whether the provider bills requests by the token.
+    pub fn is_paid(self) -> bool {
+        matches!(self, LlmBackend::OpenAi | LlmBackend::Anthropic)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            LlmBackend::Stub => "Stub (offline)",
+            LlmBackend::OpenAi => "OpenAI",
+            LlmBackend::Ollama => "Ollama",
+            LlmBackend::LlamaCpp => "llama.cpp server",
+            LlmBackend::Anthropic => "Anthropic",
+        }
+    }
+}
+
+/// Events sent from a request thread back to the UI while a reply is being generated.
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// The passages retrieved for the question and sent with it, or why retrieval
+    /// failed and the question went without; comes before anything else.
+    Context(Result<Vec<ScoredChunk>, String>),
+    Token(String),
+    /// The request failed transiently and is about to be retried; carries the attempt number.
+    Retrying(u32),
+    /// The reply is complete; carries what the backend reported besides its text.
+    Done(ChatOutcome),
+    Error(String),
+    /// The backend could not be reached at all, so the question is worth sending again later.
+    Unreachable(String),
+}
+
+/// Tokens a paid backend billed for one request, as reported in its response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// A function the model asked to have called, in the OpenAI function-calling format.
+/// Nothing is executed; the call is stored and shown as part of the reply.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Identifier the backend gave the call, for matching a result to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    /// The arguments as the model wrote them, normally a JSON object.
+    pub arguments: String,
+}
+
+/// What a finished chat request produced besides the streamed text.
+#[derive(Debug, Clone, Default)]
+pub struct ChatOutcome {
+    /// Tokens billed, if the backend reported them.
+    pub usage: Option<Usage>,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Why a chat request failed.
+#[derive(Debug)]
+pub enum ChatError {
+    /// No connection to the backend could be made, even after retrying.
+    Unreachable(String),
+    Failed(String),
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::Unreachable(msg) | ChatError::Failed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<String> for ChatError {
+    fn from(msg: String) -> Self {
+        ChatError::Failed(msg)
+    }
+}
+
+/// What a backend actually receives for each message. Our `Message` carries UI-only
+/// fields that providers would reject.
+#[derive(Serialize)]
+struct WireMessage<'a> {
+    role: &'a str,
+    content: Cow<'a, str>,
+}
+
+fn wire_messages(messages: &[Message]) -> Vec<WireMessage<'_>> {
+    messages
+        .iter()
+        .map(|m| WireMessage {
+            role: &m.role,
+            content: m.request_content(),
+        })
+        .collect()
+}
+
+/// Offline backend that just echoes the last user message, a word at a time. Useful
+/// for trying out the UI without any model available.
+///
+/// Like every backend, it hands tokens to `on_token` and stops early when that
+/// returns `ControlFlow::Break`, which is how a user cancels a request.
+pub fn stub_chat(
+    messages: &[Message],
+    mut on_token: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<(), ChatError> {
+    let last_user = messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .map(|m| m.content.as_str())
+        .unwrap_or_default();
+    let reply = format!("(Stub) LLM Response to: '{}'", last_user);
+    for word in reply.split_inclusive(' ') {
+        if on_token(word).is_break() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(30));
+    }
+    Ok(())
+}
+
+/// Characters per token assumed by `estimate_tokens`.
+pub const CHARS_PER_TOKEN: usize = 4;
+
+/// Rough token count for `text`: about four characters per token for English text,
+/// which is close enough to judge how full the context window is.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Sampling controls sent along with every chat request to a real backend.
+#[derive(Debug, Clone, Copy)]
+pub struct SamplingOptions {
+    pub temperature: f32,
+    pub max_tokens: i32,
+}
+
+impl SamplingOptions {
+    pub const TEMPERATURE_RANGE: std::ops::RangeInclusive<f32> = 0.0..=2.0;
+
+    /// Pull out-of-range values back into what the providers accept.
+    pub fn clamped(self) -> Self {
+        SamplingOptions {
+            temperature: self.temperature.clamp(
+                *Self::TEMPERATURE_RANGE.start(),
+                *Self::TEMPERATURE_RANGE.end(),
+            ),
+            max_tokens: self.max_tokens.max(1),
+        }
+    }
+}
+
+/// Connection handling shared by every request to a backend.
+#[derive(Debug, Clone)]
+pub struct RequestOptions {
+    pub max_retries: u32,
+    /// Limit for each attempt, from connecting until the last byte of the reply.
+    pub timeout: Duration,
+    pub proxy: ProxySettings,
+}
+
+/// Proxy that requests to backends go through.
+#[derive(Debug, Clone, Default)]
+pub struct ProxySettings {
+    /// An `http://` or `https://` proxy URL. Empty falls back to the HTTP_PROXY,
+    /// HTTPS_PROXY and ALL_PROXY environment variables, honouring NO_PROXY.
+    pub url: String,
+    /// Comma-separated hosts, domains and IP ranges reached without `url`, in the
+    /// NO_PROXY format. Empty uses the NO_PROXY environment variable.
+    pub no_proxy: String,
+}
+
+/// HTTP client that gives up on a request after `timeout` and goes through `proxy`.
+fn http_client(
+    timeout: Duration,
+    proxy: &ProxySettings,
+) -> Result<reqwest::blocking::Client, String> {
+    let mut builder = reqwest::blocking::Client::builder().timeout(timeout);
+    let url = proxy.url.trim();
+    if !url.is_empty() {
+        let no_proxy = match proxy.no_proxy.trim() {
+            "" => reqwest::NoProxy::from_env(),
+            list => reqwest::NoProxy::from_string(list),
+        };
+        let proxy = reqwest::Proxy::all(url)
+            .map_err(|e| format!("Invalid proxy URL {}: {}", url, e))?
+            .no_proxy(no_proxy);
+        builder = builder.proxy(proxy);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+/// A chat request as it goes over the wire, kept so it can be reproduced outside
+/// the app.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub url: String,
+    headers: Vec<Header>,
+    /// The JSON payload, exactly as sent.
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+struct Header {
+    name: &'static str,
+    value: String,
+    /// The value holds a credential and is left out of `HttpRequest::to_curl`.
+    secret: bool,
+}
+
+impl HttpRequest {
+    fn new(url: String, body: &impl Serialize) -> Self {
+        HttpRequest {
+            url,
+            headers: Vec::new(),
+            // Structs of strings and numbers always serialize.
+            body: serde_json::to_string(body).unwrap_or_default(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push(Header {
+            name,
+            value: value.into(),
+            secret: false,
+        });
+        self
+    }
+
+    fn secret_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push(Header {
+            name,
+            value: value.into(),
+            secret: true,
+        });
+        self
+    }
+
+    /// POST the request with `client`.
+    fn send(&self, client: &reqwest::blocking::Client) -> reqwest::Result<Response> {
+        let mut request = client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        for header in &self.headers {
+            request = request.header(header.name, &header.value);
+        }
+        request.body(self.body.clone()).send()
+    }
+
+    /// A shell command making the same request with cURL, streaming the reply as it
+    /// arrives. Credentials are replaced by `$API_KEY`, to be set before running it.
+    pub fn to_curl(&self) -> String {
+        let mut command = format!("curl -N {}", shell_quote(&self.url));
+        command.push_str(" \\\n  -H 'Content-Type: application/json'");
+        for header in &self.headers {
+            let line = if header.secret {
+                // Double quotes, so the shell expands the variable.
+                let value = match header.value.strip_prefix("Bearer ") {
+                    Some(_) => "Bearer $API_KEY",
+                    None => "$API_KEY",
+                };
+                format!("\"{}: {}\"", header.name, value)
+            } else {
+                shell_quote(&format!("{}: {}", header.name, header.value))
+            };
+            command.push_str(&format!(" \\\n  -H {}", line));
+        }
+        command.push_str(&format!(" \\\n  --data-raw {}", shell_quote(&self.body)));
+        command
+    }
+}
+
+/// `text` as a single-quoted POSIX shell word.
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Describe a request to `provider` that failed before any reply arrived. Failing to
+/// connect means the backend is unreachable; anything else is a plain failure.
+fn request_error(provider: &str, e: reqwest::Error) -> ChatError {
+    if e.is_connect() {
+        ChatError::Unreachable(format!("{} request failed: {}", provider, e))
+    } else if e.is_timeout() {
+        ChatError::Failed(format!("{} request timed out", provider))
+    } else {
+        ChatError::Failed(format!("{} request failed: {}", provider, e))
+    }
+}
+
+/// Describe a failure while reading the streamed reply from `provider`.
+fn stream_error(provider: &str, e: std::io::Error) -> ChatError {
+    if e.kind() == std::io::ErrorKind::TimedOut {
+        ChatError::Failed(format!(
+            "{} request timed out while streaming the reply",
+            provider
+        ))
+    } else {
+        ChatError::Failed(format!("{} stream interrupted: {}", provider, e))
+    }
+}
+
+/// Send a request built by `send`, retrying connection failures, timeouts, 429 and 5xx
+/// responses up to `max_retries` times with exponential backoff. A Retry-After header
+/// (in seconds) overrides the computed delay. `on_retry` is told the attempt number
+/// before each retry and can stop retrying by returning `ControlFlow::Break`, in which
+/// case the last outcome is returned as is.
+pub fn send_with_retry(
+    max_retries: u32,
+    on_retry: &mut dyn FnMut(u32) -> ControlFlow<()>,
+    mut send: impl FnMut() -> reqwest::Result<Response>,
+) -> reqwest::Result<Response> {
+    let mut attempt = 0;
+    loop {
+        let outcome = send();
+        let retry_after = match &outcome {
+            Ok(response) => {
+                let status = response.status();
+                if status != reqwest::StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+                    return outcome;
+                }
+                response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse::<u64>().ok())
+                    .map(Duration::from_secs)
+            }
+            Err(e) if e.is_connect() || e.is_timeout() => None,
+            Err(_) => return outcome,
+        };
+        if attempt >= max_retries {
+            return outcome;
+        }
+        attempt += 1;
+        if on_retry(attempt).is_break() {
+            return outcome;
+        }
+        let backoff = RETRY_BASE_DELAY * 2u32.saturating_pow(attempt - 1);
+        std::thread::sleep(retry_after.unwrap_or(backoff).min(RETRY_MAX_DELAY));
+    }
+}
+
+/// Turn a non-2xx response into an error string that includes the response body,
+/// which is where providers put the useful part of the explanation.
+fn check_status(provider: &str, response: Response) -> Result<Response, String> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response)
+    } else {
+        let body = response.text().unwrap_or_default();
+        Err(format!("{} returned {}: {}", provider, status, body))
+    }
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<WireMessage<'a>>,
+    stream: bool,
+    temperature: f32,
+    max_tokens: i32,
+    stream_options: StreamOptions,
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    /// Ask for a final chunk carrying the token usage of the request.
+    include_usage: bool,
+}
+
+#[derive(Deserialize)]
+struct ChatChunk {
+    choices: Vec<ChatChunkChoice>,
+    #[serde(default)]
+    usage: Option<ChatUsage>,
+}
+
+#[derive(Deserialize)]
+struct ChatUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct ChatChunkChoice {
+    delta: ChatDelta,
+}
+
+#[derive(Deserialize)]
+struct ChatDelta {
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// A fragment of a tool call; the arguments arrive in pieces, which are appended to
+/// the call at `index`.
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    #[serde(default)]
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+/// POST the conversation to the chat completions endpoint under `base_url` (OpenAI's
+/// or another provider's speaking the same API) and stream the assistant reply
+/// through `on_token` as server-sent events arrive. Errors are
+/// returned as human-readable strings so the caller can surface them in the
+/// conversation. On success, returns the token usage OpenAI reported and any tool
+/// calls the model made.
+#[allow(clippy::too_many_arguments)]
+pub fn openai_chat(
+    base_url: &str,
+    api_key: &str,
+    model: &str,
+    messages: &[Message],
+    sampling: SamplingOptions,
+    options: RequestOptions,
+    on_retry: &mut dyn FnMut(u32) -> ControlFlow<()>,
+    on_token: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<ChatOutcome, ChatError> {
+    let request = openai_request(base_url, api_key, model, messages, sampling);
+    openai_compatible_chat("OpenAI", &request, options, on_retry, on_token)
+}
+
+/// The request `openai_chat` makes.
+pub fn openai_request(
+    base_url: &str,
+    api_key: &str,
+    model: &str,
+    messages: &[Message],
+    sampling: SamplingOptions,
+) -> HttpRequest {
+    let url = format!("{}/chat/completions", base_url.trim_end_matches('/'));
+    chat_completions_request(url, model, messages, sampling)
+        .secret_header("Authorization", format!("Bearer {}", api_key))
+}
+
+/// Stream a reply from a llama.cpp server (`llama-server`) at `base_url` through its
+/// OpenAI-compatible chat endpoint.
+pub fn llamacpp_chat(
+    base_url: &str,
+    messages: &[Message],
+    sampling: SamplingOptions,
+    options: RequestOptions,
+    on_retry: &mut dyn FnMut(u32) -> ControlFlow<()>,
+    on_token: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<ChatOutcome, ChatError> {
+    let request = llamacpp_request(base_url, messages, sampling);
+    let outcome = openai_compatible_chat("llama.cpp", &request, options, on_retry, on_token)?;
+    // A local server costs nothing, whatever usage it reports.
+    Ok(ChatOutcome {
+        usage: None,
+        ..outcome
+    })
+}
+
+/// The request `llamacpp_chat` makes.
+pub fn llamacpp_request(
+    base_url: &str,
+    messages: &[Message],
+    sampling: SamplingOptions,
+) -> HttpRequest {
+    let url = format!("{}/v1/chat/completions", base_url.trim_end_matches('/'));
+    chat_completions_request(url, LLAMACPP_MODEL, messages, sampling)
+}
+
+/// A streaming request to the chat completions endpoint at `url`.
+fn chat_completions_request(
+    url: String,
+    model: &str,
+    messages: &[Message],
+    sampling: SamplingOptions,
+) -> HttpRequest {
+    let sampling = sampling.clamped();
+    let body = ChatRequest {
+        model,
+        messages: wire_messages(messages),
+        stream: true,
+        temperature: sampling.temperature,
+        max_tokens: sampling.max_tokens,
+        stream_options: StreamOptions {
+            include_usage: true,
+        },
+    };
+    HttpRequest::new(url, &body)
+}
+
+/// Send `request` to a chat completions endpoint that speaks the OpenAI protocol and
+/// stream the reply's server-sent events through `on_token`. `provider` names the
+/// server in error messages. Returns the token usage, if the server reported it, and the
+/// tool calls the model made, assembled from their streamed fragments.
+fn openai_compatible_chat(
+    provider: &str,
+    request: &HttpRequest,
+    options: RequestOptions,
+    on_retry: &mut dyn FnMut(u32) -> ControlFlow<()>,
+    mut on_token: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<ChatOutcome, ChatError> {
+    let client = http_client(options.timeout, &options.proxy)?;
+    let response = send_with_retry(options.max_retries, on_retry, || request.send(&client))
+        .map_err(|e| request_error(provider, e))?;
+    let response = check_status(provider, response)?;
+
+    let mut outcome = ChatOutcome::default();
+    for line in BufReader::new(response).lines() {
+        let line = line.map_err(|e| stream_error(provider, e))?;
+        let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+            continue;
+        };
+        if data == "[DONE]" {
+            break;
+        }
+        let chunk: ChatChunk = serde_json::from_str(data)
+            .map_err(|e| format!("Failed to parse {} stream chunk: {}", provider, e))?;
+        if let Some(reported) = chunk.usage {
+            outcome.usage = Some(Usage {
+                prompt_tokens: reported.prompt_tokens,
+                completion_tokens: reported.completion_tokens,
+            });
+        }
+        let Some(delta) = chunk.choices.into_iter().next().map(|c| c.delta) else {
+            continue;
+        };
+        for fragment in delta.tool_calls.unwrap_or_default() {
+            if outcome.tool_calls.len() <= fragment.index {
+                outcome
+                    .tool_calls
+                    .resize_with(fragment.index + 1, ToolCall::default);
+            }
+            let call = &mut outcome.tool_calls[fragment.index];
+            if fragment.id.is_some() {
+                call.id = fragment.id;
+            }
+            if let Some(function) = fragment.function {
+                call.name.push_str(&function.name.unwrap_or_default());
+                call.arguments
+                    .push_str(&function.arguments.unwrap_or_default());
+            }
+        }
+        if let Some(content) = delta.content {
+            if on_token(&content).is_break() {
+                break;
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+#[derive(Serialize)]
+struct AnthropicRequest<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage<'a>>,
+    max_tokens: i32,
+    temperature: f32,
+    stream: bool,
+}
+
+#[derive(Serialize)]
+struct AnthropicMessage<'a> {
+    role: &'a str,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct AnthropicEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    delta: Option<AnthropicDelta>,
+    #[serde(default)]
+    error: Option<AnthropicErrorBody>,
+    /// Set on `message_start`, with the prompt's token count.
+    #[serde(default)]
+    message: Option<AnthropicMessageStart>,
+    /// Set on `message_delta`, with the reply's token count so far.
+    #[serde(default)]
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicMessageStart {
+    #[serde(default)]
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct AnthropicDelta {
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicErrorBody {
+    message: String,
+}
+
+/// Split `messages` the way the Messages API wants them: system messages are joined
+/// into the top-level system prompt, and the rest must alternate between user and
+/// assistant, starting with the user, so consecutive turns of one role are merged.
+fn anthropic_messages(messages: &[Message]) -> (Option<String>, Vec<AnthropicMessage<'_>>) {
+    let mut system = Vec::new();
+    let mut turns: Vec<AnthropicMessage> = Vec::new();
+    for message in messages {
+        if message.role == "system" {
+            system.push(message.content.as_str());
+            continue;
+        }
+        if message.content.trim().is_empty() {
+            continue;
+        }
+        match turns.last_mut() {
+            Some(last) if last.role == message.role => {
+                last.content.push_str("\n\n");
+                last.content.push_str(&message.request_content());
+            }
+            _ => turns.push(AnthropicMessage {
+                role: &message.role,
+                content: message.request_content().into_owned(),
+            }),
+        }
+    }
+    if turns.first().is_some_and(|t| t.role == "assistant") {
+        turns.remove(0);
+    }
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+    (system, turns)
+}
+
+/// POST the conversation to Anthropic's Messages API and stream the reply through
+/// `on_token`. On success, returns the token usage Anthropic reported.
+pub fn anthropic_chat(
+    api_key: &str,
+    model: &str,
+    messages: &[Message],
+    sampling: SamplingOptions,
+    options: RequestOptions,
+    on_retry: &mut dyn FnMut(u32) -> ControlFlow<()>,
+    mut on_token: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<ChatOutcome, ChatError> {
+    let client = http_client(options.timeout, &options.proxy)?;
+    let request = anthropic_request(api_key, model, messages, sampling);
+    let response = send_with_retry(options.max_retries, on_retry, || request.send(&client))
+        .map_err(|e| request_error("Anthropic", e))?;
+    let response = check_status("Anthropic", response)?;
+
+    let mut usage: Option<Usage> = None;
+    for line in BufReader::new(response).lines() {
+        let line = line.map_err(|e| stream_error("Anthropic", e))?;
+        let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+            continue;
+        };
+        let event: AnthropicEvent = serde_json::from_str(data)
+            .map_err(|e| format!("Failed to parse Anthropic stream event: {}", e))?;
+        match event.kind.as_str() {
+            "message_start" => {
+                if let Some(reported) = event.message.and_then(|m| m.usage) {
+                    usage = Some(Usage {
+                        prompt_tokens: reported.input_tokens,
+                        completion_tokens: reported.output_tokens,
+                    });
+                }
+            }
+            "message_delta" => {
+                if let Some(reported) = event.usage {
+                    usage.get_or_insert_with(Usage::default).completion_tokens =
+                        reported.output_tokens;
+                }
+            }
+            "content_block_delta" => {
+                if let Some(text) = event.delta.and_then(|d| d.text) {
+                    if on_token(&text).is_break() {
+                        break;
+                    }
+                }
+            }
+            "message_stop" => break,
+            "error" => {
+                let message = event.error.map(|e| e.message).unwrap_or_default();
+                return Err(ChatError::Failed(format!(
+                    "Anthropic stream error: {}",
+                    message
+                )));
+            }
+            _ => {}
+        }
+    }
+    Ok(ChatOutcome {
+        usage,
+        tool_calls: Vec::new(),
+    })
+}
+
+/// The request `anthropic_chat` makes.
+pub fn anthropic_request(
+    api_key: &str,
+    model: &str,
+    messages: &[Message],
+    sampling: SamplingOptions,
+) -> HttpRequest {
+    let sampling = sampling.clamped();
+    let (system, messages) = anthropic_messages(messages);
+    let body = AnthropicRequest {
+        model,
+        system,
+        messages,
+        max_tokens: sampling.max_tokens,
+        // Anthropic accepts at most 1.0.
+        temperature: sampling.temperature.min(1.0),
+        stream: true,
+    };
+    HttpRequest::new(ANTHROPIC_MESSAGES_URL.to_string(), &body)
+        .secret_header("x-api-key", api_key)
+        .header("anthropic-version", ANTHROPIC_VERSION)
+}
+
+#[derive(Serialize)]
+struct OllamaChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<WireMessage<'a>>,
+    stream: bool,
+    options: OllamaOptions,
+}
+
+#[derive(Serialize)]
+struct OllamaOptions {
+    temperature: f32,
+    num_predict: i32,
+}
+
+#[derive(Deserialize)]
+struct OllamaChunkMessage {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct OllamaChatChunk {
+    message: Option<OllamaChunkMessage>,
+    #[serde(default)]
+    done: bool,
+}
+
+/// POST the conversation to an Ollama server's `/api/chat` endpoint and stream the
+/// newline-delimited JSON reply through `on_token`.
+pub fn ollama_chat(
+    base_url: &str,
+    model: &str,
+    messages: &[Message],
+    sampling: SamplingOptions,
+    options: RequestOptions,
+    on_retry: &mut dyn FnMut(u32) -> ControlFlow<()>,
+    mut on_token: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<(), ChatError> {
+    let client = http_client(options.timeout, &options.proxy)?;
+    let request = ollama_request(base_url, model, messages, sampling);
+    let response = send_with_retry(options.max_retries, on_retry, || request.send(&client))
+        .map_err(|e| {
+            if e.is_connect() {
+                ChatError::Unreachable(format!(
+                    "Could not connect to Ollama at {} - is it running?",
+                    base_url
+                ))
+            } else {
+                request_error("Ollama", e)
+            }
+        })?;
+    let response = check_status("Ollama", response)?;
+
+    for line in BufReader::new(response).lines() {
+        let line = line.map_err(|e| stream_error("Ollama", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chunk: OllamaChatChunk = serde_json::from_str(&line)
+            .map_err(|e| format!("Failed to parse Ollama stream chunk: {}", e))?;
+        if let Some(message) = chunk.message {
+            if on_token(&message.content).is_break() {
+                break;
+            }
+        }
+        if chunk.done {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The request `ollama_chat` makes.
+pub fn ollama_request(
+    base_url: &str,
+    model: &str,
+    messages: &[Message],
+    sampling: SamplingOptions,
+) -> HttpRequest {
+    let sampling = sampling.clamped();
+    let url = format!("{}/api/chat", base_url.trim_end_matches('/'));
+    let body = OllamaChatRequest {
+        model,
+        messages: wire_messages(messages),
+        stream: true,
+        options: OllamaOptions {
+            temperature: sampling.temperature,
+            num_predict: sampling.max_tokens,
+        },
+    };
+    HttpRequest::new(url, &body)
+}
+
+/// How a backend answered the latest health check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    /// Not checked since the backend or its address last changed.
+    Unknown,
+    Reachable,
+    /// The backend answered, but with an error such as a rejected API key.
+    Failing(String),
+    /// No connection could be made.
+    Unreachable(String),
+}
+
+/// A cheap request that shows whether a backend is up: a GET that needs no model and
+/// costs nothing, sent with the same credentials as a chat request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthTarget {
+    provider: &'static str,
+    url: String,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl HealthTarget {
+    /// The model list of an OpenAI-compatible provider at `base_url`.
+    pub fn openai(base_url: &str, api_key: &str) -> Self {
+        HealthTarget {
+            provider: "OpenAI",
+            url: format!("{}/models", base_url.trim_end_matches('/')),
+            headers: vec![("Authorization", format!("Bearer {}", api_key))],
+        }
+    }
+
+    /// The list of models an Ollama server has pulled.
+    pub fn ollama(url: &str) -> Self {
+        HealthTarget {
+            provider: "Ollama",
+            url: format!("{}/api/tags", url.trim_end_matches('/')),
+            headers: Vec::new(),
+        }
+    }
+
+    /// The health endpoint of a llama.cpp server, which fails while the model loads.
+    pub fn llamacpp(url: &str) -> Self {
+        HealthTarget {
+            provider: "llama.cpp",
+            url: format!("{}/health", url.trim_end_matches('/')),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Anthropic's model list.
+    pub fn anthropic(api_key: &str) -> Self {
+        HealthTarget {
+            provider: "Anthropic",
+            url: ANTHROPIC_MODELS_URL.to_string(),
+            headers: vec![
+                ("x-api-key", api_key.to_string()),
+                ("anthropic-version", ANTHROPIC_VERSION.to_string()),
+            ],
+        }
+    }
+
+    /// Send the request, giving up after `timeout`.
+    pub fn check(&self, timeout: Duration, proxy: &ProxySettings) -> Health {
+        let client = match http_client(timeout, proxy) {
+            Ok(client) => client,
+            Err(e) => return Health::Failing(e),
+        };
+        let mut request = client.get(&self.url);
+        for (name, value) in &self.headers {
+            request = request.header(*name, value);
+        }
+        match request.send() {
+            Ok(response) if response.status().is_success() => Health::Reachable,
+            Ok(response) => Health::Failing(format!(
+                "{} answered {} at {}",
+                self.provider,
+                response.status(),
+                self.url
+            )),
+            Err(e) if e.is_timeout() => Health::Unreachable(format!(
+                "{} did not answer at {} in time",
+                self.provider, self.url
+            )),
+            Err(e) => Health::Unreachable(format!(
+                "Could not reach {} at {}: {}",
+                self.provider, self.url, e
+            )),
+        }
+    }
+}
+
+/// Dimension of the offline embedding used by the stub backend.
+const STUB_EMBEDDING_DIM: usize = 256;
+
+/// Everything needed to embed text outside the UI thread.
+#[derive(Debug, Clone)]
+pub struct EmbeddingConfig {
+    pub backend: LlmBackend,
+    pub model: String,
+    pub api_key: String,
+    pub openai_base_url: String,
+    pub ollama_url: String,
+    pub llamacpp_url: String,
+    pub timeout: Duration,
+    pub proxy: ProxySettings,
+    /// How often a request rejected with 429 or 5xx, or that failed to connect, is
+    /// retried; see `send_with_retry`.
+    pub max_retries: u32,
+    /// Texts embedded per minute at most during indexing; 0 means no limit. See
+    /// `RateLimiter`.
+    pub max_per_minute: u32,
+}
+
+/// Spaces out embedding requests evenly so that no more than a given number of texts
+/// are embedded per minute, keeping large index runs under a provider's rate limit.
+pub struct RateLimiter {
+    /// Time one text takes up; `None` when unlimited.
+    interval: Option<Duration>,
+    /// When the next request may be sent.
+    next: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// A limiter allowing `limit` texts a minute; 0 never waits.
+    pub fn per_minute(limit: u32) -> Self {
+        RateLimiter {
+            interval: (limit > 0).then(|| Duration::from_secs(60) / limit),
+            next: None,
+        }
+    }
+
+    /// Sleep until a request embedding `count` texts may be sent, and reserve the time
+    /// they take up.
+    pub fn wait(&mut self, count: usize) {
+        std::thread::sleep(self.reserve(count, Instant::now()));
+    }
+
+    /// Reserve the time `count` texts take up for a request made at `now`, returning
+    /// how long it has to wait before being sent.
+    fn reserve(&mut self, count: usize, now: Instant) -> Duration {
+        let Some(interval) = self.interval else {
+            return Duration::ZERO;
+        };
+        let start = self.next.map_or(now, |next| next.max(now));
+        self.next = Some(start + interval * count as u32);
+        start - now
+    }
+}
+
+#[derive(Serialize)]
+struct OpenAiEmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+}
+
+#[derive(Serialize)]
+struct OpenAiBatchEmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbeddingResponse {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbedding {
+    embedding: Vec<f32>,
+    /// Position of the input this embeds, for batch requests.
+    #[serde(default)]
+    index: usize,
+}
+
+#[derive(Serialize)]
+struct OllamaEmbeddingRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbeddingResponse {
+    embedding: Vec<f32>,
+}
+
+#[derive(Serialize)]
+struct OllamaBatchEmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct OllamaBatchEmbeddingResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl EmbeddingConfig {
+    /// Whether embedding goes to a provider that bills for it; Anthropic has no
+    /// embeddings and falls back to the local ones.
+    pub fn is_paid(&self) -> bool {
+        self.backend == LlmBackend::OpenAi
+    }
+
+    /// Names the model the vectors come from; those of different models can't be
+    /// compared with each other.
+    pub fn model_id(&self) -> String {
+        match self.backend {
+            LlmBackend::Stub | LlmBackend::Anthropic => "hashed".to_string(),
+            LlmBackend::LlamaCpp => format!("llamacpp:{}", self.llamacpp_url.trim_end_matches('/')),
+            backend => format!("{}:{}", backend.key(), self.model),
+        }
+    }
+
+    /// Compute the embedding vector for `text` with the configured backend. Requests
+    /// that are rate limited or fail transiently are retried up to `max_retries` times.
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>, String> {
+        self.embed_texts(&[text.to_string()])?
+            .pop()
+            .ok_or_else(|| "The backend returned no embedding".to_string())
+    }
+
+    /// Compute the embeddings of `texts`, in order, with as few requests as the
+    /// backend allows: one for OpenAI and Ollama, one per text for llama.cpp.
+    pub fn embed_texts(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+        let mut keep_retrying = |_| ControlFlow::Continue(());
+        match self.backend {
+            // Anthropic offers no embeddings model, so retrieval falls back to local ones.
+            LlmBackend::Stub | LlmBackend::Anthropic => {
+                Ok(texts.iter().map(|text| hashed_embedding(text)).collect())
+            }
+            LlmBackend::OpenAi => {
+                let url = format!("{}/embeddings", self.openai_base_url.trim_end_matches('/'));
+                let client = http_client(self.timeout, &self.proxy)?;
+                let response = send_with_retry(self.max_retries, &mut keep_retrying, || {
+                    client
+                        .post(&url)
+                        .bearer_auth(&self.api_key)
+                        .json(&OpenAiBatchEmbeddingRequest {
+                            model: &self.model,
+                            input: texts,
+                        })
+                        .send()
+                })
+                .map_err(|e| format!("OpenAI embeddings request failed: {}", e))?;
+                let mut parsed: OpenAiEmbeddingResponse = check_status("OpenAI", response)?
+                    .json()
+                    .map_err(|e| format!("Failed to parse OpenAI embeddings: {}", e))?;
+                parsed.data.sort_by_key(|d| d.index);
+                let embeddings = parsed.data.into_iter().map(|d| d.embedding).collect();
+                batch_embeddings("OpenAI", texts.len(), embeddings)
+            }
+            LlmBackend::Ollama => {
+                let url = format!("{}/api/embed", self.ollama_url.trim_end_matches('/'));
+                let client = http_client(self.timeout, &self.proxy)?;
+                let response = send_with_retry(self.max_retries, &mut keep_retrying, || {
+                    client
+                        .post(&url)
+                        .json(&OllamaBatchEmbeddingRequest {
+                            model: &self.model,
+                            input: texts,
+                        })
+                        .send()
+                })
+                .map_err(|e| format!("Ollama embeddings request failed: {}", e))?;
+                // Versions before 0.3 only have the one-text endpoint.
+                if response.status() == reqwest::StatusCode::NOT_FOUND {
+                    return texts
+                        .iter()
+                        .map(|text| self.ollama_legacy_embedding(text))
+                        .collect();
+                }
+                let parsed: OllamaBatchEmbeddingResponse = check_status("Ollama", response)?
+                    .json()
+                    .map_err(|e| format!("Failed to parse Ollama embeddings: {}", e))?;
+                batch_embeddings("Ollama", texts.len(), parsed.embeddings)
+            }
+            // Servers differ in whether they accept several inputs at once.
+            LlmBackend::LlamaCpp => texts
+                .iter()
+                .map(|text| self.llamacpp_embedding(text))
+                .collect(),
+        }
+    }
+
+    /// Embed `text` through the one-text endpoint of Ollama versions before 0.3.
+    fn ollama_legacy_embedding(&self, text: &str) -> Result<Vec<f32>, String> {
+        let mut keep_retrying = |_| ControlFlow::Continue(());
+        let url = format!("{}/api/embeddings", self.ollama_url.trim_end_matches('/'));
+        let client = http_client(self.timeout, &self.proxy)?;
+        let response = send_with_retry(self.max_retries, &mut keep_retrying, || {
+            client
+                .post(&url)
+                .json(&OllamaEmbeddingRequest {
+                    model: &self.model,
+                    prompt: text,
+                })
+                .send()
+        })
+        .map_err(|e| format!("Ollama embeddings request failed: {}", e))?;
+        let parsed: OllamaEmbeddingResponse = check_status("Ollama", response)?
+            .json()
+            .map_err(|e| format!("Failed to parse Ollama embeddings: {}", e))?;
+        Ok(parsed.embedding)
+    }
+
+    /// Embed `text` with llama.cpp, which needs the server started with --embedding.
+    fn llamacpp_embedding(&self, text: &str) -> Result<Vec<f32>, String> {
+        let mut keep_retrying = |_| ControlFlow::Continue(());
+        let url = format!("{}/v1/embeddings", self.llamacpp_url.trim_end_matches('/'));
+        let client = http_client(self.timeout, &self.proxy)?;
+        let response = send_with_retry(self.max_retries, &mut keep_retrying, || {
+            client
+                .post(&url)
+                .json(&OpenAiEmbeddingRequest {
+                    model: LLAMACPP_MODEL,
+                    input: text,
+                })
+                .send()
+        })
+        .map_err(|e| format!("llama.cpp embeddings request failed: {}", e))?;
+        let parsed: OpenAiEmbeddingResponse = check_status("llama.cpp", response)?
+            .json()
+            .map_err(|e| format!("Failed to parse llama.cpp embeddings: {}", e))?;
+        parsed
+            .data
+            .into_iter()
+            .next()
+            .map(|d| d.embedding)
+            .ok_or_else(|| "llama.cpp returned no embeddings".to_string())
+    }
+}
+
+/// `embeddings` as the answer to a batch of `expected` texts, unless some are missing.
+fn batch_embeddings(
+    provider: &str,
+    expected: usize,
+    embeddings: Vec<Vec<f32>>,
+) -> Result<Vec<Vec<f32>>, String> {
+    if embeddings.len() == expected {
+        Ok(embeddings)
+    } else {
+        Err(format!(
+            "{} returned {} embeddings for {} texts",
+            provider,
+            embeddings.len(),
+            expected
+        ))
+    }
+}
+
+/// Cheap bag-of-words embedding: every lowercase word is hashed into one of a fixed
+/// number of buckets and the result is L2-normalised. Good enough to exercise
+/// retrieval without any model running. The hash is fixed, so vectors stored by one
+/// build still match queries embedded by another.
+pub fn hashed_embedding(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0f32; STUB_EMBEDDING_DIM];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        let hash = blake3::hash(word.to_lowercase().as_bytes());
+        let bucket = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+        vector[(bucket % STUB_EMBEDDING_DIM as u64) as usize] += 1.0;
+    }
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_embedding_buckets_do_not_change_between_builds() {
+        let embedding = hashed_embedding("Hello, hello");
+        assert_eq!(embedding.len(), STUB_EMBEDDING_DIM);
+        assert_eq!(embedding[234], 1.0);
+        assert_eq!(embedding.iter().filter(|v| **v != 0.0).count(), 1);
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn rate_limiter_without_a_limit_never_waits() {
+        let mut limiter = RateLimiter::per_minute(0);
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(limiter.reserve(64, now), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn rate_limiter_spaces_requests_by_their_size() {
+        // 50 ms per text.
+        let mut limiter = RateLimiter::per_minute(1200);
+        let start = Instant::now();
+        assert_eq!(limiter.reserve(2, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(1, start), ms(100));
+        assert_eq!(limiter.reserve(1, start + ms(30)), ms(120));
+    }
+
+    #[test]
+    fn rate_limiter_does_not_bank_idle_time() {
+        let mut limiter = RateLimiter::per_minute(1200);
+        let start = Instant::now();
+        limiter.reserve(1, start);
+        let resumed = start + ms(120);
+        assert_eq!(limiter.reserve(1, resumed), Duration::ZERO);
+        assert_eq!(limiter.reserve(1, resumed), ms(50));
+    }
+}
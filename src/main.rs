@@ -1,29 +1,254 @@
-use std::path::PathBuf;
+mod ann;
+mod backup;
+mod chatgpt;
+mod cli;
+mod error;
+mod error_log;
+mod hooks;
+mod indexer;
+mod llm;
+mod markdown;
+mod migrations;
+mod retrieval;
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 
+use clap::Parser;
 use directories::ProjectDirs;
 use eframe::{
     egui::{self, CentralPanel, Context, ScrollArea, SidePanel, TopBottomPanel, Ui},
     App, Frame, NativeOptions,
 };
-// use egui_extras::markdown::Markdown;
+use egui_commonmark::CommonMarkCache;
+use error::AppError;
+use hooks::MessageProcessor;
+use indexer::IndexEvent;
+use llm::{LlmBackend, StreamEvent};
+use retrieval::ScoredChunk;
 use rusqlite::{params, Connection};
-use serde::{Deserialize, Serialize};
-use serde_json;
-use std::sync::{Arc, Mutex};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::ops::{ControlFlow, Range};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// How long the input must sit unchanged before the draft is written to the database.
+const DRAFT_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the open conversation and draft are written out regardless of changes,
+/// so little is lost if the process is killed.
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the active backend is pinged for the status bar, how long it gets to
+/// answer, and how soon a change of backend or address in the saved settings is
+/// noticed.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const HEALTH_CHECK_POLL: Duration = Duration::from_secs(1);
+
+/// Wait before a queued message is sent again; doubled after every failed attempt.
+const OUTBOX_BASE_DELAY: Duration = Duration::from_secs(5);
+const OUTBOX_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Longest text taken from a dropped file; the rest is cut off.
+const ATTACHMENT_MAX_CHARS: usize = 20_000;
+
+/// Pastes longer than this are not inserted right away; the user is asked whether to
+/// attach them instead.
+const LARGE_PASTE_CHARS: usize = 4000;
+
+/// Roles a message can be sent as. Only user messages are answered; the others are
+/// just added to the conversation, e.g. to build few-shot examples.
+const INPUT_ROLES: [&str; 3] = ["user", "system", "assistant"];
+
+/// How many of the latest questions and replies the backend sees when rewriting a
+/// question for retrieval.
+const QUERY_EXPANSION_HISTORY: usize = 4;
+
+/// What the backend is asked to do with a question before it is embedded.
+const QUERY_EXPANSION_INSTRUCTION: &str =
+    "Rewrite the question below as a standalone search query for finding relevant \
+     passages in the user's documents. Spell out anything it refers to from the \
+     conversation so far and add likely synonyms and related terms. Reply with the \
+     query only.";
+
+/// Title of a conversation nobody has named yet. The first question replaces it, and
+/// once that is answered the backend is asked for a better one.
+const DEFAULT_CONVERSATION_TITLE: &str = "New conversation";
+
+/// Longest automatic title, from the first question or the backend; the rest is
+/// cut off.
+const GENERATED_TITLE_MAX_CHARS: usize = 60;
+
+/// A manual index run expected to send more tokens than this to a paid embedding
+/// backend shows its estimated cost and asks before starting.
+const LARGE_INDEX_RUN_TOKENS: usize = 500_000;
+
+/// Range of the interface zoom, as a factor of the display's own scale, and how much
+/// the zoom buttons and shortcuts change it.
+const UI_SCALE_MIN: f32 = 0.5;
+const UI_SCALE_MAX: f32 = 3.0;
+const UI_SCALE_STEP: f32 = 0.1;
+
+/// Hosts that skip a configured proxy unless told otherwise: local backends such as
+/// Ollama are rarely reachable through a corporate proxy.
+const DEFAULT_NO_PROXY: &str = "localhost, 127.0.0.1, ::1";
+
+/// Environment variable pointing at the database file to use instead of the one in
+/// the platform's config directory. `--db-path` takes precedence over it.
+const DB_PATH_ENV: &str = "INDEXEDRAG_DB_PATH";
+
+const KEYRING_SERVICE: &str = "indexedRAG";
+const KEYRING_API_KEY_USER: &str = "api_key";
+const KEYRING_ANTHROPIC_API_KEY_USER: &str = "anthropic_api_key";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String, // e.g. "user", "assistant", "system"
     pub content: String,
+    /// The reply was stopped by the user before it finished.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interrupted: bool,
+    /// Indexed chunks that were given to the model as context for this reply, best
+    /// match first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Source>,
+    /// Unix time the message was created; unknown for messages saved by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+    /// A question the backend could not be reached for; it is sent again later.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub queued: bool,
+    /// Files dropped onto the window and sent along with this message only.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// Context was looked up for this reply, but no indexed chunk was similar enough.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_relevant_context: bool,
+    /// Tokens a paid backend billed for this reply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<llm::Usage>,
+    /// The reply links to images, which are loaded and shown inline. Set once the
+    /// reply is complete, so half-streamed URLs aren't fetched.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub has_images: bool,
+    /// Bookmarked by the user; listed under "Starred" in the side panel.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub starred: bool,
+    /// Functions the assistant asked to call in this reply. They are shown, not run.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<llm::ToolCall>,
+    /// The backend failed while answering this question, or while writing this
+    /// partial reply; holds the error until a retry succeeds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed: Option<String>,
+}
+
+/// Text of a dropped file, given to the model as context without being indexed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub content: String,
+    /// The file was longer than `ATTACHMENT_MAX_CHARS` and was cut off.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// Where a retrieved passage came from. The sources stored with a reply also keep
+/// what was retrieved, for the context inspector; replies saved by older versions
+/// lack it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Source {
+    pub path: String,
+    pub chunk_index: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<i64>,
+    /// Cosine similarity of the chunk to the question.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+    /// The chunk's text, as given to the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (chunk {}", self.path, self.chunk_index)?;
+        if let Some(page) = self.page {
+            write!(f, ", page {}", page)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl Message {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Message {
+            role: role.into(),
+            content: content.into(),
+            interrupted: false,
+            sources: Vec::new(),
+            created_at: Some(chrono::Utc::now().timestamp()),
+            queued: false,
+            attachments: Vec::new(),
+            no_relevant_context: false,
+            usage: None,
+            has_images: false,
+            starred: false,
+            tool_calls: Vec::new(),
+            failed: None,
+        }
+    }
+
+    /// The content as sent to a backend: the message text followed by any attachments.
+    pub fn request_content(&self) -> Cow<'_, str> {
+        if self.attachments.is_empty() {
+            return Cow::Borrowed(&self.content);
+        }
+        let mut content = self.content.clone();
+        for attachment in &self.attachments {
+            content.push_str(&format!(
+                "\n\n--- Attached file: {} ---\n{}\n--- End of {} ---",
+                attachment.name,
+                attachment.content.trim_end(),
+                attachment.name
+            ));
+        }
+        Cow::Owned(content)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Conversation {
     pub id: i64,
+    #[serde(default)]
+    pub title: String,
+    /// The title was chosen by the user, so automatic titles leave it alone.
+    #[serde(skip)]
+    pub title_manual: bool,
     pub messages: Vec<Message>,
+    /// Model used for this conversation instead of the one configured for the backend.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Conversation this one was forked from. Only meaningful within one database,
+    /// so it is left out of exports.
+    #[serde(skip)]
+    pub parent_id: Option<i64>,
+    /// Labels the conversation list can be filtered by.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Root paths whose files retrieval is limited to; empty means the whole index.
+    /// Paths on this machine, so left out of exports.
+    #[serde(skip)]
+    pub scope: Vec<String>,
+    /// Overview written by the backend on request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,43 +256,861 @@ pub struct AppSettings {
     pub id: i64,
     pub root_paths: Vec<String>,
     pub index_interval_minutes: i32,
+    pub backend: LlmBackend,
+    pub model: String,
+    /// Lives in the OS keychain, never in the settings table.
+    #[serde(skip)]
+    pub api_key: String,
+    pub ollama_url: String,
+    pub ollama_model: String,
+    /// API root of the OpenAI backend; any provider with an OpenAI-compatible API works.
+    pub openai_base_url: String,
+    pub llamacpp_url: String,
+    pub anthropic_model: String,
+    /// Kept in the OS keychain like `api_key`.
+    #[serde(skip)]
+    pub anthropic_api_key: String,
+    pub embedding_model: String,
+    /// Texts embedded per minute at most while indexing; 0 means no limit.
+    pub embeddings_per_minute: i32,
+    /// US dollars per million embedded tokens, for estimating what an index run costs.
+    pub embedding_price_per_million: f64,
+    pub retrieval_top_k: i32,
+    /// Cosine similarity below which a retrieved chunk is not given to the model.
+    pub min_similarity: f32,
+    /// Have the backend rewrite each question before it is embedded for retrieval;
+    /// see `query_expansion`. Costs an extra request per question.
+    pub expand_queries: bool,
+    /// Leading system message for new conversations and for every request sent to a
+    /// real backend. Empty means none.
+    pub system_prompt: String,
+    pub temperature: f32,
+    pub max_tokens: i32,
+    /// Size of the model's context window; the conversation size is flagged past it.
+    pub context_limit_tokens: i32,
+    /// How often a request that failed transiently (network error, 429, 5xx) is retried.
+    pub max_retries: i32,
+    /// How long a single request may take, including streaming the reply.
+    pub request_timeout_seconds: i32,
+    /// US dollars per million prompt and completion tokens, for estimating what the
+    /// replies of paid backends cost.
+    pub prompt_price_per_million: f64,
+    pub completion_price_per_million: f64,
+    /// Estimated prompt size above which sending asks for confirmation; 0 never asks.
+    pub max_prompt_tokens: i32,
+    /// Zoom of the whole interface on top of the display's scale; 1.0 is unscaled.
+    pub ui_scale: f32,
+    /// Only the last this many messages are sent with a question, besides the system
+    /// prompt; 0 sends the whole (pruned) history.
+    pub history_window: usize,
+    /// Proxy for backend requests and the hosts that bypass it; see
+    /// `llm::ProxySettings`.
+    pub proxy_url: String,
+    pub no_proxy: String,
+    /// Replace what looks like a key or password in typed messages before they are
+    /// stored or sent; see `hooks::SecretRedactor`.
+    pub redact_secrets: bool,
+    /// File extensions the indexer picks up; empty means all.
+    pub allowed_extensions: Vec<String>,
+    pub max_file_size_bytes: i64,
+    /// Skip files excluded by .gitignore/.ignore rules, and hidden files.
+    pub respect_gitignore: bool,
+    /// Index the stored conversations too, so retrieval can draw on earlier answers.
+    pub index_conversations: bool,
+    /// Length of an indexed chunk and how much of it repeats the previous chunk;
+    /// changing either re-chunks every file on the next index run.
+    pub chunk_size_chars: i32,
+    pub chunk_overlap_chars: i32,
+    /// Conversation reopened on start. Written on its own whenever the open
+    /// conversation changes, not by the settings form.
+    pub last_opened_conversation_id: Option<i64>,
+}
+
+impl AppSettings {
+    fn embedding_config(&self) -> llm::EmbeddingConfig {
+        llm::EmbeddingConfig {
+            backend: self.backend,
+            model: self.embedding_model.clone(),
+            api_key: self.api_key.clone(),
+            openai_base_url: self.openai_base_url.clone(),
+            ollama_url: self.ollama_url.clone(),
+            llamacpp_url: self.llamacpp_url.clone(),
+            timeout: self.request_options().timeout,
+            proxy: self.proxy(),
+            max_retries: self.request_options().max_retries,
+            max_per_minute: self.embeddings_per_minute.max(0) as u32,
+        }
+    }
+
+    /// Estimated cost of `usage` in US dollars at the configured prices.
+    fn usage_cost(&self, usage: llm::Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt_price_per_million
+            + usage.completion_tokens as f64 * self.completion_price_per_million)
+            / 1_000_000.0
+    }
+
+    fn request_options(&self) -> llm::RequestOptions {
+        llm::RequestOptions {
+            max_retries: self.max_retries.max(0) as u32,
+            timeout: Duration::from_secs(self.request_timeout_seconds.max(1) as u64),
+            proxy: self.proxy(),
+        }
+    }
+
+    fn proxy(&self) -> llm::ProxySettings {
+        llm::ProxySettings {
+            url: self.proxy_url.trim().to_string(),
+            no_proxy: self.no_proxy.trim().to_string(),
+        }
+    }
+
+    /// The hooks run on messages going out and coming in.
+    fn message_processor(&self) -> Box<dyn MessageProcessor> {
+        if self.redact_secrets {
+            Box::new(hooks::SecretRedactor)
+        } else {
+            Box::new(hooks::NoProcessing)
+        }
+    }
+
+    /// What the health check pings for the selected backend; nothing for the stub,
+    /// which is always there.
+    fn health_target(&self) -> Option<llm::HealthTarget> {
+        match self.backend {
+            LlmBackend::Stub => None,
+            LlmBackend::OpenAi => Some(llm::HealthTarget::openai(
+                self.openai_base_url.trim(),
+                &self.api_key,
+            )),
+            LlmBackend::Ollama => Some(llm::HealthTarget::ollama(&self.ollama_url)),
+            LlmBackend::LlamaCpp => Some(llm::HealthTarget::llamacpp(&self.llamacpp_url)),
+            LlmBackend::Anthropic => Some(llm::HealthTarget::anthropic(&self.anthropic_api_key)),
+        }
+    }
+
+    /// The model configured for the selected backend, if it lets one be chosen.
+    fn backend_model(&self) -> Option<&str> {
+        match self.backend {
+            LlmBackend::OpenAi => Some(&self.model),
+            LlmBackend::Ollama => Some(&self.ollama_model),
+            LlmBackend::Anthropic => Some(&self.anthropic_model),
+            LlmBackend::Stub | LlmBackend::LlamaCpp => None,
+        }
+    }
+
+    fn chunking(&self) -> indexer::Chunking {
+        indexer::Chunking {
+            size_chars: self.chunk_size_chars.max(1) as usize,
+            overlap_chars: self.chunk_overlap_chars.max(0) as usize,
+        }
+        .clamped()
+    }
+
+    fn file_filter(&self) -> indexer::FileFilter {
+        indexer::FileFilter {
+            allowed_extensions: self
+                .allowed_extensions
+                .iter()
+                .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect(),
+            max_file_size_bytes: self.max_file_size_bytes.max(0) as u64,
+            respect_ignore_files: self.respect_gitignore,
+            index_conversations: self.index_conversations,
+        }
+    }
+
+    fn sampling(&self) -> llm::SamplingOptions {
+        llm::SamplingOptions {
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+        }
+        .clamped()
+    }
+}
+
+/// Delivery attempts for the queued question at the end of the open conversation.
+struct OutboxRetry {
+    attempts: u32,
+    next_attempt: Instant,
+    error: String,
+}
+
+impl OutboxRetry {
+    fn immediate() -> Self {
+        OutboxRetry {
+            attempts: 0,
+            next_attempt: Instant::now(),
+            error: String::new(),
+        }
+    }
+}
+
+/// Where the main window was and how big it was, in egui points.
+#[derive(Debug, Clone, Copy)]
+struct WindowGeometry {
+    position: Option<egui::Pos2>, // Unknown on some platforms, e.g. Wayland
+    size: egui::Vec2,
+}
+
+/// A question held back because its request is larger than `max_prompt_tokens`.
+struct LargePrompt {
+    /// Estimated size of the whole request, with room for the retrieved context.
+    tokens: usize,
+}
+
+/// Everything needed to retrieve context for a question on a thread of its own, away
+/// from the UI, with its own database connection.
+struct RetrievalJob {
+    query: String,
+    /// Turns `query` into a better one before it is embedded, when set.
+    expansion: Option<QueryExpansion>,
+    k: usize,
+    min_similarity: f32,
+    scope: Vec<String>,
+    /// Chunks stored under this path are left out.
+    skip_path: Option<String>,
+    embedder: llm::EmbeddingConfig,
+    ann_index: SharedAnnIndex,
+    db_path: PathBuf,
+}
+
+impl RetrievalJob {
+    /// The `k` indexed chunks most similar to the query, after query expansion,
+    /// leaving out any below `min_similarity`, outside `scope` or under `skip_path`.
+    /// Fails when embeddings or the index are unavailable; callers carry on without
+    /// context and log the reason. Setting `cancel` stops query expansion.
+    fn run(self, cancel: &AtomicBool) -> Result<Vec<ScoredChunk>, String> {
+        let conn = IndexedragApp::open_connection(&self.db_path)
+            .map_err(|e| format!("Could not open the database: {}", e))?;
+        let query = match self.expansion {
+            Some(expansion) => expansion.run(&self.query, &conn, cancel),
+            None => self.query,
+        };
+        let query_embedding = self.embedder.embed(&query)?;
+        // Small indexes, and queries embedded by a different model than the graph, are
+        // searched exhaustively.
+        let ann_index = self
+            .ann_index
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|ann| ann.dim() == query_embedding.len());
+        let skip_path = self.skip_path.as_deref();
+        let result = match ann_index {
+            Some(ann) => retrieval::ann_top_k_chunks(
+                &conn,
+                &ann,
+                &query_embedding,
+                self.k,
+                self.min_similarity,
+                &self.scope,
+                skip_path,
+            ),
+            None => retrieval::top_k_chunks(
+                &conn,
+                &query_embedding,
+                self.k,
+                self.min_similarity,
+                &self.scope,
+                skip_path,
+            ),
+        };
+        result.map_err(|e| e.to_string())
+    }
+}
+
+/// A request asking the backend to rewrite a question into a standalone search
+/// query; see `IndexedragApp::query_expansion`.
+struct QueryExpansion {
+    request: BackendRequest,
+    messages: Vec<Message>,
+}
+
+impl QueryExpansion {
+    /// The expanded query, or `question` itself if the backend fails or `cancel` is
+    /// set. The request is not retried, as the user is waiting on it.
+    fn run(self, question: &str, conn: &Connection, cancel: &AtomicBool) -> String {
+        let mut expanded = String::new();
+        let result = (self.request)(
+            &self.messages,
+            &mut |_| ControlFlow::Break(()),
+            &mut |token| {
+                if cancel.load(Ordering::Relaxed) {
+                    return ControlFlow::Break(());
+                }
+                expanded.push_str(token);
+                ControlFlow::Continue(())
+            },
+        );
+        match result {
+            _ if cancel.load(Ordering::Relaxed) => question.to_string(),
+            Ok(_) if !expanded.trim().is_empty() => expanded.trim().to_string(),
+            Ok(_) => question.to_string(),
+            Err(e) => {
+                let _ = error_log::write(conn, "query expansion", &e.to_string());
+                question.to_string()
+            }
+        }
+    }
+}
+
+/// The context a reply is given.
+enum ReplyContext {
+    /// Passages already at hand, such as those of the reply being regenerated.
+    Passages(Vec<ScoredChunk>),
+    /// Passages to retrieve on the request thread before asking the backend.
+    Retrieve(Box<RetrievalJob>),
+}
+
+/// The request sending the current input would make, shown in the preview window.
+struct PromptPreview {
+    messages: Vec<Message>,
+    /// Delivers the retrieved context, which is then added to `messages`.
+    context_rx: Option<Receiver<Result<Vec<ScoredChunk>, String>>>,
+}
+
+/// A manual index run held back because embedding it is expected to cost more than
+/// `LARGE_INDEX_RUN_TOKENS` would.
+struct LargeIndexRun {
+    full: bool,
+    /// Estimated tokens sent to the embedding backend.
+    tokens: usize,
+}
+
+/// What a `SummaryJob` asks the backend to write about a conversation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SummaryKind {
+    Summary,
+    Title,
+}
+
+impl SummaryKind {
+    /// The request appended to the conversation.
+    fn instruction(self) -> &'static str {
+        match self {
+            SummaryKind::Summary => {
+                "Summarize the conversation so far in a short paragraph: what was asked, \
+                 what was found out and what is still open. Reply with the summary only."
+            }
+            SummaryKind::Title => {
+                "Write a title of at most six words for the conversation so far. Reply with \
+                 the title only, without quotes."
+            }
+        }
+    }
+}
+
+/// A background request condensing a conversation into a summary or a title.
+struct SummaryJob {
+    conversation_id: i64,
+    kind: SummaryKind,
+    /// The backend's reply, or why it failed.
+    rx: Receiver<Result<String, String>>,
+}
+
+/// A chat request to the active backend, ready to run on a background thread. It
+/// gets the messages to send and callbacks for retries and streamed tokens.
+type BackendRequest = Box<
+    dyn FnOnce(
+            &[Message],
+            &mut dyn FnMut(u32) -> ControlFlow<()>,
+            &mut dyn FnMut(&str) -> ControlFlow<()>,
+        ) -> Result<llm::ChatOutcome, llm::ChatError>
+        + Send,
+>;
+
+/// Passphrase prompt for a backup, open once its file has been chosen.
+struct BackupDialog {
+    path: PathBuf,
+    /// Restore from `path` rather than export to it.
+    restore: bool,
+    passphrase: String,
+    /// Export only: the passphrase typed again.
+    confirmation: String,
+    /// Export only: put the API keys from the keyring in the backup too.
+    include_keys: bool,
+}
+
+/// The nearest-neighbour graph, if the index is large enough to have one. The graph
+/// itself is immutable; the indexer threads swap in a new one after each run.
+type SharedAnnIndex = Arc<Mutex<Option<Arc<ann::AnnIndex>>>>;
+
+/// The settings as last saved, for threads that outlive a single request. Edits in
+/// the settings window only show up here once they are saved.
+type SharedSettings = Arc<RwLock<AppSettings>>;
+
+/// Outcome of the latest health check of the active backend, written by the health
+/// check thread.
+type SharedHealth = Arc<Mutex<llm::Health>>;
+
+/// A write handed to the database worker thread, so the UI thread never waits on
+/// disk I/O for the frequent saves.
+enum DbCommand {
+    SaveConversation {
+        id: i64,
+        messages: Vec<Message>,
+    },
+    SaveDraft {
+        conversation_id: i64,
+        draft: String,
+    },
+    SaveSettings(Box<AppSettings>),
+    SaveLastOpenedConversation(i64),
+    SaveUiScale(f32),
+    LogError {
+        context: String,
+        message: String,
+    },
+    ClearErrorLog,
+    /// Acknowledged once every command queued before it has been applied.
+    Flush(mpsc::Sender<()>),
 }
 
 pub struct IndexedragApp {
-    result: Arc<Mutex<Option<String>>>, // Shared state for computation result
+    stream_rx: Option<Receiver<StreamEvent>>, // Tokens of the reply being generated
+    cancel_flag: Option<Arc<AtomicBool>>,     // Set to stop the in-flight request
+    retry_attempt: Option<u32>,               // Retry of the in-flight request currently waited on
+    outbox_retry: Option<OutboxRetry>,        // Set while the conversation ends in a queued message
     conn: Connection,
     conversation: Conversation,
+    conversations: Vec<(i64, String, Option<i64>, Vec<String>)>, // (id, title, last activity, tags) for the side panel
+    tag_filter: Option<String>, // Only conversations with this tag are listed
+    new_tag: String,            // Tag being typed in for the open conversation
+    renaming: Option<(i64, String)>, // Conversation being renamed and the edited title
+    editing_message: Option<(usize, String)>, // Message being edited and its new content
+    selecting_message: Option<usize>, // Message shown as raw, selectable text
+    last_retrieval: Option<(i64, Vec<ScoredChunk>)>, // Passages used for the latest reply, by conversation id
+    window_geometry: Option<WindowGeometry>,         // Saved on close, restored on the next launch
     current_input: String,
+    input_role: &'static str,          // Role the next message is sent as
+    attachments: Vec<Attachment>, // Dropped files and pastes waiting to go out with the next message
+    pending_paste: Option<String>, // Large paste held back until the user decides where it goes
+    draft_changed_at: Option<Instant>, // Set while current_input has unsaved edits
+    last_autosave: Instant,       // When save_all last ran
+    markdown_cache: CommonMarkCache,
     settings_open: bool,
     settings: AppSettings,
+    index_status: Option<String>, // Outcome of the last manual reindex
+    index_rx: Option<Receiver<IndexEvent>>, // Progress of the manual reindex in flight
+    index_cancel: Option<Arc<AtomicBool>>, // Set to stop the manual reindex
+    index_progress: (usize, usize), // (files done, files total) of the manual reindex
+    index_full: bool,             // The manual reindex in flight re-reads unchanged files too
+    index_stats: Option<indexer::IndexStats>, // Shown in the statistics window while set
+    confirm_clear_index: bool,    // The "clear index" confirmation is open
+    large_index_run: Option<LargeIndexRun>, // Reindex held back until the user confirms its cost
+    selected_conversations: Option<BTreeSet<i64>>, // Ticked in the conversation list while selecting
+    confirm_bulk_delete: bool, // The "delete selected conversations" confirmation is open
+    large_prompt: Option<LargePrompt>, // Send held back until the user confirms its size
+    prompt_preview: Option<PromptPreview>, // Assembled request shown in the preview window
+    backup_dialog: Option<BackupDialog>, // Passphrase prompt of a backup export or restore
+    backup_export_rx: Option<Receiver<Result<(), AppError>>>, // Outcome of the backup export in flight
+    summary_jobs: Vec<SummaryJob>, // Summaries and titles being written by the backend
+    last_request: Option<llm::HttpRequest>, // The latest reply request, for "Copy as cURL"
+    error_log: Option<Vec<error_log::LogEntry>>, // Shown in the error log window while set
+    error_log_refresh: Option<Receiver<()>>, // Queued writes the error log reload waits for
+    db_path: PathBuf,
+    db_tx: mpsc::Sender<DbCommand>, // Writes applied by the database worker thread
+    db_errors: Receiver<AppError>,  // Failures reported back by the database worker
+    last_indexed_at: Arc<Mutex<Option<i64>>>, // Unix time of the last index run, shared with the indexer thread
+    ann_index: SharedAnnIndex, // Nearest-neighbour graph for retrieval, rebuilt by the indexer threads
+    saved_settings: SharedSettings, // Read by the background indexer
+    settings_changed: mpsc::Sender<()>, // Wakes the background indexer after a save
+    backend_health: Option<SharedHealth>, // Shown in the status bar; checked once the window is up
+    message_processor: Box<dyn MessageProcessor>, // Hooks for the saved settings, run on messages out and in
+    error_message: Option<String>,                // Shown in an error window until dismissed
+    document_query: String,
+    document_results: Vec<(String, String)>, // (path, snippet) matches for document_query
+    conversation_query: String,
+    conversation_results: Vec<(i64, String)>, // (conversation id, snippet) matches for conversation_query
+    scroll_to_message: Option<usize>,         // Message to bring into view on the next frame
+    starred: Vec<(i64, usize, String)>, // (conversation id, message index, preview) of starred messages in other conversations
 }
 
 impl IndexedragApp {
-    pub fn new() -> Self {
-        let db_path = Self::get_db_path();
-        if let Some(parent) = db_path.parent() {
-            std::fs::create_dir_all(parent).expect("Could not create config directory");
-        }
-        let conn = Connection::open(&db_path).expect("Failed to open DB");
-        Self::initialize_db(&conn);
-        let conversation = Self::load_or_create_default_conversation(&conn);
-        let settings = Self::load_or_create_default_settings(&conn);
-        IndexedragApp {
-            result: Arc::new(Mutex::new(None)),
+    /// Open the app on the database at `db_path`, or on the default one (see
+    /// `get_db_path`) when it's `None`.
+    pub fn new(db_path: Option<PathBuf>) -> Result<Self, AppError> {
+        let db_path = Self::get_db_path(db_path);
+        if let Some(parent) = db_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|source| AppError::ConfigDir {
+                path: parent.to_path_buf(),
+                source,
+            })?;
+            Self::check_writable(parent)?;
+        }
+        let mut conn = Self::open_connection(&db_path)?;
+        migrations::migrate_with_backup(&mut conn, &db_path)?;
+        // Stored JSON that no longer parses; reported once the window is up.
+        let mut damaged = Vec::new();
+        let settings = Self::load_or_create_default_settings(&conn, &mut damaged)?;
+        let message_processor = settings.message_processor();
+        // The stored conversation may have been deleted since.
+        let conversation = match settings.last_opened_conversation_id {
+            Some(id) => Self::load_conversation(&conn, id, &mut damaged)?,
+            None => None,
+        };
+        let conversation = match conversation {
+            Some(conversation) => Some(conversation),
+            None => match Self::list_conversations(&conn)?.first() {
+                Some((id, _, _, _)) => Self::load_conversation(&conn, *id, &mut damaged)?,
+                None => None,
+            },
+        };
+        for e in &damaged {
+            let _ = error_log::write(&conn, "database", &e.to_string());
+        }
+        let conversation = match conversation {
+            Some(conversation) => conversation,
+            None => Self::create_conversation(&conn, &settings.system_prompt)?,
+        };
+        let outbox_retry = conversation
+            .messages
+            .last()
+            .is_some_and(|m| m.queued)
+            .then(OutboxRetry::immediate);
+        let conversations = Self::list_conversations(&conn)?;
+        let current_input = Self::load_draft(&conn, conversation.id)?;
+        let window_geometry = Self::load_window_geometry(&conn)?;
+        let last_indexed_at = Arc::new(Mutex::new(indexer::last_indexed_at(&conn)?));
+        let ann_index = SharedAnnIndex::default();
+        let saved_settings = Arc::new(RwLock::new(settings.clone()));
+        let (settings_changed, settings_rx) = mpsc::channel();
+        Self::spawn_background_indexer(
+            db_path.clone(),
+            Arc::clone(&saved_settings),
+            settings_rx,
+            Arc::clone(&last_indexed_at),
+            Arc::clone(&ann_index),
+        );
+        let (db_tx, db_errors) = Self::spawn_db_writer(Self::open_connection(&db_path)?);
+        let starred = Self::list_starred(&conn, conversation.id)?;
+        Ok(IndexedragApp {
+            stream_rx: None,
+            outbox_retry,
+            cancel_flag: None,
+            retry_attempt: None,
             conn,
             conversation,
-            current_input: String::new(),
+            conversations,
+            tag_filter: None,
+            new_tag: String::new(),
+            renaming: None,
+            editing_message: None,
+            selecting_message: None,
+            last_retrieval: None,
+            window_geometry,
+            current_input,
+            input_role: INPUT_ROLES[0],
+            attachments: Vec::new(),
+            pending_paste: None,
+            draft_changed_at: None,
+            last_autosave: Instant::now(),
+            markdown_cache: CommonMarkCache::default(),
             settings_open: false,
             settings,
+            index_status: None,
+            index_rx: None,
+            index_cancel: None,
+            index_progress: (0, 0),
+            index_full: false,
+            index_stats: None,
+            confirm_clear_index: false,
+            large_index_run: None,
+            selected_conversations: None,
+            confirm_bulk_delete: false,
+            large_prompt: None,
+            prompt_preview: None,
+            backup_dialog: None,
+            backup_export_rx: None,
+            summary_jobs: Vec::new(),
+            last_request: None,
+            error_log: None,
+            error_log_refresh: None,
+            db_path,
+            db_tx,
+            db_errors,
+            last_indexed_at,
+            ann_index,
+            saved_settings,
+            settings_changed,
+            backend_health: None,
+            message_processor,
+            error_message: (!damaged.is_empty()).then(|| {
+                damaged
+                    .iter()
+                    .map(AppError::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n\n")
+            }),
+            document_query: String::new(),
+            document_results: Vec::new(),
+            conversation_query: String::new(),
+            conversation_results: Vec::new(),
+            scroll_to_message: None,
+            starred,
+        })
+    }
+
+    /// Fail early with a clear error when `dir` can't hold the database, instead of
+    /// SQLite's generic one on the first write.
+    fn check_writable(dir: &Path) -> Result<(), AppError> {
+        let probe = dir.join(".indexedRAG-write-test");
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&probe)
+            .and_then(|_| std::fs::remove_file(&probe))
+            .map_err(|source| AppError::DataDirNotWritable {
+                path: dir.to_path_buf(),
+                source,
+            })
+    }
+
+    /// Open the database with settings suitable for sharing it between the UI and
+    /// the background indexer: WAL lets readers proceed during an index write, and
+    /// the busy timeout makes the other writer wait instead of failing immediately.
+    fn open_connection(db_path: &Path) -> Result<Connection, AppError> {
+        let conn = Connection::open(db_path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.busy_timeout(Duration::from_secs(10))?;
+        Ok(conn)
+    }
+
+    /// Re-index on a timer in a thread with its own connection, using `settings`. A
+    /// message on `changed` means they were saved: the wait is worked out again from
+    /// the new interval, so a shorter one can start a run right away. An interval of
+    /// zero or less disables the timer. The thread ends once the app is gone.
+    fn spawn_background_indexer(
+        db_path: PathBuf,
+        settings: SharedSettings,
+        changed: Receiver<()>,
+        last_indexed_at: Arc<Mutex<Option<i64>>>,
+        ann_index: SharedAnnIndex,
+    ) {
+        thread::spawn(move || {
+            let conn = match Self::open_connection(&db_path) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("Background indexer disabled: {}", e);
+                    return;
+                }
+            };
+            Self::rebuild_ann_index(&conn, &ann_index);
+            let mut last_run = Instant::now();
+            loop {
+                let minutes = settings.read().unwrap().index_interval_minutes;
+                let woken = match u64::try_from(minutes).ok().filter(|&m| m > 0) {
+                    Some(minutes) => {
+                        let interval = Duration::from_secs(minutes * 60);
+                        changed.recv_timeout(interval.saturating_sub(last_run.elapsed()))
+                    }
+                    None => changed.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match woken {
+                    Ok(()) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+
+                last_run = Instant::now();
+                let settings = settings.read().unwrap().clone();
+                let embedder = settings.embedding_config();
+                let mut no_progress = |_, _| ControlFlow::Continue(());
+                match indexer::index_paths(
+                    &conn,
+                    &settings.root_paths,
+                    &settings.file_filter(),
+                    settings.chunking(),
+                    &embedder,
+                    false,
+                    &mut no_progress,
+                ) {
+                    Ok(summary) => {
+                        if let Some(err) = summary.and_then(|s| s.embedding_error) {
+                            let _ = error_log::write(&conn, "background indexer embeddings", &err);
+                        }
+                        if let Ok(at) = indexer::last_indexed_at(&conn) {
+                            *last_indexed_at.lock().unwrap() = at;
+                        }
+                        Self::rebuild_ann_index(&conn, &ann_index);
+                    }
+                    Err(e) => {
+                        let _ = error_log::write(&conn, "background indexer", &e.to_string());
+                    }
+                }
+            }
+        });
+    }
+
+    /// Start the thread that pings the backend in the saved `settings` every
+    /// `HEALTH_CHECK_INTERVAL`, and right away when the backend or its address changes,
+    /// repainting `ctx` with each result. It stops once the returned status is dropped.
+    fn spawn_health_checker(ctx: Context, settings: SharedSettings) -> SharedHealth {
+        let health = Arc::new(Mutex::new(llm::Health::Unknown));
+        let shared: Weak<Mutex<llm::Health>> = Arc::downgrade(&health);
+        thread::spawn(move || {
+            let mut last_check: Option<(Option<llm::HealthTarget>, Instant)> = None;
+            loop {
+                let (target, proxy) = {
+                    let settings = settings.read().unwrap();
+                    (settings.health_target(), settings.proxy())
+                };
+                let changed = last_check.as_ref().is_none_or(|(t, _)| *t != target);
+                let due = last_check
+                    .as_ref()
+                    .is_none_or(|(_, at)| at.elapsed() >= HEALTH_CHECK_INTERVAL);
+                if changed || due {
+                    let Some(health) = shared.upgrade() else {
+                        return;
+                    };
+                    if changed {
+                        *health.lock().unwrap() = llm::Health::Unknown;
+                        ctx.request_repaint();
+                    }
+                    let status = match &target {
+                        Some(target) => target.check(HEALTH_CHECK_TIMEOUT, &proxy),
+                        None => llm::Health::Reachable,
+                    };
+                    *health.lock().unwrap() = status;
+                    ctx.request_repaint();
+                    last_check = Some((target, Instant::now()));
+                } else if shared.strong_count() == 0 {
+                    return;
+                }
+                thread::sleep(HEALTH_CHECK_POLL);
+            }
+        });
+        health
+    }
+
+    /// Replace the shared nearest-neighbour graph with one built from the current
+    /// index, or drop it when the index is small enough to search exhaustively.
+    fn rebuild_ann_index(conn: &Connection, ann_index: &SharedAnnIndex) {
+        match ann::AnnIndex::build(conn) {
+            Ok(index) => *ann_index.lock().unwrap() = index.map(Arc::new),
+            Err(e) => {
+                let _ = error_log::write(conn, "nearest-neighbour index", &e.to_string());
+            }
+        }
+    }
+
+    /// Start the thread that owns `conn` and applies queued writes in order. Failures
+    /// come back on the returned receiver for the UI to show.
+    fn spawn_db_writer(conn: Connection) -> (mpsc::Sender<DbCommand>, Receiver<AppError>) {
+        let (tx, rx) = mpsc::channel::<DbCommand>();
+        let (error_tx, error_rx) = mpsc::channel();
+        thread::spawn(move || {
+            for command in rx {
+                if let Err(e) = Self::apply_db_command(&conn, command) {
+                    let _ = error_tx.send(e);
+                }
+            }
+        });
+        (tx, error_rx)
+    }
+
+    fn apply_db_command(conn: &Connection, command: DbCommand) -> Result<(), AppError> {
+        match command {
+            DbCommand::SaveConversation { id, messages } => {
+                Self::write_conversation(conn, id, &messages)
+            }
+            DbCommand::SaveDraft {
+                conversation_id,
+                draft,
+            } => Self::write_draft(conn, conversation_id, &draft),
+            DbCommand::SaveSettings(settings) => Self::write_settings(conn, &settings),
+            DbCommand::SaveLastOpenedConversation(id) => {
+                conn.execute("UPDATE settings SET last_opened_conversation_id = ?1", [id])?;
+                Ok(())
+            }
+            DbCommand::SaveUiScale(scale) => {
+                conn.execute("UPDATE settings SET ui_scale = ?1", [scale as f64])?;
+                Ok(())
+            }
+            DbCommand::LogError { context, message } => {
+                error_log::write(conn, &context, &message)?;
+                Ok(())
+            }
+            DbCommand::ClearErrorLog => {
+                error_log::clear(conn)?;
+                Ok(())
+            }
+            DbCommand::Flush(done) => {
+                let _ = done.send(());
+                Ok(())
+            }
+        }
+    }
+
+    /// Queue `command` for the database worker. Should the worker be gone, the write
+    /// is applied right here instead of being lost.
+    fn queue_db_write(&self, command: DbCommand) -> Result<(), AppError> {
+        match self.db_tx.send(command) {
+            Ok(()) => Ok(()),
+            Err(mpsc::SendError(command)) => Self::apply_db_command(&self.conn, command),
+        }
+    }
+
+    /// Block until every queued write has reached the database.
+    fn flush_db_writes(&self) {
+        let _ = self.db_writes_landed().recv();
+    }
+
+    /// A receiver that gets a message once every write queued so far has reached the
+    /// database, for reading them back without blocking the UI thread meanwhile.
+    fn db_writes_landed(&self) -> Receiver<()> {
+        let (done_tx, done_rx) = mpsc::channel();
+        if let Err(mpsc::SendError(DbCommand::Flush(done_tx))) =
+            self.db_tx.send(DbCommand::Flush(done_tx))
+        {
+            // Without a worker, writes are applied as they are queued.
+            let _ = done_tx.send(());
         }
+        done_rx
     }
 
-    /// Return a platform-appropriate path to the database file:
+    /// Show failures reported by the database worker. They are not logged, as the
+    /// log itself is written by that worker.
+    fn drain_db_errors(&mut self) {
+        while let Ok(e) = self.db_errors.try_recv() {
+            self.error_message = Some(e.to_string());
+        }
+    }
+
+    /// Record a failure in the error log; `context` says where it happened, such as
+    /// the backend or the file involved.
+    fn log_error(&self, context: &str, message: &str) {
+        let command = DbCommand::LogError {
+            context: context.to_string(),
+            message: message.to_string(),
+        };
+        if let Err(e) = self.queue_db_write(command) {
+            eprintln!("Could not log error ({}: {}): {}", context, message, e);
+        }
+    }
+
+    /// Return the path to the database file: `cli_path` if given, else the path in
+    /// `INDEXEDRAG_DB_PATH`, else a platform-appropriate default:
     ///  - Linux:   ~/.config/indexedrag/indexedrag.db
     ///  - Windows: %APPDATA%\indexedrag\indexedrag.db
     ///  - macOS:   ~/Library/Application Support/indexedrag/indexedrag.db
-    fn get_db_path() -> PathBuf {
-        if let Some(proj_dirs) = ProjectDirs::from("pl", "aaugustyniak", "indexedRAG") {
+    ///
+    /// An existing directory given as the path gets the database inside it.
+    fn get_db_path(cli_path: Option<PathBuf>) -> PathBuf {
+        let configured = cli_path.or_else(|| {
+            std::env::var_os(DB_PATH_ENV)
+                .filter(|p| !p.is_empty())
+                .map(PathBuf::from)
+        });
+        if let Some(path) = configured {
+            if path.is_dir() {
+                path.join("indexedRAG.db")
+            } else {
+                path
+            }
+        } else if let Some(proj_dirs) = ProjectDirs::from("pl", "aaugustyniak", "indexedRAG") {
             let config_dir = proj_dirs.config_dir();
             config_dir.join("indexedRAG.db")
         } else {
@@ -75,232 +1118,4289 @@ impl IndexedragApp {
         }
     }
 
-    fn initialize_db(conn: &Connection) {
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS settings (
-                id INTEGER PRIMARY KEY,
-                root_paths TEXT NOT NULL,
-                index_interval_minutes INTEGER NOT NULL
-            )",
-            [],
-        )
-        .expect("Failed to create settings table");
+    /// (id, title, last activity, tags) of every conversation, most recently active
+    /// first.
+    #[allow(clippy::type_complexity)]
+    fn list_conversations(
+        conn: &Connection,
+    ) -> Result<Vec<(i64, String, Option<i64>, Vec<String>)>, AppError> {
+        let mut stmt = conn.prepare(
+            "SELECT id, title, last_activity, tags FROM conversation
+             ORDER BY COALESCE(last_activity, 0) DESC, id DESC",
+        )?;
+        let conversations = stmt
+            .query_map([], |row| {
+                // Damaged tags are quarantined when the conversation is opened.
+                let tags: String = row.get(3)?;
+                let tags = serde_json::from_str(&tags).unwrap_or_default();
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, tags))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(conversations)
+    }
+
+    /// Parse the JSON stored as `what`. If it is malformed, copy it into the
+    /// quarantine directory next to the database, so the next save can't destroy it,
+    /// record that in `damaged` and return an empty value instead.
+    fn parse_or_quarantine<T: DeserializeOwned + Default>(
+        conn: &Connection,
+        what: String,
+        file_stem: &str,
+        raw: &str,
+        damaged: &mut Vec<AppError>,
+    ) -> Result<T, AppError> {
+        let source = match serde_json::from_str(raw) {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let dir = conn
+            .path()
+            .and_then(|db| Path::new(db).parent())
+            .unwrap_or(Path::new("."))
+            .join("quarantine");
+        std::fs::create_dir_all(&dir)?;
+        // Named after the content, so loading the same damage again adds no copies.
+        let hash = blake3::hash(raw.as_bytes()).to_hex();
+        let path = dir.join(format!("{}-{}.json", file_stem, &hash[..16]));
+        if !path.exists() {
+            std::fs::write(&path, raw)?;
+        }
+        damaged.push(AppError::Quarantined { what, path, source });
+        Ok(T::default())
+    }
+
+    /// Load conversation `id`. Messages that can't be parsed are quarantined and
+    /// reported in `damaged`; the conversation then opens empty.
+    fn load_conversation(
+        conn: &Connection,
+        id: i64,
+        damaged: &mut Vec<AppError>,
+    ) -> Result<Option<Conversation>, AppError> {
+        let mut stmt = conn.prepare(
+            "SELECT id, title, messages, model, parent_id, tags, summary, title_manual, scope
+             FROM conversation WHERE id = ?1",
+        )?;
+        let mut rows = stmt.query(params![id])?;
+
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let id: i64 = row.get(0)?;
+        let title: String = row.get(1)?;
+        let messages_str: String = row.get(2)?;
+        let messages: Vec<Message> = Self::parse_or_quarantine(
+            conn,
+            format!("the messages of \"{}\"", title),
+            &format!("conversation-{}", id),
+            &messages_str,
+            damaged,
+        )?;
+        let model: Option<String> = row.get(3)?;
+        let parent_id: Option<i64> = row.get(4)?;
+        let tags_str: String = row.get(5)?;
+        let tags: Vec<String> = Self::parse_or_quarantine(
+            conn,
+            format!("the tags of \"{}\"", title),
+            &format!("conversation-{}-tags", id),
+            &tags_str,
+            damaged,
+        )?;
+        let summary: Option<String> = row.get(6)?;
+        let title_manual: bool = row.get(7)?;
+        let scope_str: String = row.get(8)?;
+        let scope: Vec<String> = Self::parse_or_quarantine(
+            conn,
+            format!("the retrieval scope of \"{}\"", title),
+            &format!("conversation-{}-scope", id),
+            &scope_str,
+            damaged,
+        )?;
+
+        Ok(Some(Conversation {
+            id,
+            title,
+            title_manual,
+            messages,
+            model,
+            parent_id,
+            tags,
+            scope,
+            summary,
+        }))
+    }
+
+    /// Insert a fresh conversation row and return it. It opens with `system_prompt`,
+    /// or with a welcome note when no prompt is configured.
+    fn create_conversation(
+        conn: &Connection,
+        system_prompt: &str,
+    ) -> Result<Conversation, AppError> {
+        let opening = if system_prompt.trim().is_empty() {
+            "Welcome to Indexedrag!"
+        } else {
+            system_prompt
+        };
+        let messages = vec![Message::new("system", opening)];
+        let title = DEFAULT_CONVERSATION_TITLE.to_string();
+        let messages_str = serde_json::to_string(&messages)?;
+
+        conn.execute(
+            "INSERT INTO conversation (title, messages, last_activity) VALUES (?1, ?2, ?3)",
+            params![title, messages_str, last_activity(&messages)],
+        )?;
+
+        Ok(Conversation {
+            id: conn.last_insert_rowid(),
+            title,
+            title_manual: false,
+            messages,
+            model: None,
+            parent_id: None,
+            tags: Vec::new(),
+            scope: Vec::new(),
+            summary: None,
+        })
+    }
+
+    /// Make conversation `id` the current one.
+    fn switch_conversation(&mut self, id: i64) -> Result<(), AppError> {
+        self.save_draft()?;
+        let mut damaged = Vec::new();
+        let loaded = Self::load_conversation(&self.conn, id, &mut damaged)?;
+        for e in damaged {
+            self.report_error(e);
+        }
+        if let Some(conversation) = loaded {
+            self.current_input = Self::load_draft(&self.conn, conversation.id)?;
+            let previous = std::mem::replace(&mut self.conversation, conversation);
+            self.editing_message = None;
+            self.selecting_message = None;
+            self.outbox_retry = self.has_queued_message().then(OutboxRetry::immediate);
+            self.remember_open_conversation()?;
+            self.refresh_starred(&previous)?;
+        }
+        Ok(())
+    }
+
+    fn new_conversation(&mut self) -> Result<(), AppError> {
+        self.save_draft()?;
+        let conversation = Self::create_conversation(&self.conn, &self.settings.system_prompt)?;
+        let previous = std::mem::replace(&mut self.conversation, conversation);
+        self.conversations = Self::list_conversations(&self.conn)?;
+        self.current_input.clear();
+        self.editing_message = None;
+        self.selecting_message = None;
+        self.outbox_retry = None;
+        self.remember_open_conversation()?;
+        self.refresh_starred(&previous)
+    }
+
+    /// Starred messages of every conversation except `open_id`, whose messages are
+    /// read from memory instead, as (conversation id, message index, preview).
+    fn list_starred(
+        conn: &Connection,
+        open_id: i64,
+    ) -> Result<Vec<(i64, usize, String)>, AppError> {
+        let mut stmt = conn.prepare(
+            "SELECT id, messages FROM conversation
+             WHERE id != ?1 AND messages LIKE '%\"starred\":true%' ORDER BY id DESC",
+        )?;
+        let rows = stmt
+            .query_map([open_id], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut starred = Vec::new();
+        for (id, messages) in rows {
+            let Ok(messages) = serde_json::from_str::<Vec<Message>>(&messages) else {
+                continue;
+            };
+            starred.extend(
+                messages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, m)| m.starred)
+                    .map(|(i, m)| (id, i, message_preview(&m.content))),
+            );
+        }
+        Ok(starred)
+    }
+
+    /// Reload the starred messages of the conversations that aren't open after
+    /// `previous` was left. Its messages are taken as they are in memory, since saving
+    /// them may still be queued, unless it was deleted.
+    fn refresh_starred(&mut self, previous: &Conversation) -> Result<(), AppError> {
+        let mut starred = Self::list_starred(&self.conn, self.conversation.id)?;
+        let listed = self.conversations.iter().any(|(id, ..)| *id == previous.id);
+        if previous.id != self.conversation.id && listed {
+            starred.retain(|(id, _, _)| *id != previous.id);
+            starred.extend(
+                previous
+                    .messages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, m)| m.starred)
+                    .map(|(i, m)| (previous.id, i, message_preview(&m.content))),
+            );
+            // Newest conversation first, as listed; the sort keeps message order.
+            starred.sort_by_key(|(id, _, _)| std::cmp::Reverse(*id));
+        }
+        self.starred = starred;
+        Ok(())
+    }
+
+    /// Record the current conversation as the one to reopen on the next start.
+    fn remember_open_conversation(&mut self) -> Result<(), AppError> {
+        self.settings.last_opened_conversation_id = Some(self.conversation.id);
+        self.queue_db_write(DbCommand::SaveLastOpenedConversation(self.conversation.id))
+    }
+
+    fn load_draft(conn: &Connection, conversation_id: i64) -> Result<String, AppError> {
+        let draft = conn.query_row(
+            "SELECT draft FROM conversation WHERE id = ?1",
+            params![conversation_id],
+            |row| row.get(0),
+        )?;
+        Ok(draft)
+    }
+
+    /// Persist the unsent input of the current conversation.
+    fn save_draft(&mut self) -> Result<(), AppError> {
+        self.draft_changed_at = None;
+        self.queue_db_write(DbCommand::SaveDraft {
+            conversation_id: self.conversation.id,
+            draft: self.current_input.clone(),
+        })
+    }
+
+    fn write_draft(conn: &Connection, conversation_id: i64, draft: &str) -> Result<(), AppError> {
+        conn.execute(
+            "UPDATE conversation SET draft = ?1 WHERE id = ?2",
+            params![draft, conversation_id],
+        )?;
+        Ok(())
+    }
+
+    /// Write the draft once typing has paused for `DRAFT_SAVE_DELAY`.
+    fn autosave_draft(&mut self, ctx: &Context) {
+        let Some(changed_at) = self.draft_changed_at else {
+            return;
+        };
+        let elapsed = changed_at.elapsed();
+        if elapsed >= DRAFT_SAVE_DELAY {
+            if let Err(e) = self.save_draft() {
+                self.report_error(e);
+            }
+        } else {
+            ctx.request_repaint_after(DRAFT_SAVE_DELAY - elapsed);
+        }
+    }
+
+    /// Write out the open conversation and its draft.
+    fn save_all(&mut self) -> Result<(), AppError> {
+        self.last_autosave = Instant::now();
+        self.save_conversation()?;
+        self.save_draft()
+    }
+
+    /// Run `save_all` every `AUTOSAVE_INTERVAL`.
+    fn autosave(&mut self, ctx: &Context) {
+        let elapsed = self.last_autosave.elapsed();
+        if elapsed >= AUTOSAVE_INTERVAL {
+            if let Err(e) = self.save_all() {
+                self.report_error(e);
+            }
+            ctx.request_repaint_after(AUTOSAVE_INTERVAL);
+        } else {
+            ctx.request_repaint_after(AUTOSAVE_INTERVAL - elapsed);
+        }
+    }
+
+    /// Rename conversation `id`. A `manual` title, given by the user, is never
+    /// replaced by an automatic one.
+    fn save_conversation_title(
+        &mut self,
+        id: i64,
+        title: &str,
+        manual: bool,
+    ) -> Result<(), AppError> {
+        self.conn.execute(
+            "UPDATE conversation SET title = ?1, title_manual = title_manual OR ?2
+             WHERE id = ?3",
+            params![title, manual, id],
+        )?;
+        if self.conversation.id == id {
+            self.conversation.title = title.to_string();
+            self.conversation.title_manual |= manual;
+        }
+        self.conversations = Self::list_conversations(&self.conn)?;
+        Ok(())
+    }
+
+    /// Whether conversation `id` may still be titled automatically.
+    fn has_automatic_title(&self, id: i64) -> Result<bool, AppError> {
+        let manual: Option<bool> = self
+            .conn
+            .query_row(
+                "SELECT title_manual FROM conversation WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .map(Some)
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e),
+            })?;
+        Ok(manual == Some(false))
+    }
+
+    /// Persist the retrieval scope of the open conversation.
+    fn save_conversation_scope(&self) -> Result<(), AppError> {
+        self.conn.execute(
+            "UPDATE conversation SET scope = ?1 WHERE id = ?2",
+            params![
+                serde_json::to_string(&self.conversation.scope)?,
+                self.conversation.id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Persist the tags of the open conversation.
+    fn save_conversation_tags(&mut self) -> Result<(), AppError> {
+        self.conn.execute(
+            "UPDATE conversation SET tags = ?1 WHERE id = ?2",
+            params![
+                serde_json::to_string(&self.conversation.tags)?,
+                self.conversation.id
+            ],
+        )?;
+        self.conversations = Self::list_conversations(&self.conn)?;
+        Ok(())
+    }
+
+    fn save_conversation_summary(&mut self, id: i64, summary: &str) -> Result<(), AppError> {
+        self.conn.execute(
+            "UPDATE conversation SET summary = ?1 WHERE id = ?2",
+            params![summary, id],
+        )?;
+        if self.conversation.id == id {
+            self.conversation.summary = Some(summary.to_string());
+        }
+        Ok(())
+    }
+
+    /// Persist the model override of the open conversation.
+    fn save_conversation_model(&self) -> Result<(), AppError> {
+        self.conn.execute(
+            "UPDATE conversation SET model = ?1 WHERE id = ?2",
+            params![self.conversation.model, self.conversation.id],
+        )?;
+        Ok(())
+    }
+
+    /// Copy the open conversation up to and including message `index` into a new
+    /// conversation linked to it, and switch to the copy.
+    fn fork_conversation(&mut self, index: usize) -> Result<(), AppError> {
+        let mut messages = self.conversation.messages[..=index].to_vec();
+        for message in &mut messages {
+            message.queued = false;
+        }
+        let title = format!("{} (fork)", self.conversation.title);
+        let messages_str = serde_json::to_string(&messages)?;
+        self.conn.execute(
+            "INSERT INTO conversation (title, messages, model, parent_id, last_activity, tags,
+                                       title_manual, scope)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1, ?7)",
+            params![
+                title,
+                messages_str,
+                self.conversation.model,
+                self.conversation.id,
+                chrono::Utc::now().timestamp(),
+                serde_json::to_string(&self.conversation.tags)?,
+                serde_json::to_string(&self.conversation.scope)?
+            ],
+        )?;
+        let id = self.conn.last_insert_rowid();
+        self.save_conversation()?;
+        self.conversations = Self::list_conversations(&self.conn)?;
+        self.switch_conversation(id)
+    }
+
+    /// Link back to the conversation the open one was forked from.
+    fn draw_conversation_parent(&mut self, ui: &mut Ui) {
+        let Some(parent_id) = self.conversation.parent_id else {
+            return;
+        };
+        let Some((_, title, _, _)) = self
+            .conversations
+            .iter()
+            .find(|(id, _, _, _)| *id == parent_id)
+        else {
+            return;
+        };
+        let mut open_parent = false;
+        ui.horizontal(|ui| {
+            ui.weak("Forked from");
+            open_parent = ui
+                .add_enabled(self.stream_rx.is_none(), egui::Link::new(title))
+                .clicked();
+        });
+        if open_parent {
+            if let Err(e) = self.switch_conversation(parent_id) {
+                self.report_error(e);
+            }
+        }
+    }
+
+    /// The model to ask in the open conversation: its override if it has one,
+    /// otherwise `configured`.
+    fn conversation_model(&self, configured: &str) -> String {
+        self.conversation
+            .model
+            .as_deref()
+            .map(str::trim)
+            .filter(|m| !m.is_empty())
+            .unwrap_or(configured)
+            .to_string()
+    }
+
+    /// Delete conversation `id`. If it was the open one, switch to the newest remaining
+    /// conversation, creating a fresh one when none are left.
+    fn delete_conversation(&mut self, id: i64) -> Result<(), AppError> {
+        self.delete_conversations(&[id])
+    }
+
+    /// Delete the conversations in `ids` in one transaction, so either all of them go
+    /// or none. Another conversation is opened if the open one was among them.
+    fn delete_conversations(&mut self, ids: &[i64]) -> Result<(), AppError> {
+        let tx = self.conn.unchecked_transaction()?;
+        for id in ids {
+            tx.execute("DELETE FROM conversation WHERE id = ?1", params![id])?;
+            // Its indexed chunks would otherwise be retrieved until the next index run.
+            tx.execute(
+                "DELETE FROM documents WHERE path = ?1",
+                [indexer::conversation_path(*id)],
+            )?;
+            // Forks of it stay, but no longer point at a parent.
+            tx.execute(
+                "UPDATE conversation SET parent_id = NULL WHERE parent_id = ?1",
+                params![id],
+            )?;
+        }
+        tx.commit()?;
+        if let Some(chosen) = &mut self.selected_conversations {
+            chosen.retain(|id| !ids.contains(id));
+        }
+        if self
+            .conversation
+            .parent_id
+            .is_some_and(|parent| ids.contains(&parent))
+        {
+            self.conversation.parent_id = None;
+        }
+        self.conversations = Self::list_conversations(&self.conn)?;
+
+        if ids.contains(&self.conversation.id) {
+            match self.conversations.first() {
+                Some((next, _, _, _)) => {
+                    let next = *next;
+                    self.switch_conversation(next)?;
+                }
+                None => self.new_conversation()?,
+            }
+        } else {
+            self.starred
+                .retain(|(conversation_id, _, _)| !ids.contains(conversation_id));
+        }
+        Ok(())
+    }
+
+    fn load_window_geometry(conn: &Connection) -> Result<Option<WindowGeometry>, AppError> {
+        let mut stmt =
+            conn.prepare("SELECT x, y, width, height FROM window_geometry WHERE id = 1")?;
+        let mut rows = stmt.query([])?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let x: Option<f64> = row.get(0)?;
+        let y: Option<f64> = row.get(1)?;
+        let width: f64 = row.get(2)?;
+        let height: f64 = row.get(3)?;
+        Ok(Some(WindowGeometry {
+            position: x.zip(y).map(|(x, y)| egui::pos2(x as f32, y as f32)),
+            size: egui::vec2(width as f32, height as f32),
+        }))
+    }
+
+    fn save_window_geometry(&self) -> Result<(), AppError> {
+        let Some(geometry) = self.window_geometry else {
+            return Ok(());
+        };
+        self.conn.execute(
+            "INSERT OR REPLACE INTO window_geometry (id, x, y, width, height)
+             VALUES (1, ?1, ?2, ?3, ?4)",
+            params![
+                geometry.position.map(|p| p.x as f64),
+                geometry.position.map(|p| p.y as f64),
+                geometry.size.x as f64,
+                geometry.size.y as f64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Remember the current window geometry, unless the window is minimized,
+    /// maximized or fullscreen; those states shouldn't become the restored size.
+    fn track_window_geometry(&mut self, frame: &Frame) {
+        let info = &frame.info().window_info;
+        if info.minimized || info.maximized || info.fullscreen {
+            return;
+        }
+        self.window_geometry = Some(WindowGeometry {
+            position: info.position,
+            size: info.size,
+        });
+    }
+
+    /// Zoom the interface to `scale` (clamped to the supported range) and remember it.
+    fn set_ui_scale(&mut self, scale: f32) {
+        let scale = scale.clamp(UI_SCALE_MIN, UI_SCALE_MAX);
+        if scale == self.settings.ui_scale {
+            return;
+        }
+        self.settings.ui_scale = scale;
+        if let Err(e) = self.queue_db_write(DbCommand::SaveUiScale(scale)) {
+            self.report_error(e);
+        }
+    }
+
+    /// Apply the zoom: Ctrl+scroll, Ctrl+plus/minus and Ctrl+0 change it, and the
+    /// result scales everything drawn this frame.
+    fn apply_ui_scale(&mut self, ctx: &Context, frame: &Frame) {
+        let (zoom, zoom_in, zoom_out, reset) = ctx.input_mut(|i| {
+            (
+                i.zoom_delta(),
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::PlusEquals),
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::Minus),
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::Num0),
+            )
+        });
+        let mut scale = self.settings.ui_scale * zoom;
+        if zoom_in {
+            scale += UI_SCALE_STEP;
+        }
+        if zoom_out {
+            scale -= UI_SCALE_STEP;
+        }
+        if reset {
+            scale = 1.0;
+        }
+        self.set_ui_scale(scale);
+        let native = frame.info().native_pixels_per_point.unwrap_or(1.0);
+        let pixels_per_point = native * self.settings.ui_scale;
+        if ctx.pixels_per_point() != pixels_per_point {
+            ctx.set_pixels_per_point(pixels_per_point);
+        }
+    }
+
+    fn draw_ui_scale(&mut self, ui: &mut Ui) {
+        let mut scale = self.settings.ui_scale;
+        if ui
+            .small_button("-")
+            .on_hover_text("Zoom out (Ctrl+minus)")
+            .clicked()
+        {
+            scale -= UI_SCALE_STEP;
+        }
+        if ui
+            .small_button(format!("{:.0}%", scale * 100.0))
+            .on_hover_text("Reset zoom (Ctrl+0); Ctrl+scroll also zooms")
+            .clicked()
+        {
+            scale = 1.0;
+        }
+        if ui
+            .small_button("+")
+            .on_hover_text("Zoom in (Ctrl+plus)")
+            .clicked()
+        {
+            scale += UI_SCALE_STEP;
+        }
+        self.set_ui_scale(scale);
+    }
+
+    /// Load the settings row, creating it with defaults on first run. Lists that can't
+    /// be parsed are quarantined, reported in `damaged` and loaded empty.
+    fn load_or_create_default_settings(
+        conn: &Connection,
+        damaged: &mut Vec<AppError>,
+    ) -> Result<AppSettings, AppError> {
+        let mut stmt = conn.prepare(
+            "SELECT id, root_paths, index_interval_minutes, model, api_key,
+                        ollama_url, ollama_model, backend, embedding_model,
+                        retrieval_top_k, system_prompt, temperature, max_tokens,
+                        context_limit_tokens, max_retries, allowed_extensions,
+                        max_file_size_bytes, respect_gitignore,
+                        last_opened_conversation_id, chunk_size_chars,
+                        chunk_overlap_chars, llamacpp_url, anthropic_model,
+                        request_timeout_seconds, min_similarity,
+                        prompt_price_per_million, completion_price_per_million,
+                        max_prompt_tokens, openai_base_url, ui_scale, history_window,
+                        proxy_url, no_proxy, index_conversations, expand_queries,
+                        redact_secrets, embeddings_per_minute, embedding_price_per_million
+                 FROM settings LIMIT 1",
+        )?;
+        let mut rows = stmt.query([])?;
+
+        if let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let root_paths_str: String = row.get(1)?;
+            let root_paths: Vec<String> = Self::parse_or_quarantine(
+                conn,
+                "the indexed folders in the settings".to_string(),
+                "settings-root-paths",
+                &root_paths_str,
+                damaged,
+            )?;
+            let index_interval_minutes: i32 = row.get(2)?;
+            let model: String = row.get(3)?;
+            // Older versions stored the key in plaintext; use it until the next save
+            // moves it into the keyring and blanks the column.
+            let legacy_api_key: String = row.get(4)?;
+            let api_key = Self::load_api_key(KEYRING_API_KEY_USER).unwrap_or(legacy_api_key);
+            let ollama_url: String = row.get(5)?;
+            let ollama_model: String = row.get(6)?;
+            let backend_str: String = row.get(7)?;
+            let backend = LlmBackend::from_key(&backend_str).unwrap_or(LlmBackend::Ollama);
+            let embedding_model: String = row.get(8)?;
+            let retrieval_top_k: i32 = row.get(9)?;
+            let system_prompt: String = row.get(10)?;
+            let temperature: f64 = row.get(11)?;
+            let max_tokens: i32 = row.get(12)?;
+            let context_limit_tokens: i32 = row.get(13)?;
+            let max_retries: i32 = row.get(14)?;
+            let allowed_extensions_str: String = row.get(15)?;
+            let allowed_extensions: Vec<String> = Self::parse_or_quarantine(
+                conn,
+                "the allowed extensions in the settings".to_string(),
+                "settings-allowed-extensions",
+                &allowed_extensions_str,
+                damaged,
+            )?;
+            let max_file_size_bytes: i64 = row.get(16)?;
+            let respect_gitignore: bool = row.get(17)?;
+            let last_opened_conversation_id: Option<i64> = row.get(18)?;
+            let chunk_size_chars: i32 = row.get(19)?;
+            let chunk_overlap_chars: i32 = row.get(20)?;
+            let llamacpp_url: String = row.get(21)?;
+            let anthropic_model: String = row.get(22)?;
+            let request_timeout_seconds: i32 = row.get(23)?;
+            let min_similarity: f64 = row.get(24)?;
+            let prompt_price_per_million: f64 = row.get(25)?;
+            let completion_price_per_million: f64 = row.get(26)?;
+            let max_prompt_tokens: i32 = row.get(27)?;
+            let openai_base_url: String = row.get(28)?;
+            let ui_scale: f64 = row.get(29)?;
+            let history_window: usize = row.get(30)?;
+            let proxy_url: String = row.get(31)?;
+            let no_proxy: String = row.get(32)?;
+            let index_conversations: bool = row.get(33)?;
+            let expand_queries: bool = row.get(34)?;
+            let redact_secrets: bool = row.get(35)?;
+            let embeddings_per_minute: i32 = row.get(36)?;
+            let embedding_price_per_million: f64 = row.get(37)?;
+            let anthropic_api_key =
+                Self::load_api_key(KEYRING_ANTHROPIC_API_KEY_USER).unwrap_or_default();
+
+            Ok(AppSettings {
+                id,
+                root_paths,
+                index_interval_minutes,
+                backend,
+                model,
+                api_key,
+                openai_base_url,
+                ollama_url,
+                ollama_model,
+                llamacpp_url,
+                anthropic_model,
+                anthropic_api_key,
+                embedding_model,
+                embeddings_per_minute,
+                embedding_price_per_million,
+                retrieval_top_k,
+                min_similarity: min_similarity as f32,
+                expand_queries,
+                system_prompt,
+                temperature: temperature as f32,
+                max_tokens,
+                context_limit_tokens,
+                max_retries,
+                request_timeout_seconds,
+                prompt_price_per_million,
+                completion_price_per_million,
+                max_prompt_tokens,
+                ui_scale: ui_scale as f32,
+                history_window,
+                proxy_url,
+                no_proxy,
+                redact_secrets,
+                allowed_extensions,
+                max_file_size_bytes,
+                respect_gitignore,
+                index_conversations,
+                last_opened_conversation_id,
+                chunk_size_chars,
+                chunk_overlap_chars,
+            })
+        } else {
+            let default = AppSettings {
+                id: 1,
+                root_paths: vec!["/path/to/somewhere".to_string()],
+                index_interval_minutes: 60,
+                backend: LlmBackend::Ollama,
+                model: "gpt-4o-mini".to_string(),
+                api_key: String::new(),
+                openai_base_url: llm::OPENAI_DEFAULT_BASE_URL.to_string(),
+                ollama_url: llm::OLLAMA_DEFAULT_URL.to_string(),
+                ollama_model: "llama3".to_string(),
+                llamacpp_url: llm::LLAMACPP_DEFAULT_URL.to_string(),
+                anthropic_model: "claude-3-5-sonnet-latest".to_string(),
+                anthropic_api_key: String::new(),
+                embedding_model: "nomic-embed-text".to_string(),
+                embeddings_per_minute: 0,
+                embedding_price_per_million: 0.02,
+                retrieval_top_k: 4,
+                min_similarity: 0.0,
+                expand_queries: false,
+                system_prompt: String::new(),
+                temperature: 0.7,
+                max_tokens: 1024,
+                context_limit_tokens: 8192,
+                max_retries: 3,
+                request_timeout_seconds: 120,
+                prompt_price_per_million: 0.15,
+                completion_price_per_million: 0.6,
+                max_prompt_tokens: 16_000,
+                ui_scale: 1.0,
+                history_window: 0,
+                proxy_url: String::new(),
+                no_proxy: DEFAULT_NO_PROXY.to_string(),
+                redact_secrets: false,
+                allowed_extensions: indexer::DEFAULT_EXTENSIONS
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect(),
+                max_file_size_bytes: indexer::DEFAULT_MAX_FILE_SIZE_BYTES as i64,
+                respect_gitignore: true,
+                index_conversations: false,
+                last_opened_conversation_id: None,
+                chunk_size_chars: indexer::DEFAULT_CHUNK_SIZE_CHARS as i32,
+                chunk_overlap_chars: indexer::DEFAULT_CHUNK_OVERLAP_CHARS as i32,
+            };
+
+            let root_paths_str = serde_json::to_string(&default.root_paths)?;
+            let allowed_extensions_str = serde_json::to_string(&default.allowed_extensions)?;
+            conn.execute(
+                "INSERT INTO settings (id, root_paths, index_interval_minutes, model,
+                                       ollama_url, ollama_model, backend, embedding_model,
+                                       retrieval_top_k, system_prompt, temperature,
+                                       max_tokens, context_limit_tokens, max_retries,
+                                       allowed_extensions, max_file_size_bytes,
+                                       respect_gitignore, chunk_size_chars,
+                                       chunk_overlap_chars, llamacpp_url, anthropic_model,
+                                       request_timeout_seconds, min_similarity,
+                                       prompt_price_per_million,
+                                       completion_price_per_million, max_prompt_tokens,
+                                       openai_base_url, ui_scale, history_window,
+                                       proxy_url, no_proxy, index_conversations,
+                                       expand_queries, redact_secrets, embeddings_per_minute,
+                                       embedding_price_per_million)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15,
+                         ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28,
+                         ?29, ?30, ?31, ?32, ?33, ?34, ?35, ?36)",
+                params![
+                    default.id,
+                    root_paths_str,
+                    default.index_interval_minutes,
+                    default.model,
+                    default.ollama_url,
+                    default.ollama_model,
+                    default.backend.key(),
+                    default.embedding_model,
+                    default.retrieval_top_k,
+                    default.system_prompt,
+                    default.temperature as f64,
+                    default.max_tokens,
+                    default.context_limit_tokens,
+                    default.max_retries,
+                    allowed_extensions_str,
+                    default.max_file_size_bytes,
+                    default.respect_gitignore,
+                    default.chunk_size_chars,
+                    default.chunk_overlap_chars,
+                    default.llamacpp_url,
+                    default.anthropic_model,
+                    default.request_timeout_seconds,
+                    default.min_similarity as f64,
+                    default.prompt_price_per_million,
+                    default.completion_price_per_million,
+                    default.max_prompt_tokens,
+                    default.openai_base_url,
+                    default.ui_scale as f64,
+                    default.history_window,
+                    default.proxy_url,
+                    default.no_proxy,
+                    default.index_conversations,
+                    default.expand_queries,
+                    default.redact_secrets,
+                    default.embeddings_per_minute,
+                    default.embedding_price_per_million
+                ],
+            )?;
+
+            Ok(default)
+        }
+    }
+
+    fn save_conversation(&self) -> Result<(), AppError> {
+        self.queue_db_write(DbCommand::SaveConversation {
+            id: self.conversation.id,
+            messages: self.conversation.messages.clone(),
+        })
+    }
+
+    fn write_conversation(
+        conn: &Connection,
+        id: i64,
+        messages: &[Message],
+    ) -> Result<(), AppError> {
+        let messages_str = serde_json::to_string(messages)?;
+        conn.execute(
+            "UPDATE conversation SET messages = ?1, last_activity = COALESCE(?2, last_activity)
+             WHERE id = ?3",
+            params![messages_str, last_activity(messages), id],
+        )?;
+        Ok(())
+    }
+
+    /// Persist the settings, switch to the message hooks they ask for, and hand them to
+    /// the background indexer, which picks them up right away.
+    fn save_settings(&mut self) -> Result<(), AppError> {
+        self.message_processor = self.settings.message_processor();
+        self.publish_settings();
+        self.queue_db_write(DbCommand::SaveSettings(Box::new(self.settings.clone())))
+    }
+
+    /// Make `self.settings` the ones background threads work with.
+    fn publish_settings(&self) {
+        *self.saved_settings.write().unwrap() = self.settings.clone();
+        // Only fails once the indexer thread is gone.
+        let _ = self.settings_changed.send(());
+    }
+
+    fn write_settings(conn: &Connection, settings: &AppSettings) -> Result<(), AppError> {
+        let root_paths_str = serde_json::to_string(&settings.root_paths)?;
+        let allowed_extensions_str = serde_json::to_string(&settings.allowed_extensions)?;
+        conn.execute(
+            "UPDATE settings
+                 SET root_paths = ?1,
+                     index_interval_minutes = ?2,
+                     model = ?3,
+                     ollama_url = ?4,
+                     ollama_model = ?5,
+                     backend = ?6,
+                     embedding_model = ?7,
+                     retrieval_top_k = ?8,
+                     system_prompt = ?9,
+                     temperature = ?10,
+                     max_tokens = ?11,
+                     context_limit_tokens = ?12,
+                     max_retries = ?13,
+                     allowed_extensions = ?14,
+                     max_file_size_bytes = ?15,
+                     respect_gitignore = ?16,
+                     chunk_size_chars = ?17,
+                     chunk_overlap_chars = ?18,
+                     llamacpp_url = ?19,
+                     anthropic_model = ?20,
+                     request_timeout_seconds = ?21,
+                     min_similarity = ?22,
+                     prompt_price_per_million = ?23,
+                     completion_price_per_million = ?24,
+                     max_prompt_tokens = ?25,
+                     openai_base_url = ?26,
+                     ui_scale = ?27,
+                     history_window = ?28,
+                     proxy_url = ?29,
+                     no_proxy = ?30,
+                     index_conversations = ?31,
+                     expand_queries = ?32,
+                     redact_secrets = ?33,
+                     embeddings_per_minute = ?34,
+                     embedding_price_per_million = ?35
+                 WHERE id = ?36",
+            params![
+                root_paths_str,
+                settings.index_interval_minutes,
+                settings.model,
+                settings.ollama_url,
+                settings.ollama_model,
+                settings.backend.key(),
+                settings.embedding_model,
+                settings.retrieval_top_k,
+                settings.system_prompt,
+                settings.sampling().temperature as f64,
+                settings.sampling().max_tokens,
+                settings.context_limit_tokens.max(1),
+                settings.max_retries.max(0),
+                allowed_extensions_str,
+                settings.max_file_size_bytes.max(0),
+                settings.respect_gitignore,
+                settings.chunking().size_chars as i64,
+                settings.chunking().overlap_chars as i64,
+                settings.llamacpp_url,
+                settings.anthropic_model,
+                settings.request_timeout_seconds.max(1),
+                settings.min_similarity.clamp(-1.0, 1.0) as f64,
+                settings.prompt_price_per_million.max(0.0),
+                settings.completion_price_per_million.max(0.0),
+                settings.max_prompt_tokens.max(0),
+                settings.openai_base_url.trim(),
+                settings.ui_scale.clamp(UI_SCALE_MIN, UI_SCALE_MAX) as f64,
+                settings.history_window,
+                settings.proxy_url.trim(),
+                settings.no_proxy.trim(),
+                settings.index_conversations,
+                settings.expand_queries,
+                settings.redact_secrets,
+                settings.embeddings_per_minute.max(0),
+                settings.embedding_price_per_million.max(0.0),
+                settings.id
+            ],
+        )?;
+
+        Self::store_api_key(KEYRING_API_KEY_USER, &settings.api_key)?;
+        Self::store_api_key(KEYRING_ANTHROPIC_API_KEY_USER, &settings.anthropic_api_key)?;
+        // Only now that the keychain holds the key is the legacy plaintext copy dropped.
+        conn.execute(
+            "UPDATE settings SET api_key = '' WHERE id = ?1",
+            [settings.id],
+        )?;
+        Ok(())
+    }
+
+    /// Write the current conversation to `path` as Markdown. Message content is
+    /// already Markdown, so it passes through verbatim.
+    fn export_conversation_markdown(&self, path: &Path) -> Result<(), AppError> {
+        let mut out = format!("# {}\n\n", self.conversation.title);
+        for msg in &self.conversation.messages {
+            out.push_str(&format!("**{}:** {}\n\n", msg.role, msg.content.trim_end()));
+        }
+        std::fs::write(path, out)?;
+        Ok(())
+    }
+
+    /// Write the current conversation to `path` as JSON, in the same shape that
+    /// `import_conversation_json` reads back.
+    fn export_conversation_json(&self, path: &Path) -> Result<(), AppError> {
+        let json = serde_json::to_string_pretty(&self.conversation)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Read a conversation exported by `export_conversation_json`, store it as a new
+    /// conversation and switch to it. Malformed files are rejected as a whole.
+    fn import_conversation_json(&mut self, path: &Path) -> Result<(), AppError> {
+        let json = std::fs::read_to_string(path)?;
+        let imported: Conversation =
+            serde_json::from_str(&json).map_err(|e| AppError::InvalidImport(e.to_string()))?;
+        if let Some(msg) = imported
+            .messages
+            .iter()
+            .find(|msg| !matches!(msg.role.as_str(), "system" | "user" | "assistant"))
+        {
+            return Err(AppError::InvalidImport(format!(
+                "unknown message role \"{}\"",
+                msg.role
+            )));
+        }
+
+        let title = if imported.title.trim().is_empty() {
+            "Imported conversation".to_string()
+        } else {
+            imported.title
+        };
+        let messages_str = serde_json::to_string(&imported.messages)?;
+        self.conn.execute(
+            "INSERT INTO conversation (title, messages, model, last_activity, tags, summary,
+                                       title_manual)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1)",
+            params![
+                title,
+                messages_str,
+                imported.model,
+                last_activity(&imported.messages),
+                serde_json::to_string(&imported.tags)?,
+                imported.summary
+            ],
+        )?;
+        let id = self.conn.last_insert_rowid();
+        self.conversations = Self::list_conversations(&self.conn)?;
+        self.switch_conversation(id)
+    }
+
+    /// Store every conversation of a ChatGPT export (`conversations.json`) as a new
+    /// conversation, in one transaction, and open the first.
+    fn import_chatgpt_export(&mut self, path: &Path) -> Result<(), AppError> {
+        let json = std::fs::read_to_string(path)?;
+        let imported = chatgpt::parse(&json).map_err(AppError::InvalidImport)?;
+        if imported.is_empty() {
+            return Err(AppError::InvalidImport(
+                "it holds no conversations with text messages".to_string(),
+            ));
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        let mut first = None;
+        for conversation in &imported {
+            tx.execute(
+                "INSERT INTO conversation (title, messages, last_activity, title_manual)
+                 VALUES (?1, ?2, ?3, 1)",
+                params![
+                    conversation.title,
+                    serde_json::to_string(&conversation.messages)?,
+                    last_activity(&conversation.messages)
+                ],
+            )?;
+            first.get_or_insert(tx.last_insert_rowid());
+        }
+        tx.commit()?;
+        self.conversations = Self::list_conversations(&self.conn)?;
+        if let Some(id) = first {
+            self.switch_conversation(id)?;
+        }
+        Ok(())
+    }
+
+    /// Surface a runtime failure to the user instead of crashing.
+    fn report_error(&mut self, error: AppError) {
+        let message = error.to_string();
+        self.log_error("app", &message);
+        self.error_message = Some(message);
+    }
+
+    /// Ask before an index run whose embeddings are expected to cost a lot.
+    fn draw_large_index_run_confirmation(&mut self, ctx: &Context) {
+        let Some(run) = &self.large_index_run else {
+            return;
+        };
+        let mut start = false;
+        let mut cancel = false;
+        egui::Window::new("Start a large index run?")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let cost =
+                    run.tokens as f64 * self.settings.embedding_price_per_million / 1_000_000.0;
+                ui.label(format!(
+                    "This run would embed about {} tokens with {}, costing about {}.",
+                    run.tokens,
+                    self.settings.backend.label(),
+                    format_cost(cost)
+                ));
+                ui.weak(
+                    "The estimate counts every new or modified file by size, so the actual \
+                     cost is usually lower. Narrow the indexed folders or extensions to \
+                     embed less.",
+                );
+                ui.horizontal(|ui| {
+                    start = ui.button("Index anyway").clicked();
+                    cancel = ui.button("Cancel").clicked();
+                });
+            });
+        if start {
+            if let Some(run) = self.large_index_run.take() {
+                self.index_files(run.full, false);
+            }
+        } else if cancel {
+            self.large_index_run = None;
+        }
+    }
+
+    /// Walk every configured root path and update the document chunks from the text
+    /// files found there. Unless `full` is set, only new and modified files are read.
+    ///
+    /// The run happens on its own thread and connection so chatting can continue;
+    /// progress arrives over `self.index_rx` and is applied by `drain_index_events`.
+    ///
+    /// With `confirm` set, a run expected to send more than `LARGE_INDEX_RUN_TOKENS`
+    /// to a paid embedding backend is not started; it is held in `large_index_run`
+    /// until the user accepts its estimated cost.
+    fn index_files(&mut self, full: bool, confirm: bool) {
+        let (tx, rx) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.index_rx = Some(rx);
+        self.index_cancel = Some(Arc::clone(&cancel));
+        self.index_progress = (0, 0);
+        self.index_full = full;
+        self.index_status = None;
+
+        let db_path = self.db_path.clone();
+        let root_paths = self.settings.root_paths.clone();
+        let filter = self.settings.file_filter();
+        let chunking = self.settings.chunking();
+        let embedder = self.settings.embedding_config();
+        let last_indexed_at = Arc::clone(&self.last_indexed_at);
+        let ann_index = Arc::clone(&self.ann_index);
+        thread::spawn(move || {
+            let mut on_progress = |files_done, files_total| {
+                if cancel.load(Ordering::Relaxed) {
+                    return ControlFlow::Break(());
+                }
+                let _ = tx.send(IndexEvent::Progress {
+                    files_done,
+                    files_total,
+                });
+                ControlFlow::Continue(())
+            };
+            let conn = match Self::open_connection(&db_path) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    let _ = tx.send(IndexEvent::Finished(Err(e.to_string())));
+                    return;
+                }
+            };
+            if confirm && embedder.is_paid() {
+                let tokens =
+                    indexer::estimate_embedding_tokens(&conn, &root_paths, &filter, chunking, full);
+                match tokens {
+                    Ok(_) if cancel.load(Ordering::Relaxed) => {
+                        let _ = tx.send(IndexEvent::Finished(Ok(None)));
+                        return;
+                    }
+                    Ok(tokens) if tokens > LARGE_INDEX_RUN_TOKENS => {
+                        let _ = tx.send(IndexEvent::NeedsConfirmation { tokens });
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        let _ = error_log::write(&conn, "index cost estimate", &e.to_string());
+                    }
+                }
+            }
+            let result = indexer::index_paths(
+                &conn,
+                &root_paths,
+                &filter,
+                chunking,
+                &embedder,
+                full,
+                &mut on_progress,
+            );
+            if let Ok(summary) = &result {
+                if let Ok(at) = indexer::last_indexed_at(&conn) {
+                    *last_indexed_at.lock().unwrap() = at;
+                }
+                if summary.is_some() {
+                    Self::rebuild_ann_index(&conn, &ann_index);
+                }
+            }
+            let _ = tx.send(IndexEvent::Finished(result.map_err(|e| e.to_string())));
+        });
+    }
+
+    /// Apply progress reported by the manual reindex thread.
+    fn drain_index_events(&mut self, ctx: &Context) {
+        let Some(rx) = &self.index_rx else {
+            return;
+        };
+        let mut finished = None;
+        loop {
+            match rx.try_recv() {
+                Ok(IndexEvent::Progress {
+                    files_done,
+                    files_total,
+                }) => self.index_progress = (files_done, files_total),
+                Ok(IndexEvent::Finished(result)) => finished = Some(result),
+                Ok(IndexEvent::NeedsConfirmation { tokens }) => {
+                    self.index_rx = None;
+                    self.index_cancel = None;
+                    self.large_index_run = Some(LargeIndexRun {
+                        full: self.index_full,
+                        tokens,
+                    });
+                    return;
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    finished = Some(Err("The indexer stopped unexpectedly".to_string()));
+                    break;
+                }
+            }
+            if finished.is_some() {
+                break;
+            }
+        }
+        let Some(result) = finished else {
+            ctx.request_repaint();
+            return;
+        };
+        self.index_rx = None;
+        self.index_cancel = None;
+        self.index_status = Some(match result {
+            Ok(Some(summary)) => {
+                let mut status = format!(
+                    "Indexed {} files into {} chunks ({} unchanged, {} skipped)",
+                    summary.files_indexed,
+                    summary.chunks,
+                    summary.files_unchanged,
+                    summary.files_skipped
+                );
+                if summary.embeddings_reused > 0 || summary.duplicate_chunks > 0 {
+                    status.push_str(&format!(
+                        "\n{} embeddings reused, {} duplicate chunks dropped",
+                        summary.embeddings_reused, summary.duplicate_chunks
+                    ));
+                }
+                if summary.files_guessed_encoding > 0 {
+                    status.push_str(&format!(
+                        "\n{} files weren't UTF-8 and were decoded with a guessed encoding",
+                        summary.files_guessed_encoding
+                    ));
+                }
+                if !summary.errors.is_empty() {
+                    status.push_str(&format!(
+                        "\n{} files failed to index; see Index Statistics",
+                        summary.errors.len()
+                    ));
+                }
+                if let Some(err) = summary.embedding_error {
+                    self.log_error("indexer embeddings", &err);
+                    status.push_str(&format!("\nEmbeddings unavailable: {}", err));
+                }
+                status
+            }
+            Ok(None) => "Indexing cancelled".to_string(),
+            Err(e) => {
+                self.log_error("indexer", &e.to_string());
+                format!("Indexing failed: {}", e)
+            }
+        });
+        if self.index_stats.is_some() {
+            self.index_stats = self.index_stats().ok();
+        }
+    }
+
+    fn cancel_indexing(&self) {
+        if let Some(cancel) = &self.index_cancel {
+            cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Write the whole database, and the stored API keys if `include_keys` is set, to
+    /// an encrypted backup at `path`. The export runs on its own thread and connection
+    /// once the queued writes have landed; its outcome arrives over
+    /// `self.backup_export_rx`.
+    fn export_backup(&mut self, path: PathBuf, passphrase: String, include_keys: bool) {
+        let (tx, rx) = mpsc::channel();
+        self.backup_export_rx = Some(rx);
+        let landed = self.db_writes_landed();
+        let db_path = self.db_path.clone();
+        thread::spawn(move || {
+            let mut keys = BTreeMap::new();
+            if include_keys {
+                for user in [KEYRING_API_KEY_USER, KEYRING_ANTHROPIC_API_KEY_USER] {
+                    if let Some(key) = Self::load_api_key(user) {
+                        keys.insert(user.to_string(), key);
+                    }
+                }
+            }
+            let _ = landed.recv();
+            let result = Self::open_connection(&db_path)
+                .and_then(|conn| backup::export(&conn, &path, &passphrase, keys));
+            let _ = tx.send(result);
+        });
+    }
+
+    /// Replace the database with the backup at `path`, store the API keys it carries
+    /// in the keyring, and reopen everything from the restored data.
+    fn restore_backup(&mut self, path: &Path, passphrase: &str) -> Result<(), AppError> {
+        self.flush_db_writes();
+        let keys = backup::restore(&mut self.conn, path, passphrase)?;
+        migrations::migrate_with_backup(&mut self.conn, &self.db_path)?;
+        for user in [KEYRING_API_KEY_USER, KEYRING_ANTHROPIC_API_KEY_USER] {
+            if let Some(key) = keys.get(user) {
+                Self::store_api_key(user, key).map_err(|e| {
+                    AppError::Backup(format!("could not store the restored API key: {}", e))
+                })?;
+            }
+        }
+        self.reload_from_database()
+    }
+
+    /// Drop everything loaded from the database and load it afresh, as after a restore.
+    /// Nothing is saved first: the open conversation may not exist any more.
+    fn reload_from_database(&mut self) -> Result<(), AppError> {
+        let mut damaged = Vec::new();
+        self.settings = Self::load_or_create_default_settings(&self.conn, &mut damaged)?;
+        self.message_processor = self.settings.message_processor();
+        self.publish_settings();
+        self.conversations = Self::list_conversations(&self.conn)?;
+        let open = self
+            .settings
+            .last_opened_conversation_id
+            .filter(|id| self.conversations.iter().any(|(c, _, _, _)| c == id))
+            .or_else(|| self.conversations.first().map(|(id, _, _, _)| *id));
+        let conversation = match open {
+            Some(id) => Self::load_conversation(&self.conn, id, &mut damaged)?,
+            None => None,
+        };
+        self.conversation = match conversation {
+            Some(conversation) => conversation,
+            None => {
+                let conversation =
+                    Self::create_conversation(&self.conn, &self.settings.system_prompt)?;
+                self.conversations = Self::list_conversations(&self.conn)?;
+                conversation
+            }
+        };
+        self.current_input = Self::load_draft(&self.conn, self.conversation.id)?;
+        self.draft_changed_at = None;
+        self.attachments.clear();
+        self.pending_paste = None;
+        self.renaming = None;
+        self.editing_message = None;
+        self.selecting_message = None;
+        self.tag_filter = None;
+        self.selected_conversations = None;
+        self.last_retrieval = None;
+        self.outbox_retry = self.has_queued_message().then(OutboxRetry::immediate);
+        self.document_results.clear();
+        self.conversation_results.clear();
+        self.index_stats = None;
+        self.index_status = None;
+        self.starred = Self::list_starred(&self.conn, self.conversation.id)?;
+        *self.last_indexed_at.lock().unwrap() = indexer::last_indexed_at(&self.conn)?;
+        *self.ann_index.lock().unwrap() = None;
+        let (db_path, ann_index) = (self.db_path.clone(), Arc::clone(&self.ann_index));
+        thread::spawn(move || {
+            if let Ok(conn) = Self::open_connection(&db_path) {
+                Self::rebuild_ann_index(&conn, &ann_index);
+            }
+        });
+        for e in damaged {
+            self.report_error(e);
+        }
+        Ok(())
+    }
+
+    /// Ask for the passphrase of the backup being exported or restored.
+    fn draw_backup_dialog(&mut self, ctx: &Context) {
+        if let Some(rx) = &self.backup_export_rx {
+            match rx.try_recv() {
+                Ok(result) => {
+                    self.backup_export_rx = None;
+                    if let Err(e) = result {
+                        self.report_error(e);
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => ctx.request_repaint(),
+                Err(mpsc::TryRecvError::Disconnected) => self.backup_export_rx = None,
+            }
+        }
+        let Some(dialog) = &mut self.backup_dialog else {
+            return;
+        };
+        let mut run = false;
+        let mut cancelled = false;
+        let title = if dialog.restore {
+            "Restore backup"
+        } else {
+            "Export backup"
+        };
+        egui::Window::new(title)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(dialog.path.display().to_string());
+                if dialog.restore {
+                    ui.label(
+                        "Restoring replaces all conversations, settings and the index with \
+                         the ones in the backup.",
+                    );
+                }
+                egui::Grid::new("backup_passphrase").show(ui, |ui| {
+                    ui.label("Passphrase:");
+                    ui.add(egui::TextEdit::singleline(&mut dialog.passphrase).password(true));
+                    ui.end_row();
+                    if !dialog.restore {
+                        ui.label("Repeat:");
+                        ui.add(egui::TextEdit::singleline(&mut dialog.confirmation).password(true));
+                        ui.end_row();
+                    }
+                });
+                let mut ready = !dialog.passphrase.is_empty();
+                if !dialog.restore {
+                    ui.checkbox(&mut dialog.include_keys, "Include API keys")
+                        .on_hover_text("Store the keys from the keyring in the backup as well");
+                    let matching = dialog.passphrase == dialog.confirmation;
+                    if !matching && !dialog.confirmation.is_empty() {
+                        ui.colored_label(egui::Color32::RED, "The passphrases differ");
+                    }
+                    ready &= matching;
+                }
+                ui.horizontal(|ui| {
+                    run = ui.add_enabled(ready, egui::Button::new(title)).clicked();
+                    cancelled = ui.button("Cancel").clicked();
+                });
+            });
+        if cancelled {
+            self.backup_dialog = None;
+        }
+        if !run {
+            return;
+        }
+        let Some(dialog) = self.backup_dialog.take() else {
+            return;
+        };
+        if !dialog.restore {
+            self.export_backup(dialog.path, dialog.passphrase, dialog.include_keys);
+        } else if let Err(e) = self.restore_backup(&dialog.path, &dialog.passphrase) {
+            self.report_error(e);
+        }
+    }
+
+    /// Empty the document index. Conversations and settings are left alone.
+    fn clear_index(&self) -> rusqlite::Result<()> {
+        indexer::clear_index(&self.conn)?;
+        *self.last_indexed_at.lock().unwrap() = None;
+        *self.ann_index.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn draw_bulk_delete_confirmation(&mut self, ctx: &Context) {
+        if !self.confirm_bulk_delete {
+            return;
+        }
+        let count = self
+            .selected_conversations
+            .as_ref()
+            .map_or(0, BTreeSet::len);
+        egui::Window::new("Delete conversations?")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "This deletes {} conversation(s) and all their messages. Forks of them \
+                     are kept.",
+                    count
+                ));
+                ui.horizontal(|ui| {
+                    if ui.button("Delete").clicked() {
+                        self.confirm_bulk_delete = false;
+                        let ids: Vec<i64> = self
+                            .selected_conversations
+                            .take()
+                            .unwrap_or_default()
+                            .into_iter()
+                            .collect();
+                        if let Err(e) = self.delete_conversations(&ids) {
+                            self.report_error(e);
+                        }
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.confirm_bulk_delete = false;
+                    }
+                });
+            });
+    }
+
+    fn draw_clear_index_confirmation(&mut self, ctx: &Context) {
+        if !self.confirm_clear_index {
+            return;
+        }
+        egui::Window::new("Clear index?")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(
+                    "This removes every indexed chunk. Conversations and settings are kept; \
+                     run Reindex Now to rebuild the index.",
+                );
+                ui.horizontal(|ui| {
+                    if ui.button("Clear Index").clicked() {
+                        self.confirm_clear_index = false;
+                        match self.clear_index().and_then(|()| self.index_stats()) {
+                            Ok(stats) => {
+                                self.index_stats = Some(stats);
+                                self.index_status = None;
+                                self.document_results.clear();
+                            }
+                            Err(e) => self.report_error(e.into()),
+                        }
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.confirm_clear_index = false;
+                    }
+                });
+            });
+    }
+
+    fn index_stats(&self) -> rusqlite::Result<indexer::IndexStats> {
+        indexer::index_stats(&self.conn, &self.settings.root_paths)
+    }
+
+    fn draw_index_stats(&mut self, ctx: &Context) {
+        let Some(stats) = &self.index_stats else {
+            return;
+        };
+        let ann_chunks = self.ann_index.lock().unwrap().as_ref().map(|ann| ann.len());
+        let mut open = true;
+        egui::Window::new("Index Statistics")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("index_stats_totals").show(ui, |ui| {
+                    ui.label("Documents:");
+                    ui.label(stats.documents.to_string());
+                    ui.end_row();
+                    ui.label("Chunks:");
+                    ui.label(stats.chunks.to_string());
+                    ui.end_row();
+                    ui.label("Indexed text:");
+                    ui.label(format!("{:.1} KiB", stats.bytes as f64 / 1024.0));
+                    ui.end_row();
+                    ui.label("Last indexed:");
+                    ui.label(
+                        stats
+                            .last_indexed_at
+                            .map_or("never".to_string(), format_timestamp),
+                    );
+                    ui.end_row();
+                    ui.label("Search:");
+                    ui.label(match ann_chunks {
+                        Some(chunks) => format!("nearest-neighbour graph of {} chunks", chunks),
+                        None => "exhaustive".to_string(),
+                    });
+                    ui.end_row();
+                });
+                if !stats.per_root.is_empty() {
+                    ui.separator();
+                    egui::Grid::new("index_stats_roots")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.strong("Root path");
+                            ui.strong("Documents");
+                            ui.strong("Chunks");
+                            ui.end_row();
+                            for (root, documents, chunks) in &stats.per_root {
+                                ui.label(root);
+                                ui.label(documents.to_string());
+                                ui.label(chunks.to_string());
+                                ui.end_row();
+                            }
+                        });
+                }
+                if !stats.guessed_encodings.is_empty() {
+                    ui.separator();
+                    egui::CollapsingHeader::new(format!(
+                        "{} files with a guessed encoding",
+                        stats.guessed_encodings.len()
+                    ))
+                    .id_source("index_stats_encodings")
+                    .show(ui, |ui| {
+                        ui.weak(
+                            "These files aren't UTF-8. Their text may contain wrong characters \
+                             if the guess was off.",
+                        );
+                        ScrollArea::vertical()
+                            .id_source("index_stats_encoding_scroll")
+                            .max_height(240.0)
+                            .show(ui, |ui| {
+                                egui::Grid::new("index_stats_encoding_list")
+                                    .striped(true)
+                                    .show(ui, |ui| {
+                                        for (path, encoding) in &stats.guessed_encodings {
+                                            ui.label(path);
+                                            ui.label(encoding);
+                                            ui.end_row();
+                                        }
+                                    });
+                            });
+                    });
+                }
+                if !stats.errors.is_empty() {
+                    ui.separator();
+                    egui::CollapsingHeader::new(format!(
+                        "{} files failed to index",
+                        stats.errors.len()
+                    ))
+                    .id_source("index_stats_errors")
+                    .show(ui, |ui| {
+                        ScrollArea::vertical().max_height(240.0).show(ui, |ui| {
+                            egui::Grid::new("index_stats_error_list")
+                                .striped(true)
+                                .show(ui, |ui| {
+                                    for error in &stats.errors {
+                                        ui.label(&error.path);
+                                        ui.label(&error.reason);
+                                        ui.end_row();
+                                    }
+                                });
+                        });
+                    });
+                }
+            });
+        if !open {
+            self.index_stats = None;
+        }
+    }
+
+    /// Reload the error log window from the database, including writes still queued,
+    /// once those have landed; the window opens then if it is closed.
+    fn refresh_error_log(&mut self) {
+        self.error_log_refresh = Some(self.db_writes_landed());
+    }
+
+    fn draw_error_log(&mut self, ctx: &Context) {
+        if let Some(rx) = &self.error_log_refresh {
+            match rx.try_recv() {
+                Ok(()) | Err(mpsc::TryRecvError::Disconnected) => {
+                    self.error_log_refresh = None;
+                    match error_log::recent(&self.conn, 500) {
+                        Ok(entries) => self.error_log = Some(entries),
+                        Err(e) => self.error_message = Some(AppError::from(e).to_string()),
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => ctx.request_repaint(),
+            }
+        }
+        let Some(entries) = &self.error_log else {
+            return;
+        };
+        let mut open = true;
+        let mut refresh = false;
+        let mut clear = false;
+        egui::Window::new("Error log")
+            .open(&mut open)
+            .default_width(600.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    refresh = ui.button("Refresh").clicked();
+                    if ui
+                        .add_enabled(!entries.is_empty(), egui::Button::new("Copy all"))
+                        .on_hover_text("Copy the log as text, e.g. for a bug report")
+                        .clicked()
+                    {
+                        let text = entries
+                            .iter()
+                            .map(|e| {
+                                format!(
+                                    "{} [{}] {}",
+                                    format_timestamp(e.logged_at),
+                                    e.context,
+                                    e.message
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n");
+                        ui.output_mut(|o| o.copied_text = text);
+                    }
+                    clear = ui
+                        .add_enabled(!entries.is_empty(), egui::Button::new("Clear"))
+                        .clicked();
+                });
+                ui.separator();
+                if entries.is_empty() {
+                    ui.label("No errors logged.");
+                    return;
+                }
+                ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                    egui::Grid::new("error_log_entries")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for entry in entries {
+                                ui.label(format_timestamp(entry.logged_at));
+                                ui.label(&entry.context);
+                                ui.add(egui::Label::new(&entry.message).wrap(true));
+                                ui.end_row();
+                            }
+                        });
+                });
+            });
+        if !open {
+            self.error_log = None;
+            self.error_log_refresh = None;
+        } else if clear {
+            // Queued behind the errors still to be logged, so those are cleared too.
+            match self.queue_db_write(DbCommand::ClearErrorLog) {
+                Ok(()) => self.error_log = Some(Vec::new()),
+                Err(e) => self.report_error(e),
+            }
+        } else if refresh {
+            self.refresh_error_log();
+        }
+    }
+
+    /// Bottom bar naming the backend and model replies come from, with a dot showing
+    /// whether the backend answered its latest health check: green when it did, amber
+    /// when it answered with an error, red when it could not be reached.
+    fn draw_status_bar(&mut self, ctx: &Context) {
+        let health = self
+            .backend_health
+            .get_or_insert_with(|| {
+                Self::spawn_health_checker(ctx.clone(), Arc::clone(&self.saved_settings))
+            })
+            .lock()
+            .unwrap()
+            .clone();
+        let (color, status) = match &health {
+            llm::Health::Unknown => (egui::Color32::GRAY, "Checking…".to_string()),
+            llm::Health::Reachable => (
+                egui::Color32::from_rgb(60, 170, 80),
+                "Reachable".to_string(),
+            ),
+            llm::Health::Failing(e) => (egui::Color32::from_rgb(220, 150, 30), e.clone()),
+            llm::Health::Unreachable(e) => (egui::Color32::RED, e.clone()),
+        };
+        // The health check pings the saved backend, so that is the one named here.
+        let (backend, model) = {
+            let saved = self.saved_settings.read().unwrap();
+            let model = saved
+                .backend_model()
+                .map(|configured| self.conversation_model(configured));
+            (saved.backend, model)
+        };
+        TopBottomPanel::bottom("status_bar").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.colored_label(color, "●").on_hover_text(&status);
+                ui.small(backend.label()).on_hover_text(&status);
+                if let Some(model) = model {
+                    ui.small("·");
+                    ui.small(model);
+                }
+            });
+        });
+    }
+
+    /// Progress bar and cancel button for a manual reindex, if one is running.
+    fn draw_index_progress(&self, ui: &mut Ui) {
+        if self.index_rx.is_none() {
+            return;
+        }
+        let (files_done, files_total) = self.index_progress;
+        if ui.small_button("Cancel").clicked() {
+            self.cancel_indexing();
+        }
+        let fraction = if files_total == 0 {
+            0.0
+        } else {
+            files_done as f32 / files_total as f32
+        };
+        ui.add(
+            egui::ProgressBar::new(fraction)
+                .desired_width(200.0)
+                .text(format!("Indexing {}/{} files", files_done, files_total)),
+        );
+    }
+
+    /// How the text embedded to retrieve context for `question` is found. With query
+    /// expansion on, the backend first rewrites it into a standalone search query,
+    /// which recalls far more for terse follow-ups like "and for PDFs?". `None`, so
+    /// that the question itself is embedded, when expansion is off or for the stub
+    /// backend.
+    fn query_expansion(&self, question: &str) -> Option<QueryExpansion> {
+        if !self.settings.expand_queries || self.settings.backend == LlmBackend::Stub {
+            return None;
+        }
+        let mut recent: Vec<&Message> = self
+            .conversation
+            .messages
+            .iter()
+            .filter(|m| m.role != "system" && !m.queued && !m.content.trim().is_empty())
+            .collect();
+        // When a reply is retried, the question is already in the conversation.
+        if recent
+            .last()
+            .is_some_and(|m| m.role == "user" && m.content == question)
+        {
+            recent.pop();
+        }
+        let start = recent.len().saturating_sub(QUERY_EXPANSION_HISTORY);
+        let mut messages: Vec<Message> = recent[start..]
+            .iter()
+            .map(|m| Message::new(m.role.clone(), m.content.clone()))
+            .collect();
+        messages.push(Message::new(
+            "user",
+            format!("{}\n\nQuestion: {}", QUERY_EXPANSION_INSTRUCTION, question),
+        ));
+
+        Some(QueryExpansion {
+            request: self.backend_request(),
+            messages,
+        })
+    }
+
+    /// Keyword search over the indexed documents, returning (path, snippet) pairs.
+    fn search_documents(&self, query: &str) -> Vec<(String, String)> {
+        retrieval::full_text_search(&self.conn, query, 20).unwrap_or_default()
+    }
+
+    /// Find conversations with a message containing `query` (case-insensitively).
+    /// Returns (conversation id, snippet of the first match), newest conversation first.
+    fn search_conversations(&self, query: &str) -> Vec<(i64, String)> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Vec::new();
+        }
+        // LIKE narrows down the rows, so it has to look for the query the way the
+        // messages JSON spells it; the snippet then comes from the decoded messages.
+        let json_query = serde_json::to_string(query).unwrap_or_default();
+        let json_query = json_query.trim_matches('"');
+        let pattern = format!(
+            "%{}%",
+            json_query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let rows = self
+            .conn
+            .prepare(
+                "SELECT id, messages FROM conversation
+                 WHERE messages LIKE ?1 ESCAPE '\\' ORDER BY id DESC",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map([pattern], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .unwrap_or_default();
+        rows.into_iter()
+            .filter_map(|(id, messages)| {
+                let messages: Vec<Message> = serde_json::from_str(&messages).ok()?;
+                let snippet = messages
+                    .iter()
+                    .find_map(|m| match_snippet(&m.content, query))?;
+                Some((id, snippet))
+            })
+            .collect()
+    }
+
+    /// What retrieving context for `question` takes: the configured number of chunks
+    /// most similar to it, after query expansion if `expand` is set, within the open
+    /// conversation's scope. `None` when retrieval is turned off.
+    fn retrieval_job(&self, question: &str, expand: bool) -> Option<RetrievalJob> {
+        let k = self.settings.retrieval_top_k.max(0) as usize;
+        if k == 0 {
+            return None;
+        }
+        Some(RetrievalJob {
+            query: question.to_string(),
+            expansion: expand.then(|| self.query_expansion(question)).flatten(),
+            k,
+            min_similarity: self.settings.min_similarity,
+            scope: self.conversation.scope.clone(),
+            // The open conversation's own chunks would only repeat its history.
+            skip_path: self
+                .settings
+                .index_conversations
+                .then(|| indexer::conversation_path(self.conversation.id)),
+            // The user is waiting on this one, so a rate-limited request isn't retried.
+            embedder: llm::EmbeddingConfig {
+                max_retries: 0,
+                ..self.settings.embedding_config()
+            },
+            ann_index: Arc::clone(&self.ann_index),
+            db_path: self.db_path.clone(),
+        })
+    }
+
+    /// The context for a reply to `question`, retrieved once the request starts.
+    fn reply_context(&self, question: &str) -> ReplyContext {
+        match self.retrieval_job(question, true) {
+            Some(job) => ReplyContext::Retrieve(Box::new(job)),
+            None => ReplyContext::Passages(Vec::new()),
+        }
+    }
+
+    /// How the indexed conversation `id` is referred to: by its title in
+    /// `conversations`, if it still exists.
+    fn conversation_name(
+        conversations: &[(i64, String, Option<i64>, Vec<String>)],
+        id: i64,
+    ) -> String {
+        match conversations.iter().find(|(c, _, _, _)| *c == id) {
+            Some((_, title, _, _)) => format!("Conversation “{}”", title),
+            None => "A deleted conversation".to_string(),
+        }
+    }
+
+    /// `source` as listed under a reply: indexed conversations by title.
+    fn source_label(
+        conversations: &[(i64, String, Option<i64>, Vec<String>)],
+        source: &Source,
+    ) -> String {
+        match indexer::conversation_id(&source.path) {
+            Some(id) => format!(
+                "{} (chunk {})",
+                Self::conversation_name(conversations, id),
+                source.chunk_index
+            ),
+            None => source.to_string(),
+        }
+    }
+
+    /// Whether anything has been indexed yet.
+    fn has_indexed_chunks(&self) -> bool {
+        self.conn
+            .query_row("SELECT EXISTS(SELECT 1 FROM documents)", [], |row| {
+                row.get(0)
+            })
+            .unwrap_or(false)
+    }
+
+    fn api_key_entry(user: &str) -> keyring::Result<keyring::Entry> {
+        keyring::Entry::new(KEYRING_SERVICE, user)
+    }
+
+    /// Read the API key stored for `user` from the OS keychain, if there is one.
+    fn load_api_key(user: &str) -> Option<String> {
+        Self::api_key_entry(user)
+            .and_then(|e| e.get_password())
+            .ok()
+    }
+
+    /// Write the API key for `user` to the OS keychain, or remove it when `api_key`
+    /// is empty.
+    fn store_api_key(user: &str, api_key: &str) -> keyring::Result<()> {
+        let entry = Self::api_key_entry(user)?;
+        if api_key.is_empty() {
+            match entry.delete_password() {
+                Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                Err(e) => Err(e),
+            }
+        } else {
+            entry.set_password(api_key)
+        }
+    }
+
+    /// Push an empty assistant message, then run `request` with the history on a
+    /// background thread. The passages of `context`, retrieved on that thread first if
+    /// need be, are injected just before the question in the request payload only, so
+    /// they don't clutter the stored transcript; they are sent back over
+    /// `self.stream_rx` ahead of the reply, for `apply_context` to record. Tokens the
+    /// request produces are sent back the same way and appended to the assistant
+    /// message in `update`; so are retry notifications.
+    fn spawn_llm_request<F>(&mut self, context: ReplyContext, request: F)
+    where
+        F: FnOnce(
+                &[Message],
+                &mut dyn FnMut(u32) -> ControlFlow<()>,
+                &mut dyn FnMut(&str) -> ControlFlow<()>,
+            ) -> Result<llm::ChatOutcome, llm::ChatError>
+            + Send
+            + 'static,
+    {
+        let mut messages = self.request_messages(&self.conversation.messages, &[]);
+        self.last_request = self.http_request(&messages);
+        self.conversation
+            .messages
+            .push(Message::new("assistant", ""));
+
+        let (tx, rx) = mpsc::channel();
+        self.stream_rx = Some(rx);
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.cancel_flag = Some(Arc::clone(&cancel));
+        thread::spawn(move || {
+            let retrieved = match context {
+                ReplyContext::Passages(passages) => Ok(passages),
+                ReplyContext::Retrieve(job) => job.run(&cancel),
+            };
+            insert_context(&mut messages, retrieved.as_deref().unwrap_or_default());
+            let _ = tx.send(StreamEvent::Context(retrieved));
+            // Stopped while retrieving: don't send a request nobody waits for.
+            if cancel.load(Ordering::Relaxed) {
+                return;
+            }
+            let mut on_token = |token: &str| {
+                if cancel.load(Ordering::Relaxed) {
+                    return ControlFlow::Break(());
+                }
+                let _ = tx.send(StreamEvent::Token(token.to_string()));
+                ControlFlow::Continue(())
+            };
+            let mut on_retry = |attempt: u32| {
+                if cancel.load(Ordering::Relaxed) {
+                    return ControlFlow::Break(());
+                }
+                let _ = tx.send(StreamEvent::Retrying(attempt));
+                ControlFlow::Continue(())
+            };
+            let event = match request(&messages, &mut on_retry, &mut on_token) {
+                Ok(outcome) => StreamEvent::Done(outcome),
+                Err(llm::ChatError::Unreachable(err)) => StreamEvent::Unreachable(err),
+                Err(llm::ChatError::Failed(err)) => StreamEvent::Error(err),
+            };
+            let _ = tx.send(event);
+        });
+    }
+
+    /// Record the passages the in-flight reply is given: as the sources of the
+    /// assistant message, for regenerating it, and in the request "Copy as cURL" copies.
+    /// A failed retrieval is logged, and the reply recorded as given none.
+    fn apply_context(&mut self, retrieved: Result<Vec<ScoredChunk>, String>) {
+        let passages = retrieved.unwrap_or_else(|e| {
+            self.log_error("retrieval", &e);
+            Vec::new()
+        });
+        let no_relevant_context =
+            passages.is_empty() && self.settings.retrieval_top_k > 0 && self.has_indexed_chunks();
+        let messages = &mut self.conversation.messages;
+        if let Some(reply) = messages.last_mut().filter(|m| m.role == "assistant") {
+            reply.sources = passages
+                .iter()
+                .map(|p| Source {
+                    score: Some(p.score),
+                    content: Some(p.content.clone()),
+                    ..p.source.clone()
+                })
+                .collect();
+            reply.no_relevant_context = no_relevant_context;
+        }
+        let messages = &self.conversation.messages;
+        let history = &messages[..messages.len().saturating_sub(1)];
+        self.last_request = self.http_request(&self.request_messages(history, &passages));
+        self.last_retrieval = Some((self.conversation.id, passages));
+    }
+
+    /// Apply any tokens received from the in-flight request to the last message.
+    fn drain_stream(&mut self) {
+        let Some(rx) = &self.stream_rx else {
+            return;
+        };
+        let mut finished = false;
+        let mut replied = false;
+        let mut queued_error = None;
+        let mut context = None;
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                StreamEvent::Context(passages) => context = Some(passages),
+                StreamEvent::Retrying(attempt) => self.retry_attempt = Some(attempt),
+                StreamEvent::Token(token) => {
+                    self.retry_attempt = None;
+                    if let Some(last) = self.conversation.messages.last_mut() {
+                        last.content.push_str(&token);
+                    }
+                }
+                StreamEvent::Done(outcome) => {
+                    let messages = &mut self.conversation.messages;
+                    if let [.., question, _] = messages.as_mut_slice() {
+                        question.failed = None;
+                    }
+                    if let Some(last) = messages.last_mut().filter(|m| m.role == "assistant") {
+                        self.message_processor.post_receive(last);
+                        last.usage = outcome.usage;
+                        last.tool_calls = outcome.tool_calls;
+                        last.has_images = markdown::contains_images(&last.content);
+                    }
+                    finished = true;
+                    replied = true;
+                }
+                StreamEvent::Error(err) => {
+                    self.log_error(self.settings.backend.label(), &err);
+                    // Drop the placeholder if nothing arrived, otherwise keep the partial
+                    // reply, and mark whichever ends the turn so it can be retried.
+                    if self
+                        .conversation
+                        .messages
+                        .last()
+                        .is_some_and(|m| m.role == "assistant" && m.content.is_empty())
+                    {
+                        self.conversation.messages.pop();
+                    }
+                    match self
+                        .conversation
+                        .messages
+                        .last_mut()
+                        .filter(|m| m.role == "user" || m.role == "assistant")
+                    {
+                        Some(turn) => turn.failed = Some(err),
+                        None => self.conversation.messages.push(Message::new("system", err)),
+                    }
+                    finished = true;
+                }
+                StreamEvent::Unreachable(err) => {
+                    self.log_error(self.settings.backend.label(), &err);
+                    if self
+                        .conversation
+                        .messages
+                        .last()
+                        .is_some_and(|m| m.role == "assistant" && m.content.is_empty())
+                    {
+                        self.conversation.messages.pop();
+                    }
+                    match self
+                        .conversation
+                        .messages
+                        .last_mut()
+                        .filter(|m| m.role == "user")
+                    {
+                        Some(question) => {
+                            question.queued = true;
+                            queued_error = Some(err);
+                        }
+                        None => self.conversation.messages.push(Message::new("system", err)),
+                    }
+                    finished = true;
+                }
+            }
+        }
+        if let Some(retrieved) = context {
+            self.apply_context(retrieved);
+        }
+        if let Some(err) = queued_error {
+            self.schedule_outbox_retry(err);
+        }
+        if finished {
+            self.finish_stream();
+        }
+        // The first answer is in: replace the title taken from the question.
+        let first_reply = self
+            .conversation
+            .messages
+            .iter()
+            .filter(|m| m.role == "assistant")
+            .count()
+            == 1;
+        let titling = self.summary_jobs.iter().any(|job| {
+            job.conversation_id == self.conversation.id && job.kind == SummaryKind::Title
+        });
+        if replied && first_reply && !self.conversation.title_manual && !titling {
+            self.request_summary(SummaryKind::Title);
+        }
+    }
+
+    /// Ask the active backend, in the background, for a summary or a title of the
+    /// open conversation. `drain_summary_jobs` stores the result.
+    fn request_summary(&mut self, kind: SummaryKind) {
+        let mut history: Vec<Message> = self
+            .conversation
+            .messages
+            .iter()
+            .filter(|m| !m.queued && !m.content.trim().is_empty())
+            .cloned()
+            .collect();
+        history.push(Message::new("user", kind.instruction()));
+        let messages = self.request_messages(&history, &[]);
+        let request = self.backend_request();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reply = String::new();
+            let result = request(
+                &messages,
+                &mut |_| ControlFlow::Continue(()),
+                &mut |token| {
+                    reply.push_str(token);
+                    ControlFlow::Continue(())
+                },
+            );
+            let _ = tx.send(result.map(|_| reply).map_err(|e| e.to_string()));
+        });
+        self.summary_jobs.push(SummaryJob {
+            conversation_id: self.conversation.id,
+            kind,
+            rx,
+        });
+    }
+
+    /// Store the summaries and titles the backend has finished writing.
+    fn drain_summary_jobs(&mut self, ctx: &Context) {
+        if self.summary_jobs.is_empty() {
+            return;
+        }
+        let mut finished = Vec::new();
+        self.summary_jobs.retain(|job| match job.rx.try_recv() {
+            Ok(result) => {
+                finished.push((job.conversation_id, job.kind, result));
+                false
+            }
+            Err(mpsc::TryRecvError::Empty) => true,
+            Err(mpsc::TryRecvError::Disconnected) => false,
+        });
+        for (id, kind, result) in finished {
+            let saved = match (kind, result) {
+                (SummaryKind::Summary, Ok(summary)) => {
+                    self.save_conversation_summary(id, summary.trim())
+                }
+                (SummaryKind::Title, Ok(title)) => {
+                    let title = generated_title(&title);
+                    // A name the user gave in the meantime wins.
+                    match self.has_automatic_title(id) {
+                        Ok(true) if !title.is_empty() => {
+                            self.save_conversation_title(id, &title, false)
+                        }
+                        Ok(_) => Ok(()),
+                        Err(e) => Err(e),
+                    }
+                }
+                (SummaryKind::Summary, Err(e)) => {
+                    self.log_error(self.settings.backend.label(), &e);
+                    self.error_message = Some(format!("Could not summarize: {}", e));
+                    Ok(())
+                }
+                // Titles are a convenience; the conversation keeps its default one.
+                (SummaryKind::Title, Err(e)) => {
+                    self.log_error(self.settings.backend.label(), &e);
+                    Ok(())
+                }
+            };
+            if let Err(e) = saved {
+                self.report_error(e);
+            }
+        }
+        if !self.summary_jobs.is_empty() {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+    }
+
+    fn finish_stream(&mut self) {
+        self.stream_rx = None;
+        self.retry_attempt = None;
+        self.cancel_flag = None;
+        // Anything but a question still waiting at the end has been delivered by now.
+        let messages = &mut self.conversation.messages;
+        let waiting = messages.last().is_some_and(|m| m.queued);
+        let delivered = messages.len() - usize::from(waiting);
+        for message in &mut messages[..delivered] {
+            message.queued = false;
+        }
+        if !waiting {
+            self.outbox_retry = None;
+        }
+        if let Err(e) = self.save_conversation() {
+            self.report_error(e);
+        }
+    }
+
+    /// Back off before the next attempt to deliver the queued question.
+    fn schedule_outbox_retry(&mut self, error: String) {
+        let attempts = self.outbox_retry.as_ref().map_or(0, |r| r.attempts) + 1;
+        let delay = OUTBOX_BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(attempts - 1))
+            .min(OUTBOX_MAX_DELAY);
+        self.outbox_retry = Some(OutboxRetry {
+            attempts,
+            next_attempt: Instant::now() + delay,
+            error,
+        });
+    }
+
+    /// Whether the conversation ends in a question that could not be delivered yet.
+    fn has_queued_message(&self) -> bool {
+        self.conversation.messages.last().is_some_and(|m| m.queued)
+    }
+
+    /// Ask the backend again to answer the queued question at the end of the conversation.
+    fn deliver_queued_message(&mut self) {
+        let Some(question) = self
+            .conversation
+            .messages
+            .last()
+            .filter(|m| m.queued)
+            .map(|m| m.content.clone())
+        else {
+            return;
+        };
+        self.request_reply(self.reply_context(&question));
+    }
+
+    /// Send the queued question once its backoff has run out. Returns how long until
+    /// the next attempt is due, if one is pending.
+    fn poll_outbox(&mut self) -> Option<Duration> {
+        if self.stream_rx.is_some() {
+            return None;
+        }
+        let next_attempt = self.outbox_retry.as_ref()?.next_attempt;
+        if !self.has_queued_message() {
+            self.outbox_retry = None;
+            return None;
+        }
+        let now = Instant::now();
+        if now < next_attempt {
+            return Some(next_attempt - now);
+        }
+        self.deliver_queued_message();
+        None
+    }
+
+    /// Abort the in-flight request. The background thread notices the flag at its
+    /// next token; the UI stops waiting right away and keeps the partial reply.
+    fn cancel_request(&mut self) {
+        if let Some(cancel) = &self.cancel_flag {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        self.drain_stream();
+        if self.stream_rx.is_none() {
+            return;
+        }
+        if let Some(last) = self
+            .conversation
+            .messages
+            .last_mut()
+            .filter(|m| m.role == "assistant")
+        {
+            last.interrupted = true;
+        }
+        self.finish_stream();
+    }
+
+    /// What is actually sent for `history`: only the messages within the history
+    /// window, the retrieved `passages` just before the last message, and for real
+    /// backends the system prompt up front.
+    fn request_messages(&self, history: &[Message], passages: &[ScoredChunk]) -> Vec<Message> {
+        let mut messages = history.to_vec();
+        messages.drain(outside_history_window(
+            &messages,
+            self.settings.history_window,
+            0,
+        ));
+        if self.settings.backend != LlmBackend::Stub {
+            apply_system_prompt(&mut messages, &self.settings.system_prompt);
+        }
+        insert_context(&mut messages, passages);
+        messages
+    }
+
+    /// The request that sending the current input would make, assembled the same way
+    /// (pruning, retrieval, system prompt) but without sending anything. The context
+    /// is retrieved on a thread of its own and added once it arrives; the question is
+    /// not expanded first, as that would be a request to the backend.
+    fn preview_prompt(&self) -> PromptPreview {
+        let message = self.input_message();
+        if self.input_role != "user" {
+            // Only added to the conversation; nothing would be sent.
+            let mut messages = self.conversation.messages.clone();
+            messages.push(message);
+            return PromptPreview {
+                messages,
+                context_rx: None,
+            };
+        }
+        let job = self.retrieval_job(&message.content, false);
+        let messages = self.assemble_request(message);
+        let context_rx = job.map(|job| {
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let _ = tx.send(job.run(&AtomicBool::new(false)));
+            });
+            rx
+        });
+        PromptPreview {
+            messages,
+            context_rx,
+        }
+    }
+
+    /// The input and attachments as the message sending them adds, after the
+    /// `pre_send` hook.
+    fn input_message(&self) -> Message {
+        let mut message = Message::new(self.input_role, self.current_input.clone());
+        message.attachments = self.attachments.clone();
+        self.message_processor.pre_send(&mut message);
+        message
+    }
+
+    /// The messages sending `message` would put in the request, before the retrieved
+    /// context is added.
+    fn assemble_request(&self, message: Message) -> Vec<Message> {
+        let mut history = self.conversation.messages.clone();
+        prune_messages(
+            &mut history,
+            self.settings.context_limit_tokens.max(1) as usize,
+            &message.request_content(),
+        );
+        history.push(message);
+        self.request_messages(&history, &[])
+    }
+
+    /// The most tokens the retrieved context can add to a request: the configured
+    /// number of chunks, each as long as a chunk can be.
+    fn context_token_allowance(&self) -> usize {
+        let chunk_tokens = self
+            .settings
+            .chunking()
+            .size_chars
+            .div_ceil(llm::CHARS_PER_TOKEN);
+        self.settings.retrieval_top_k.max(0) as usize * chunk_tokens
+    }
+
+    /// Send what has been typed. A question whose request could exceed
+    /// `max_prompt_tokens` once its context is retrieved waits in `large_prompt` until
+    /// the user confirms it.
+    fn submit_input(&mut self) {
+        // A second click or Enter can land before the frame that disables Send.
+        if self.stream_rx.is_some() || self.large_prompt.is_some() {
+            return;
+        }
+        if self.current_input.trim().is_empty() && self.attachments.is_empty() {
+            return;
+        }
+        // Resending a question that is still queued for delivery would ask it twice;
+        // try to deliver the queued one now instead.
+        let already_queued = self.conversation.messages.last().is_some_and(|m| {
+            m.queued && m.content.trim() == self.current_input.trim() && self.attachments.is_empty()
+        });
+        if already_queued {
+            self.current_input.clear();
+            self.draft_changed_at = Some(Instant::now());
+            self.deliver_queued_message();
+            return;
+        }
+        if self.input_role != "user" {
+            self.send_input(false);
+            return;
+        }
+        let tokens = self.context_token_allowance()
+            + self
+                .assemble_request(self.input_message())
+                .iter()
+                .map(|m| llm::estimate_tokens(&m.request_content()))
+                .sum::<usize>();
+        let limit = self.settings.max_prompt_tokens;
+        if limit > 0 && tokens > limit as usize {
+            self.large_prompt = Some(LargePrompt { tokens });
+        } else {
+            self.send_input(true);
+        }
+    }
+
+    /// Move the input and attachments into the conversation. With `ask` set, the
+    /// message is a question sent to the backend along with context retrieved for it.
+    fn send_input(&mut self, ask: bool) {
+        let message = self.input_message();
+        self.current_input.clear();
+        self.attachments.clear();
+        self.draft_changed_at = Some(Instant::now());
+        let untitled = !self.conversation.title_manual
+            && self.conversation.title == DEFAULT_CONVERSATION_TITLE
+            && message.role == "user";
+        if untitled {
+            let title = first_line(&message.content, GENERATED_TITLE_MAX_CHARS);
+            if !title.is_empty() {
+                let id = self.conversation.id;
+                if let Err(e) = self.save_conversation_title(id, &title, false) {
+                    self.report_error(e);
+                }
+            }
+        }
+        if ask {
+            self.prune_to_context_limit(&message.request_content());
+            let context = self.reply_context(&message.content);
+            self.conversation.messages.push(message);
+            self.request_reply(context);
+        } else {
+            self.conversation.messages.push(message);
+        }
+        if let Err(e) = self.save_conversation() {
+            self.report_error(e);
+        }
+    }
+
+    /// Ask before sending a request larger than `max_prompt_tokens`.
+    fn draw_large_prompt_confirmation(&mut self, ctx: &Context) {
+        let Some(large) = &self.large_prompt else {
+            return;
+        };
+        let mut send = false;
+        let mut cancel = false;
+        egui::Window::new("Send a large prompt?")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "With the context retrieved for it, this request can reach about {} \
+                     tokens, over the limit of {} set in the settings.",
+                    large.tokens, self.settings.max_prompt_tokens
+                ));
+                if self.settings.backend.is_paid() {
+                    let usage = llm::Usage {
+                        prompt_tokens: large.tokens as u64,
+                        completion_tokens: 0,
+                    };
+                    ui.label(format!(
+                        "The prompt alone would cost about {} on {}.",
+                        format_cost(self.settings.usage_cost(usage)),
+                        self.settings.backend.label()
+                    ));
+                }
+                ui.weak(
+                    "To make it smaller, shorten the message, remove attachments or retrieve \
+                     fewer chunks.",
+                );
+                ui.horizontal(|ui| {
+                    send = ui.button("Send anyway").clicked();
+                    cancel = ui.button("Cancel").clicked();
+                });
+            });
+        if send {
+            self.large_prompt = None;
+            self.send_input(true);
+        } else if cancel {
+            self.large_prompt = None;
+        }
+    }
+
+    fn draw_prompt_preview(&mut self, ctx: &Context) {
+        let Some(preview) = &mut self.prompt_preview else {
+            return;
+        };
+        if let Some(rx) = &preview.context_rx {
+            match rx.try_recv() {
+                Ok(retrieved) => {
+                    preview.context_rx = None;
+                    match retrieved {
+                        Ok(passages) => insert_context(&mut preview.messages, &passages),
+                        Err(e) => {
+                            self.log_error("retrieval", &e);
+                            ctx.request_repaint();
+                            return;
+                        }
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => ctx.request_repaint(),
+                Err(mpsc::TryRecvError::Disconnected) => preview.context_rx = None,
+            }
+        }
+        let retrieving = preview.context_rx.is_some();
+        let messages = &preview.messages;
+        let tokens: usize = messages
+            .iter()
+            .map(|m| llm::estimate_tokens(&m.request_content()))
+            .sum();
+        let mut open = true;
+        egui::Window::new("Prompt preview")
+            .open(&mut open)
+            .default_width(600.0)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} messages, ~{} tokens, for {}",
+                    messages.len(),
+                    tokens,
+                    self.settings.backend.label()
+                ));
+                if retrieving {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.weak("Retrieving context…");
+                    });
+                }
+                if self.settings.expand_queries && self.settings.backend != LlmBackend::Stub {
+                    ui.weak(
+                        "Context retrieved for the question as typed; sending it rewrites \
+                         the question first, which may retrieve different passages.",
+                    );
+                }
+                ui.separator();
+                ScrollArea::vertical().max_height(500.0).show(ui, |ui| {
+                    for message in messages {
+                        let content = message.request_content();
+                        ui.label(
+                            egui::RichText::new(format!(
+                                "{} (~{} tokens)",
+                                message.role,
+                                llm::estimate_tokens(&content)
+                            ))
+                            .strong(),
+                        );
+                        ui.add(
+                            egui::TextEdit::multiline(&mut content.as_ref())
+                                .code_editor()
+                                .desired_rows(1)
+                                .desired_width(f32::INFINITY),
+                        );
+                        ui.add_space(4.0);
+                    }
+                });
+            });
+        if !open {
+            self.prompt_preview = None;
+        }
+    }
+
+    /// Drop the oldest non-system messages until the conversation plus `pending_input`
+    /// fits the configured context limit; see `prune_messages`.
+    fn prune_to_context_limit(&mut self, pending_input: &str) {
+        prune_messages(
+            &mut self.conversation.messages,
+            self.settings.context_limit_tokens.max(1) as usize,
+            pending_input,
+        );
+    }
+
+    /// Add the user's `message` to the conversation and ask the selected backend to
+    /// answer it.
+    fn send_message(&mut self, mut message: Message) {
+        self.message_processor.pre_send(&mut message);
+        let context = self.reply_context(&message.content);
+        self.conversation.messages.push(message);
+        self.request_reply(context);
+    }
+
+    /// Read a file dropped onto the window and hold it for the next message.
+    fn attach_dropped_file(&mut self, file: &egui::DroppedFile) {
+        let name = file
+            .path
+            .as_deref()
+            .and_then(Path::file_name)
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| file.name.clone());
+        let text = match (&file.path, &file.bytes) {
+            (Some(path), _) => indexer::read_document_text(path),
+            (None, Some(bytes)) => indexer::decode_text(bytes.to_vec()).map(|d| d.text),
+            (None, None) => Err("its contents were not available".to_string()),
+        };
+        let content = match text {
+            Ok(content) => content,
+            Err(reason) => {
+                let message = format!("{} could not be read as text: {}.", name, reason);
+                self.log_error("attachment", &message);
+                self.error_message = Some(message);
+                return;
+            }
+        };
+        self.attach_text(name, content);
+    }
+
+    /// Hold `content` for the next message, cut to `ATTACHMENT_MAX_CHARS`.
+    fn attach_text(&mut self, name: String, mut content: String) {
+        let truncated = content.chars().count() > ATTACHMENT_MAX_CHARS;
+        if truncated {
+            content = content.chars().take(ATTACHMENT_MAX_CHARS).collect();
+        }
+        self.attachments.push(Attachment {
+            name,
+            content,
+            truncated,
+        });
+    }
+
+    /// Ask what to do with a large paste: attach it, insert it after all, or drop it.
+    fn draw_pending_paste(&mut self, ui: &mut Ui) {
+        let Some(text) = &self.pending_paste else {
+            return;
+        };
+        let mut attach = false;
+        let mut insert = false;
+        let mut discard = false;
+        ui.horizontal_wrapped(|ui| {
+            ui.label(format!(
+                "Pasted {} characters ({} lines).",
+                text.chars().count(),
+                text.lines().count()
+            ));
+            attach = ui
+                .button("Attach as file")
+                .on_hover_text("Send it as context with the next message")
+                .clicked();
+            insert = ui.button("Paste into message").clicked();
+            discard = ui.button("Discard").clicked();
+        });
+        if attach {
+            if let Some(text) = self.pending_paste.take() {
+                self.attach_text("pasted text".to_string(), text);
+            }
+        } else if insert {
+            if let Some(text) = self.pending_paste.take() {
+                self.current_input.push_str(&text);
+                self.draft_changed_at = Some(Instant::now());
+            }
+        } else if discard {
+            self.pending_paste = None;
+        }
+    }
+
+    /// Chips for the attachments waiting to be sent, each with a remove button.
+    fn draw_pending_attachments(&mut self, ui: &mut Ui) {
+        let mut removed = None;
+        ui.horizontal_wrapped(|ui| {
+            for (i, attachment) in self.attachments.iter().enumerate() {
+                ui.group(|ui| {
+                    ui.label(format!("📎 {}", attachment.name))
+                        .on_hover_text(format!(
+                            "{} characters",
+                            attachment.content.chars().count()
+                        ));
+                    if attachment.truncated {
+                        ui.colored_label(ui.visuals().warn_fg_color, "truncated")
+                            .on_hover_text(format!(
+                                "Only the first {} characters are sent",
+                                ATTACHMENT_MAX_CHARS
+                            ));
+                    }
+                    if ui.small_button("×").clicked() {
+                        removed = Some(i);
+                    }
+                });
+            }
+        });
+        if let Some(i) = removed {
+            self.attachments.remove(i);
+        }
+    }
+
+    /// Drop the last assistant reply and ask the backend again, with the same history
+    /// and, when still available, the same retrieved passages as the original send.
+    fn regenerate_last_response(&mut self) {
+        let has_reply = self
+            .conversation
+            .messages
+            .last()
+            .is_some_and(|m| m.role == "assistant");
+        if !has_reply {
+            return;
+        }
+        self.conversation.messages.pop();
+        let context = match self.last_retrieval.take() {
+            Some((id, passages)) if id == self.conversation.id => ReplyContext::Passages(passages),
+            _ => {
+                let question = self
+                    .conversation
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|m| m.role == "user")
+                    .map(|m| m.content.clone())
+                    .unwrap_or_default();
+                self.reply_context(&question)
+            }
+        };
+        self.request_reply(context);
+    }
+
+    /// Ask the backend again to answer the question whose reply failed, dropping any
+    /// partial reply. The failure stays marked until an answer arrives.
+    fn retry_failed_turn(&mut self) {
+        let messages = &mut self.conversation.messages;
+        let failed = messages.last().is_some_and(|m| m.failed.is_some());
+        if !failed {
+            return;
+        }
+        if messages.last().is_some_and(|m| m.role == "assistant") {
+            messages.pop();
+        }
+        let Some(question) = messages
+            .last()
+            .filter(|m| m.role == "user")
+            .map(|m| m.content.clone())
+        else {
+            return;
+        };
+        self.request_reply(self.reply_context(&question));
+    }
+
+    /// Have whichever backend is selected in the settings answer the conversation so
+    /// far, with `context`.
+    fn request_reply(&mut self, context: ReplyContext) {
+        let request = self.backend_request();
+        self.spawn_llm_request(context, request);
+    }
+
+    /// A request to the active backend with the current settings and the open
+    /// conversation's model.
+    fn backend_request(&self) -> BackendRequest {
+        let sampling = self.settings.sampling();
+        let options = self.settings.request_options();
+        match self.settings.backend {
+            LlmBackend::Stub => Box::new(|messages, _, on_token| {
+                llm::stub_chat(messages, on_token).map(|()| llm::ChatOutcome::default())
+            }),
+            // The OpenAI chat completions API, or another provider speaking it.
+            LlmBackend::OpenAi => {
+                let base_url = self.settings.openai_base_url.trim().to_string();
+                let api_key = self.settings.api_key.clone();
+                let model = self.conversation_model(&self.settings.model);
+                Box::new(move |messages, on_retry, on_token| {
+                    llm::openai_chat(
+                        &base_url, &api_key, &model, messages, sampling, options, on_retry,
+                        on_token,
+                    )
+                })
+            }
+            // A local or remote Ollama server.
+            LlmBackend::Ollama => {
+                let url = self.settings.ollama_url.clone();
+                let model = self.conversation_model(&self.settings.ollama_model);
+                Box::new(move |messages, on_retry, on_token| {
+                    llm::ollama_chat(
+                        &url, &model, messages, sampling, options, on_retry, on_token,
+                    )
+                    .map(|()| llm::ChatOutcome::default())
+                })
+            }
+            // A llama.cpp server, which answers with whatever model it has loaded.
+            LlmBackend::LlamaCpp => {
+                let url = self.settings.llamacpp_url.clone();
+                Box::new(move |messages, on_retry, on_token| {
+                    llm::llamacpp_chat(&url, messages, sampling, options, on_retry, on_token)
+                })
+            }
+            // Anthropic's Messages API.
+            LlmBackend::Anthropic => {
+                let api_key = self.settings.anthropic_api_key.clone();
+                let model = self.conversation_model(&self.settings.anthropic_model);
+                Box::new(move |messages, on_retry, on_token| {
+                    llm::anthropic_chat(
+                        &api_key, &model, messages, sampling, options, on_retry, on_token,
+                    )
+                })
+            }
+        }
+    }
+
+    /// The HTTP request `backend_request` makes for `messages`, or `None` for the stub
+    /// backend, which makes none.
+    fn http_request(&self, messages: &[Message]) -> Option<llm::HttpRequest> {
+        let sampling = self.settings.sampling();
+        let settings = &self.settings;
+        match settings.backend {
+            LlmBackend::Stub => None,
+            LlmBackend::OpenAi => Some(llm::openai_request(
+                settings.openai_base_url.trim(),
+                &settings.api_key,
+                &self.conversation_model(&settings.model),
+                messages,
+                sampling,
+            )),
+            LlmBackend::Ollama => Some(llm::ollama_request(
+                &settings.ollama_url,
+                &self.conversation_model(&settings.ollama_model),
+                messages,
+                sampling,
+            )),
+            LlmBackend::LlamaCpp => Some(llm::llamacpp_request(
+                &settings.llamacpp_url,
+                messages,
+                sampling,
+            )),
+            LlmBackend::Anthropic => Some(llm::anthropic_request(
+                &settings.anthropic_api_key,
+                &self.conversation_model(&settings.anthropic_model),
+                messages,
+                sampling,
+            )),
+        }
+    }
+
+    /// Model picker for the open conversation. Left empty, the backend's configured
+    /// model is used.
+    fn draw_conversation_model(&mut self, ui: &mut Ui) {
+        let Some(configured) = self.settings.backend_model().map(str::to_string) else {
+            return;
+        };
+        let mut suggestions = vec![
+            self.settings.model.clone(),
+            self.settings.ollama_model.clone(),
+            self.settings.anthropic_model.clone(),
+        ];
+        suggestions.retain(|m| !m.trim().is_empty());
+        suggestions.sort();
+        suggestions.dedup();
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label("Model:");
+            let mut model = self.conversation.model.clone().unwrap_or_default();
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut model)
+                    .desired_width(200.0)
+                    .hint_text(format!("{} (default)", configured)),
+            );
+            if response.changed() {
+                self.conversation.model = (!model.is_empty()).then_some(model);
+            }
+            changed |= response.lost_focus();
+            ui.menu_button("▾", |ui| {
+                if ui.button(format!("Default ({})", configured)).clicked() {
+                    self.conversation.model = None;
+                    changed = true;
+                    ui.close_menu();
+                }
+                for suggestion in &suggestions {
+                    if ui.button(suggestion).clicked() {
+                        self.conversation.model = Some(suggestion.clone());
+                        changed = true;
+                        ui.close_menu();
+                    }
+                }
+            });
+        });
+        if changed {
+            if let Err(e) = self.save_conversation_model() {
+                self.report_error(e);
+            }
+        }
+    }
+
+    /// The conversation's summary, if it has one, and a button to have the backend
+    /// write it (again).
+    fn draw_conversation_summary(&mut self, ui: &mut Ui) {
+        let summarizing = self.summary_jobs.iter().any(|job| {
+            job.conversation_id == self.conversation.id && job.kind == SummaryKind::Summary
+        });
+        let mut summarize = false;
+        ui.horizontal(|ui| {
+            if summarizing {
+                ui.spinner();
+                ui.weak("Summarizing…");
+            } else {
+                let label = if self.conversation.summary.is_some() {
+                    "Summarize again"
+                } else {
+                    "Summarize"
+                };
+                summarize = ui
+                    .add_enabled(self.stream_rx.is_none(), egui::Button::new(label).small())
+                    .on_hover_text("Have the backend summarize this conversation")
+                    .clicked();
+            }
+        });
+        if let Some(summary) = &self.conversation.summary {
+            egui::CollapsingHeader::new("Summary")
+                .id_source((self.conversation.id, "summary"))
+                .show(ui, |ui| {
+                    ui.add(egui::Label::new(summary).wrap(true));
+                });
+        }
+        if summarize {
+            self.request_summary(SummaryKind::Summary);
+        }
+    }
+
+    /// Which of the indexed folders the open conversation retrieves from, with a menu
+    /// to narrow it down.
+    fn draw_conversation_scope(&mut self, ui: &mut Ui) {
+        // Folders dropped from the settings stay listed while the scope refers to them.
+        let mut folders: Vec<String> = self
+            .settings
+            .root_paths
+            .iter()
+            .filter(|p| !p.trim().is_empty())
+            .cloned()
+            .collect();
+        for path in &self.conversation.scope {
+            if !folders.contains(path) {
+                folders.push(path.clone());
+            }
+        }
+        let summary = match self.conversation.scope.as_slice() {
+            [] => "All indexed folders".to_string(),
+            [only] => only.clone(),
+            several => format!("{} folders", several.len()),
+        };
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label("Scope:");
+            ui.menu_button(summary, |ui| {
+                if ui
+                    .radio(self.conversation.scope.is_empty(), "All indexed folders")
+                    .clicked()
+                {
+                    self.conversation.scope.clear();
+                    changed = true;
+                }
+                ui.separator();
+                for folder in &folders {
+                    let mut included = self.conversation.scope.contains(folder);
+                    if ui.checkbox(&mut included, folder).changed() {
+                        if included {
+                            self.conversation.scope.push(folder.clone());
+                        } else {
+                            self.conversation.scope.retain(|p| p != folder);
+                        }
+                        changed = true;
+                    }
+                }
+            })
+            .response
+            .on_hover_text("Only passages from these folders are retrieved for this conversation");
+        });
+        if changed {
+            if let Err(e) = self.save_conversation_scope() {
+                self.report_error(e);
+            }
+        }
+    }
+
+    /// Tags of the open conversation, each removable, and a field to add one.
+    fn draw_conversation_tags(&mut self, ui: &mut Ui) {
+        let mut removed = None;
+        let mut added = None;
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Tags:");
+            for (i, tag) in self.conversation.tags.iter().enumerate() {
+                if ui
+                    .small_button(format!("{} ×", tag))
+                    .on_hover_text("Remove this tag")
+                    .clicked()
+                {
+                    removed = Some(i);
+                }
+            }
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut self.new_tag)
+                    .desired_width(100.0)
+                    .hint_text("Add tag"),
+            );
+            if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                let tag = self.new_tag.trim().to_string();
+                self.new_tag.clear();
+                if !tag.is_empty() && !self.conversation.tags.contains(&tag) {
+                    added = Some(tag);
+                }
+            }
+        });
+        if removed.is_none() && added.is_none() {
+            return;
+        }
+        if let Some(i) = removed {
+            self.conversation.tags.remove(i);
+        }
+        if let Some(tag) = added {
+            self.conversation.tags.push(tag);
+        }
+        if let Err(e) = self.save_conversation_tags() {
+            self.report_error(e);
+        }
+    }
+
+    /// Every tag in use, to narrow the conversation list down to one of them.
+    fn draw_tag_filter(&mut self, ui: &mut Ui) {
+        let mut tags: Vec<&String> = self
+            .conversations
+            .iter()
+            .flat_map(|(_, _, _, tags)| tags)
+            .collect();
+        if tags.is_empty() {
+            self.tag_filter = None;
+            return;
+        }
+        tags.sort();
+        tags.dedup();
+        let mut filter = self.tag_filter.clone();
+        ui.horizontal_wrapped(|ui| {
+            ui.selectable_value(&mut filter, None, "All");
+            for tag in tags {
+                ui.selectable_value(&mut filter, Some(tag.clone()), tag);
+            }
+        });
+        self.tag_filter = filter;
+    }
+
+    /// Status line with the size of the current conversation, flagged once it no
+    /// longer fits the configured context window.
+    fn draw_conversation_size(&self, ui: &mut Ui) {
+        let chars: usize = self
+            .conversation
+            .messages
+            .iter()
+            .map(|m| m.content.chars().count())
+            .sum();
+        let tokens: usize = self
+            .conversation
+            .messages
+            .iter()
+            .map(|m| llm::estimate_tokens(&m.content))
+            .sum();
+        let limit = self.settings.context_limit_tokens.max(1) as usize;
+        let mut text = format!("{} characters, ~{} / {} tokens", chars, tokens, limit);
+        let billed: Vec<_> = self
+            .conversation
+            .messages
+            .iter()
+            .filter_map(|m| m.usage)
+            .collect();
+        if !billed.is_empty() {
+            let cost: f64 = billed.iter().map(|u| self.settings.usage_cost(*u)).sum();
+            text.push_str(&format!(", spent ~{}", format_cost(cost)));
+        }
+        if tokens > limit {
+            ui.colored_label(
+                egui::Color32::RED,
+                format!(
+                    "{} - over the context limit, older messages may be dropped",
+                    text
+                ),
+            );
+        } else {
+            ui.weak(text);
+        }
+    }
+
+    fn draw_conversation_ui(&mut self, ui: &mut Ui) {
+        // Editing the transcript mid-reply would shift the message being streamed into.
+        let idle = self.stream_rx.is_none();
+        let messages = &self.conversation.messages;
+        let scroll_to = self.scroll_to_message.take();
+        // Messages the next question would be sent without.
+        let unsent = outside_history_window(messages, self.settings.history_window, 1);
+        let mut edited = None;
+        let mut edit_cancelled = false;
+        let mut deleted = None;
+        let mut forked = None;
+        let mut star_toggled = None;
+        let mut retry_queued = false;
+        let mut retry_failed = false;
+        ScrollArea::vertical()
+            // .auto_shrink([false; 2])
+            // Follow new tokens while scrolled to the end; scrolling up detaches.
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for (i, msg) in messages.iter().enumerate() {
+                    if i == unsent.end && !unsent.is_empty() {
+                        ui.vertical_centered(|ui| {
+                            ui.weak(format!(
+                                "Only the messages below are sent with the next question \
+                                 (last {})",
+                                self.settings.history_window
+                            ));
+                        });
+                    }
+                    let frame = role_frame(&msg.role, ui.style());
+                    let group = frame.show(ui, |ui| {
+                        // The message's reply, deleted along with it as a pair.
+                        let reply = (msg.role == "user")
+                            .then(|| messages.get(i + 1))
+                            .flatten()
+                            .filter(|next| next.role == "assistant")
+                            .map(|_| i + 1);
+                        ui.horizontal(|ui| {
+                            let (star, hint) = if msg.starred {
+                                ("★", "Unstar")
+                            } else {
+                                ("☆", "Star, to find this message again under Starred")
+                            };
+                            if ui
+                                .add(egui::Button::new(star).small().frame(false))
+                                .on_hover_text(hint)
+                                .clicked()
+                            {
+                                star_toggled = Some(i);
+                            }
+                            ui.label(egui::RichText::new(format!("{}:", msg.role)).strong());
+                            if let Some(at) = msg.created_at {
+                                ui.label(egui::RichText::new(format_timestamp(at)).small().weak());
+                            }
+                            if unsent.contains(&i) {
+                                ui.label(egui::RichText::new("not in context").small().weak())
+                                    .on_hover_text(
+                                        "Outside the history window set in the settings, so \
+                                         not sent to the model",
+                                    );
+                            }
+                            if let Some(usage) = msg.usage {
+                                ui.label(
+                                    egui::RichText::new(format!(
+                                        "{} + {} tokens, ~{}",
+                                        usage.prompt_tokens,
+                                        usage.completion_tokens,
+                                        format_cost(self.settings.usage_cost(usage))
+                                    ))
+                                    .small()
+                                    .weak(),
+                                )
+                                .on_hover_text(
+                                    "Prompt + completion tokens reported by the backend",
+                                );
+                            }
+                            if ui.small_button("Copy").clicked() {
+                                ui.output_mut(|o| o.copied_text = msg.content.clone());
+                            }
+                            let selecting = self.selecting_message == Some(i);
+                            if ui
+                                .selectable_label(selecting, "Select")
+                                .on_hover_text("Show the raw text so part of it can be copied")
+                                .clicked()
+                            {
+                                self.selecting_message = (!selecting).then_some(i);
+                            }
+                            let code = markdown::extract_code(&msg.content);
+                            if !code.is_empty() && ui.small_button("Copy code").clicked() {
+                                ui.output_mut(|o| o.copied_text = code);
+                            }
+                            ui.add_enabled_ui(idle, |ui| {
+                                if ui.small_button("Edit").clicked() {
+                                    self.editing_message = Some((i, msg.content.clone()));
+                                }
+                                if ui.small_button("Delete").clicked() {
+                                    deleted = Some((i, None));
+                                }
+                                if ui
+                                    .small_button("Fork from here")
+                                    .on_hover_text(
+                                        "Continue in a new conversation holding the messages up \
+                                         to this one",
+                                    )
+                                    .clicked()
+                                {
+                                    forked = Some(i);
+                                }
+                                if reply.is_some()
+                                    && ui
+                                        .small_button("Delete with reply")
+                                        .on_hover_text("Also delete the assistant's answer")
+                                        .clicked()
+                                {
+                                    deleted = Some((i, reply));
+                                }
+                            });
+                        });
+                        if let Some((_, content)) = self
+                            .editing_message
+                            .as_mut()
+                            .filter(|(editing, _)| *editing == i)
+                        {
+                            ui.add(egui::TextEdit::multiline(content).desired_width(f32::INFINITY));
+                            ui.horizontal(|ui| {
+                                if ui.button("Save").clicked() {
+                                    edited = Some((i, content.clone()));
+                                }
+                                if ui.button("Cancel").clicked() {
+                                    edit_cancelled = true;
+                                }
+                            });
+                        } else if self.selecting_message == Some(i) {
+                            ui.add(
+                                egui::TextEdit::multiline(&mut msg.content.as_str())
+                                    .desired_width(f32::INFINITY),
+                            );
+                        } else if msg.role == "system" {
+                            ui.add(egui::Label::new(&msg.content).wrap(true));
+                        } else {
+                            markdown::show(
+                                ui,
+                                &mut self.markdown_cache,
+                                (self.conversation.id, i),
+                                &msg.content,
+                                msg.has_images,
+                            );
+                        }
+                        for call in &msg.tool_calls {
+                            draw_tool_call(ui, call);
+                        }
+                        if !msg.attachments.is_empty() {
+                            ui.horizontal_wrapped(|ui| {
+                                for attachment in &msg.attachments {
+                                    ui.label(
+                                        egui::RichText::new(format!("📎 {}", attachment.name))
+                                            .small()
+                                            .weak(),
+                                    );
+                                }
+                            });
+                        }
+                        if msg.interrupted {
+                            ui.label(egui::RichText::new("(interrupted)").italics().weak());
+                        }
+                        if let Some(error) = msg.failed.as_ref().filter(|_| idle) {
+                            ui.horizontal(|ui| {
+                                let status = if msg.role == "user" {
+                                    "⚠ No reply"
+                                } else {
+                                    "⚠ Reply cut off"
+                                };
+                                ui.colored_label(ui.visuals().warn_fg_color, status)
+                                    .on_hover_text(error);
+                                if i + 1 == messages.len()
+                                    && ui
+                                        .small_button("Retry")
+                                        .on_hover_text("Ask the backend again")
+                                        .clicked()
+                                {
+                                    retry_failed = true;
+                                }
+                            });
+                            ui.label(egui::RichText::new(error).small().weak());
+                        }
+                        if msg.queued {
+                            ui.horizontal(|ui| {
+                                let (status, error) = match &self.outbox_retry {
+                                    Some(retry) if retry.attempts > 0 => {
+                                        let wait = retry
+                                            .next_attempt
+                                            .saturating_duration_since(Instant::now());
+                                        (
+                                            format!(
+                                                "(queued, not sent yet - retrying in {}s)",
+                                                wait.as_secs()
+                                            ),
+                                            retry.error.as_str(),
+                                        )
+                                    }
+                                    _ => ("(queued, not sent yet)".to_string(), ""),
+                                };
+                                let label = ui.label(egui::RichText::new(status).italics().weak());
+                                if !error.is_empty() {
+                                    label.on_hover_text(error);
+                                }
+                                if ui
+                                    .add_enabled(idle, egui::Button::new("Retry now").small())
+                                    .clicked()
+                                {
+                                    retry_queued = true;
+                                }
+                            });
+                        }
+                        if msg.no_relevant_context {
+                            ui.label(
+                                egui::RichText::new("No relevant context found")
+                                    .small()
+                                    .weak(),
+                            )
+                            .on_hover_text(
+                                "No indexed chunk reached the minimum similarity, so the \
+                                 question was sent without context.",
+                            );
+                        }
+                        if !msg.sources.is_empty() {
+                            egui::CollapsingHeader::new(format!("Sources ({})", msg.sources.len()))
+                                .id_source((self.conversation.id, i, "sources"))
+                                .show(ui, |ui| {
+                                    for (j, source) in msg.sources.iter().enumerate() {
+                                        Self::draw_source(
+                                            ui,
+                                            &self.conversations,
+                                            source,
+                                            (self.conversation.id, i, j),
+                                        );
+                                    }
+                                });
+                        }
+                    });
+                    if scroll_to == Some(i) {
+                        group.response.scroll_to_me(Some(egui::Align::Center));
+                    }
+                    ui.separator();
+                }
+                if !idle {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.weak("Assistant is typing…");
+                    });
+                }
+            });
+        if edit_cancelled {
+            self.editing_message = None;
+        }
+        if retry_queued {
+            self.deliver_queued_message();
+        }
+        if retry_failed {
+            self.retry_failed_turn();
+            if let Err(e) = self.save_conversation() {
+                self.report_error(e);
+            }
+        }
+        if let Some((i, content)) = edited {
+            self.editing_message = None;
+            let message = &mut self.conversation.messages[i];
+            message.has_images = message.role == "assistant" && markdown::contains_images(&content);
+            message.content = content;
+            if let Err(e) = self.save_conversation() {
+                self.report_error(e);
+            }
+        }
+        if let Some(i) = star_toggled {
+            let message = &mut self.conversation.messages[i];
+            message.starred = !message.starred;
+            if let Err(e) = self.save_conversation() {
+                self.report_error(e);
+            }
+        }
+        if let Some(i) = forked {
+            if let Err(e) = self.fork_conversation(i) {
+                self.report_error(e);
+            }
+        }
+        if let Some((i, reply)) = deleted {
+            self.editing_message = None;
+            self.selecting_message = None;
+            if let Some(reply) = reply {
+                self.conversation.messages.remove(reply);
+            }
+            self.conversation.messages.remove(i);
+            if let Err(e) = self.save_conversation() {
+                self.report_error(e);
+            }
+        }
+
+        self.draw_pending_paste(ui);
+        if !self.attachments.is_empty() {
+            self.draw_pending_attachments(ui);
+        }
+
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("input_role")
+                .width(90.0)
+                .selected_text(self.input_role)
+                .show_ui(ui, |ui| {
+                    for role in INPUT_ROLES {
+                        ui.selectable_value(&mut self.input_role, role, role);
+                    }
+                });
+            // Plain Enter sends; take it before the text edit turns it into a newline.
+            // Shift+Enter is left alone and inserts one.
+            let input_id = ui.make_persistent_id("message_input");
+            let focused = ui.memory(|m| m.has_focus(input_id));
+            let enter_pressed =
+                focused && ui.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::Enter));
+            if focused {
+                if let Some(paste) = ui.input_mut(|i| intercept_paste(&mut i.events)) {
+                    self.pending_paste = Some(paste);
+                }
+            }
+            let response = ui.add(
+                egui::TextEdit::multiline(&mut self.current_input)
+                    .id(input_id)
+                    .desired_rows(2)
+                    .hint_text("Enter to send, Shift+Enter for a new line"),
+            );
+            if response.changed() {
+                self.draft_changed_at = Some(Instant::now());
+            }
+
+            if self.stream_rx.is_some() {
+                if ui.button("Stop").clicked() {
+                    self.cancel_request();
+                }
+                if let Some(attempt) = self.retry_attempt {
+                    ui.weak(format!(
+                        "Retrying ({}/{})…",
+                        attempt,
+                        self.settings.max_retries.max(0)
+                    ));
+                }
+            } else {
+                let can_regenerate = self
+                    .conversation
+                    .messages
+                    .last()
+                    .is_some_and(|m| m.role == "assistant");
+                if ui
+                    .add_enabled(can_regenerate, egui::Button::new("Regenerate"))
+                    .clicked()
+                {
+                    self.regenerate_last_response();
+                    if let Err(e) = self.save_conversation() {
+                        self.report_error(e);
+                    }
+                }
+            }
+            if self.stream_rx.is_none()
+                && ui
+                    .button("Preview prompt")
+                    .on_hover_text("Show what Send would send, without sending it")
+                    .clicked()
+            {
+                self.prompt_preview = Some(self.preview_prompt());
+            }
+            if let Some(request) = &self.last_request {
+                if ui
+                    .button("Copy as cURL")
+                    .on_hover_text(
+                        "Copy the latest request to the backend as a cURL command; the API \
+                         key is left out as $API_KEY",
+                    )
+                    .clicked()
+                {
+                    ui.output_mut(|o| o.copied_text = request.to_curl());
+                }
+            }
+            let can_send = self.stream_rx.is_none() && self.large_prompt.is_none();
+            let send_clicked = ui
+                .add_enabled(can_send, egui::Button::new("Send"))
+                .clicked();
+            let send_by_enter = enter_pressed && can_send && !self.current_input.trim().is_empty();
+            if send_clicked || send_by_enter {
+                self.submit_input();
+            }
+        });
+    }
+
+    fn draw_document_search(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("🔍");
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut self.document_query)
+                    .hint_text("Search indexed files"),
+            );
+            if response.changed() {
+                self.document_results = self.search_documents(&self.document_query);
+            }
+        });
+        if self.document_results.is_empty() {
+            return;
+        }
+        ScrollArea::vertical()
+            .id_source("document_results")
+            .max_height(200.0)
+            .show(ui, |ui| {
+                for (path, snippet) in &self.document_results {
+                    let name = match indexer::conversation_id(path) {
+                        Some(id) => Self::conversation_name(&self.conversations, id),
+                        None => std::path::Path::new(path)
+                            .file_name()
+                            .map(|n| n.to_string_lossy().into_owned())
+                            .unwrap_or_else(|| path.clone()),
+                    };
+                    ui.label(egui::RichText::new(name).strong())
+                        .on_hover_text(path);
+                    ui.add(egui::Label::new(egui::RichText::new(snippet).small()).wrap(true));
+                    ui.add_space(4.0);
+                }
+            });
+    }
+
+    fn draw_conversation_search(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("🔍");
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut self.conversation_query)
+                    .hint_text("Search conversations"),
+            );
+            if response.changed() {
+                self.conversation_results = self.search_conversations(&self.conversation_query);
+            }
+        });
+        if self.conversation_results.is_empty() {
+            return;
+        }
+        let idle = self.stream_rx.is_none();
+        let mut selected = None;
+        ScrollArea::vertical()
+            .id_source("conversation_results")
+            .max_height(200.0)
+            .show(ui, |ui| {
+                ui.add_enabled_ui(idle, |ui| {
+                    for (id, snippet) in &self.conversation_results {
+                        let title = self
+                            .conversations
+                            .iter()
+                            .find(|(c, _, _, _)| c == id)
+                            .map_or("", |(_, title, _, _)| title.as_str());
+                        if ui
+                            .selectable_label(false, egui::RichText::new(title).strong())
+                            .clicked()
+                            | ui.add(
+                                egui::Label::new(egui::RichText::new(snippet).small())
+                                    .wrap(true)
+                                    .sense(egui::Sense::click()),
+                            )
+                            .clicked()
+                        {
+                            selected = Some(*id);
+                        }
+                        ui.add_space(4.0);
+                    }
+                });
+            });
+        if let Some(id) = selected {
+            match self.switch_conversation(id) {
+                Ok(()) => {
+                    let query = self.conversation_query.trim().to_lowercase();
+                    self.scroll_to_message = self
+                        .conversation
+                        .messages
+                        .iter()
+                        .position(|m| m.content.to_lowercase().contains(&query));
+                }
+                Err(e) => self.report_error(e),
+            }
+        }
+    }
+
+    /// Starred messages of all conversations; clicking one opens its conversation
+    /// scrolled to it.
+    fn draw_starred(&mut self, ui: &mut Ui) {
+        // The open conversation's stars are read live, the others' from the last refresh.
+        let open: Vec<_> = self
+            .conversation
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.starred)
+            .map(|(i, m)| (self.conversation.id, i, message_preview(&m.content)))
+            .collect();
+        let idle = self.stream_rx.is_none();
+        let mut selected = None;
+        egui::CollapsingHeader::new(format!("★ Starred ({})", open.len() + self.starred.len()))
+            .id_source("starred")
+            .show(ui, |ui| {
+                if open.is_empty() && self.starred.is_empty() {
+                    ui.weak("Star a message to keep it here.");
+                    return;
+                }
+                ScrollArea::vertical()
+                    .id_source("starred_messages")
+                    .max_height(200.0)
+                    .show(ui, |ui| {
+                        ui.add_enabled_ui(idle, |ui| {
+                            for (id, index, preview) in open.iter().chain(&self.starred) {
+                                let title = self
+                                    .conversations
+                                    .iter()
+                                    .find(|(c, _, _, _)| c == id)
+                                    .map_or("", |(_, title, _, _)| title.as_str());
+                                if ui
+                                    .add(
+                                        egui::Label::new(egui::RichText::new(preview).small())
+                                            .wrap(true)
+                                            .sense(egui::Sense::click()),
+                                    )
+                                    .on_hover_text(title)
+                                    .clicked()
+                                {
+                                    selected = Some((*id, *index));
+                                }
+                                ui.add_space(4.0);
+                            }
+                        });
+                    });
+            });
+        if let Some((id, index)) = selected {
+            if id != self.conversation.id {
+                if let Err(e) = self.switch_conversation(id) {
+                    self.report_error(e);
+                    return;
+                }
+            }
+            self.scroll_to_message = Some(index);
+        }
+    }
+
+    /// (id, title, last activity) of the conversations in the side panel, in its
+    /// order: those with the selected tag, most recently active first.
+    fn listed_conversations(&self) -> Vec<(i64, String, Option<i64>)> {
+        // The open conversation's activity may not have reached the database yet.
+        let mut conversations: Vec<_> = self
+            .conversations
+            .iter()
+            .filter(|(_, _, _, tags)| {
+                self.tag_filter
+                    .as_ref()
+                    .is_none_or(|filter| tags.contains(filter))
+            })
+            .map(|(id, title, activity, _)| {
+                let activity = if *id == self.conversation.id {
+                    last_activity(&self.conversation.messages).or(*activity)
+                } else {
+                    *activity
+                };
+                (*id, title.clone(), activity)
+            })
+            .collect();
+        conversations
+            .sort_by_key(|(id, _, activity)| std::cmp::Reverse((activity.unwrap_or(0), *id)));
+        conversations
+    }
+
+    /// One retrieved chunk under a reply: where it came from, its similarity to the
+    /// question and, folded away, its text exactly as the model got it. `id` keeps the
+    /// fold's state apart from the other chunks'.
+    fn draw_source(
+        ui: &mut Ui,
+        conversations: &[(i64, String, Option<i64>, Vec<String>)],
+        source: &Source,
+        id: (i64, usize, usize),
+    ) {
+        ui.horizontal(|ui| {
+            if let Some(score) = source.score {
+                ui.label(
+                    egui::RichText::new(format!("{:.3}", score))
+                        .small()
+                        .monospace(),
+                )
+                .on_hover_text("Cosine similarity to the question");
+            }
+            ui.label(egui::RichText::new(Self::source_label(conversations, source)).small())
+                .on_hover_text(&source.path);
+        });
+        if let Some(content) = &source.content {
+            ui.indent(id, |ui| {
+                egui::CollapsingHeader::new(egui::RichText::new("Chunk text").small())
+                    .id_source(id)
+                    .show(ui, |ui| {
+                        ui.add(
+                            egui::Label::new(egui::RichText::new(content).small().monospace())
+                                .wrap(true),
+                        );
+                    });
+            });
+        }
+    }
+
+    /// Ctrl+Tab and Ctrl+Shift+Tab open the next and previous conversation in the side
+    /// panel, wrapping around, and Ctrl+N starts a new one. They are left alone while
+    /// a text field has focus, so typing is never interrupted, and while a reply is
+    /// streaming in, like the side panel.
+    fn handle_conversation_shortcuts(&mut self, ctx: &Context) {
+        let typing = ctx
+            .memory(|m| m.focus())
+            .is_some_and(|id| egui::TextEdit::load_state(ctx, id).is_some());
+        if typing || self.stream_rx.is_some() {
+            return;
+        }
+        let (next, previous, new) = ctx.input_mut(|i| {
+            (
+                i.consume_key(egui::Modifiers::CTRL, egui::Key::Tab),
+                i.consume_key(
+                    egui::Modifiers::CTRL | egui::Modifiers::SHIFT,
+                    egui::Key::Tab,
+                ),
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::N),
+            )
+        });
+        let result = if new {
+            self.new_conversation()
+        } else if next || previous {
+            let ids: Vec<i64> = self
+                .listed_conversations()
+                .into_iter()
+                .map(|(id, _, _)| id)
+                .collect();
+            let target = match ids.iter().position(|&id| id == self.conversation.id) {
+                Some(i) if next => ids.get((i + 1) % ids.len()),
+                Some(i) => ids.get((i + ids.len() - 1) % ids.len()),
+                // The open conversation is filtered out; start from either end.
+                None if next => ids.first(),
+                None => ids.last(),
+            };
+            match target {
+                Some(&id) if id != self.conversation.id => self.switch_conversation(id),
+                _ => Ok(()),
+            }
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            self.report_error(e);
+        }
+    }
+
+    fn draw_conversation_list(&mut self, ui: &mut Ui) {
+        // Switching threads mid-reply would append the tokens to the wrong conversation.
+        let idle = self.stream_rx.is_none();
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(idle, egui::Button::new("New Conversation"))
+                .on_hover_text("Ctrl+N; Ctrl+Tab and Ctrl+Shift+Tab go through the list")
+                .clicked()
+            {
+                if let Err(e) = self.new_conversation() {
+                    self.report_error(e);
+                }
+            }
+            let selecting = self.selected_conversations.is_some();
+            if ui
+                .selectable_label(selecting, "Select")
+                .on_hover_text("Tick conversations to delete several at once")
+                .clicked()
+            {
+                self.selected_conversations = (!selecting).then(BTreeSet::new);
+            }
+        });
+        self.draw_tag_filter(ui);
+        ui.separator();
+
+        let conversations = self.listed_conversations();
+        let today = chrono::Local::now().date_naive();
+
+        if let Some(chosen) = &mut self.selected_conversations {
+            ui.add_enabled_ui(idle, |ui| {
+                ui.horizontal(|ui| {
+                    let mut all = !conversations.is_empty()
+                        && conversations.iter().all(|(id, _, _)| chosen.contains(id));
+                    if ui
+                        .checkbox(&mut all, "All")
+                        .on_hover_text("Select every conversation listed")
+                        .changed()
+                    {
+                        for (id, _, _) in &conversations {
+                            if all {
+                                chosen.insert(*id);
+                            } else {
+                                chosen.remove(id);
+                            }
+                        }
+                    }
+                    if ui
+                        .add_enabled(
+                            !chosen.is_empty(),
+                            egui::Button::new(format!("Delete selected ({})", chosen.len())),
+                        )
+                        .clicked()
+                    {
+                        self.confirm_bulk_delete = true;
+                    }
+                });
+            });
+            ui.separator();
+        }
+
+        let mut selected = None;
+        let mut deleted = None;
+        let mut renamed = None;
+        ScrollArea::vertical().show(ui, |ui| {
+            ui.add_enabled_ui(idle, |ui| {
+                let mut current_group = None;
+                for (id, title, activity) in &conversations {
+                    let group = activity_group(*activity, today);
+                    if current_group != Some(group) {
+                        current_group = Some(group);
+                        ui.add_space(4.0);
+                        ui.small(group);
+                    }
+                    if let Some((renaming_id, new_title)) = &mut self.renaming {
+                        if renaming_id == id {
+                            let response = ui.text_edit_singleline(new_title);
+                            if response.lost_focus() {
+                                if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                                    self.renaming = None;
+                                } else {
+                                    renamed = Some((*id, new_title.trim().to_string()));
+                                }
+                            } else {
+                                response.request_focus();
+                            }
+                            continue;
+                        }
+                    }
+
+                    let response = match &mut self.selected_conversations {
+                        Some(chosen) => {
+                            ui.horizontal(|ui| {
+                                let mut ticked = chosen.contains(id);
+                                if ui.checkbox(&mut ticked, "").changed() {
+                                    if ticked {
+                                        chosen.insert(*id);
+                                    } else {
+                                        chosen.remove(id);
+                                    }
+                                }
+                                ui.selectable_label(*id == self.conversation.id, title.as_str())
+                            })
+                            .inner
+                        }
+                        None => ui.selectable_label(*id == self.conversation.id, title.as_str()),
+                    };
+                    if response.clicked() {
+                        selected = Some(*id);
+                    }
+                    response.context_menu(|ui| {
+                        if ui.button("Rename").clicked() {
+                            self.renaming = Some((*id, title.to_string()));
+                            ui.close_menu();
+                        }
+                        if ui.button("Delete").clicked() {
+                            deleted = Some(*id);
+                            ui.close_menu();
+                        }
+                    });
+                }
+            });
+        });
+        if let Some(id) = selected {
+            if let Err(e) = self.switch_conversation(id) {
+                self.report_error(e);
+            }
+        }
+        if let Some((id, title)) = renamed {
+            self.renaming = None;
+            if !title.is_empty() {
+                if let Err(e) = self.save_conversation_title(id, &title, true) {
+                    self.report_error(e);
+                }
+            }
+        }
+        if let Some(id) = deleted {
+            if let Err(e) = self.delete_conversation(id) {
+                self.report_error(e);
+            }
+        }
+    }
+
+    fn draw_settings_ui(&mut self, ui: &mut Ui) {
+        ui.heading("Application Settings");
+        ui.separator();
+
+        ui.label("Indexed Root Paths:");
+        let mut remove_indices = Vec::new();
+        for (i, path) in self.settings.root_paths.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(path);
+                if !std::path::Path::new(path.as_str()).is_dir() {
+                    ui.colored_label(egui::Color32::RED, "⚠")
+                        .on_hover_text("This path does not exist or is not a directory");
+                }
+                if ui.button("Remove").clicked() {
+                    remove_indices.push(i);
+                }
+            });
+        }
+
+        for i in remove_indices.iter().rev() {
+            self.settings.root_paths.remove(*i);
+        }
+
+        ui.horizontal(|ui| {
+            if ui.button("Add Another Path").clicked() {
+                self.settings.root_paths.push("".to_string());
+            }
+
+            if ui.button("Browse…").clicked() {
+                if let Some(dir) = rfd::FileDialog::new().pick_folder() {
+                    self.settings
+                        .root_paths
+                        .push(dir.to_string_lossy().into_owned());
+                }
+            }
+
+            let indexing = self.index_rx.is_some();
+            if ui
+                .add_enabled(!indexing, egui::Button::new("Reindex Now"))
+                .on_hover_text("Index new and modified files")
+                .clicked()
+            {
+                self.index_files(false, true);
+            }
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS conversation (
-                id INTEGER PRIMARY KEY,
-                messages TEXT NOT NULL
-            )",
-            [],
-        )
-        .expect("Failed to create conversation table");
-    }
+            if ui
+                .add_enabled(!indexing, egui::Button::new("Full Reindex"))
+                .on_hover_text("Re-read every file, even unchanged ones")
+                .clicked()
+            {
+                self.index_files(true, true);
+            }
 
-    fn load_or_create_default_conversation(conn: &Connection) -> Conversation {
-        let mut stmt = conn
-            .prepare("SELECT id, messages FROM conversation LIMIT 1")
-            .expect("Failed to prepare conversation select");
-        let mut rows = stmt.query([]).expect("Failed to query conversation table");
+            if ui
+                .add_enabled(!indexing, egui::Button::new("Clear Index…"))
+                .clicked()
+            {
+                self.confirm_clear_index = true;
+            }
 
-        if let Some(row) = rows.next().expect("Failed to iterate conversation rows") {
-            let id: i64 = row.get(0).expect("Failed to get conversation id");
-            let messages_str: String = row.get(1).expect("Failed to get conversation messages");
-            let messages: Vec<Message> =
-                serde_json::from_str(&messages_str).unwrap_or_else(|_| vec![]);
+            if ui.button("Index Statistics").clicked() {
+                match self.index_stats() {
+                    Ok(stats) => self.index_stats = Some(stats),
+                    Err(e) => self.report_error(e.into()),
+                }
+            }
+        });
 
-            Conversation { id, messages }
-        } else {
-            let default = Conversation {
-                id: 1,
-                messages: vec![Message {
-                    role: "system".into(),
-                    content: "Welcome to Indexedrag!".into(),
-                }],
-            };
-            let messages_str = serde_json::to_string(&default.messages).expect("Serialize fail");
+        ui.label("Indexed file extensions (none means all):");
+        let mut remove_extension = None;
+        ui.horizontal_wrapped(|ui| {
+            for (i, ext) in self.settings.allowed_extensions.iter_mut().enumerate() {
+                ui.add(egui::TextEdit::singleline(ext).desired_width(48.0));
+                if ui.small_button("×").clicked() {
+                    remove_extension = Some(i);
+                }
+            }
+            if ui.small_button("+").clicked() {
+                self.settings.allowed_extensions.push(String::new());
+            }
+        });
+        if let Some(i) = remove_extension {
+            self.settings.allowed_extensions.remove(i);
+        }
 
-            conn.execute(
-                "INSERT INTO conversation (id, messages) VALUES (?1, ?2)",
-                params![default.id, messages_str],
-            )
-            .expect("Failed to insert default conversation");
+        ui.horizontal(|ui| {
+            ui.label("Largest indexed file (KiB):");
+            let mut kib = self.settings.max_file_size_bytes / 1024;
+            if ui
+                .add(egui::DragValue::new(&mut kib).clamp_range(1..=1024 * 1024))
+                .changed()
+            {
+                self.settings.max_file_size_bytes = kib * 1024;
+            }
+        });
+
+        ui.checkbox(
+            &mut self.settings.respect_gitignore,
+            "Skip files ignored by .gitignore/.ignore and hidden files",
+        );
+        ui.checkbox(
+            &mut self.settings.index_conversations,
+            "Index conversations too, so earlier answers can be retrieved",
+        )
+        .on_hover_text(
+            "Each conversation is indexed as a document of its own on the next index \
+             run. A conversation never retrieves passages from itself.",
+        );
 
-            default
+        ui.horizontal(|ui| {
+            ui.label("Chunk size (characters):");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.chunk_size_chars).clamp_range(100..=20_000),
+            );
+            ui.label("Overlap:");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.chunk_overlap_chars)
+                    .clamp_range(0..=10_000),
+            );
+        });
+        if self.settings.chunk_overlap_chars >= self.settings.chunk_size_chars {
+            ui.colored_label(
+                ui.visuals().error_fg_color,
+                "The overlap must be smaller than the chunk size.",
+            );
         }
-    }
 
-    fn load_or_create_default_settings(conn: &Connection) -> AppSettings {
-        let mut stmt = conn
-            .prepare("SELECT id, root_paths, index_interval_minutes FROM settings LIMIT 1")
-            .expect("Failed to prepare settings select");
-        let mut rows = stmt.query([]).expect("Failed to query settings table");
+        if let Some(status) = &self.index_status {
+            ui.label(status);
+        }
 
-        if let Some(row) = rows.next().expect("Failed to iterate settings rows") {
-            let id: i64 = row.get(0).expect("Failed to get settings id");
-            let root_paths_str: String = row.get(1).expect("Failed to get root_paths");
-            let root_paths: Vec<String> =
-                serde_json::from_str(&root_paths_str).unwrap_or_else(|_| vec![]);
-            let index_interval_minutes: i32 = row.get(2).expect("Failed to get index_interval");
+        ui.separator();
 
-            AppSettings {
-                id,
-                root_paths,
-                index_interval_minutes,
-            }
-        } else {
-            let default = AppSettings {
-                id: 1,
-                root_paths: vec!["/path/to/somewhere".to_string()],
-                index_interval_minutes: 60,
-            };
+        egui::ComboBox::from_label("Backend")
+            .selected_text(self.settings.backend.label())
+            .show_ui(ui, |ui| {
+                for backend in LlmBackend::ALL {
+                    ui.selectable_value(&mut self.settings.backend, backend, backend.label());
+                }
+            });
 
-            let root_paths_str =
-                serde_json::to_string(&default.root_paths).expect("Failed to serialize root paths");
-            conn.execute(
-                "INSERT INTO settings (id, root_paths, index_interval_minutes)
-                 VALUES (?1, ?2, ?3)",
-                params![default.id, root_paths_str, default.index_interval_minutes],
-            )
-            .expect("Failed to insert default settings");
+        ui.horizontal(|ui| {
+            ui.label("Model:");
+            ui.text_edit_singleline(&mut self.settings.model);
+        });
 
-            default
-        }
-    }
+        ui.horizontal(|ui| {
+            ui.label("OpenAI base URL:");
+            ui.text_edit_singleline(&mut self.settings.openai_base_url)
+                .on_hover_text(
+                    "Point this at any OpenAI-compatible API, e.g. Groq, Together, OpenRouter \
+                     or LM Studio",
+                );
+        });
 
-    fn save_conversation(&self) {
-        let messages_str = serde_json::to_string(&self.conversation.messages)
-            .expect("Failed to serialize messages");
-        self.conn
-            .execute(
-                "UPDATE conversation SET messages = ?1 WHERE id = ?2",
-                params![messages_str, self.conversation.id],
-            )
-            .expect("Failed to update conversation");
-    }
+        ui.horizontal(|ui| {
+            ui.label("API key:");
+            ui.add(egui::TextEdit::singleline(&mut self.settings.api_key).password(true));
+        });
 
-    fn save_settings(&self) {
-        let root_paths_str = serde_json::to_string(&self.settings.root_paths)
-            .expect("Failed to serialize root paths");
-        self.conn
-            .execute(
-                "UPDATE settings
-                 SET root_paths = ?1,
-                     index_interval_minutes = ?2
-                 WHERE id = ?3",
-                params![
-                    root_paths_str,
-                    self.settings.index_interval_minutes,
-                    self.settings.id
-                ],
-            )
-            .expect("Failed to update settings");
-    }
+        ui.horizontal(|ui| {
+            ui.label("Ollama URL:");
+            ui.text_edit_singleline(&mut self.settings.ollama_url);
+        });
 
-    /// (Stub) This would call external LLM APIs in JSON format. Currently just simulates a response.
-    // fn call_llm_api_stub(&mut self, user_input: &str) {
-    //     // In a real app, you would send the conversation history plus the new user message
-    //     // to an LLM endpoint, e.g. OpenAI, llama.cpp, etc., in JSON format.
-    //     // For now, just simulate a response:
-    //     let system_reply = format!("(Stub) LLM Response to: '{}'", user_input);
+        ui.horizontal(|ui| {
+            ui.label("Ollama model:");
+            ui.text_edit_singleline(&mut self.settings.ollama_model);
+        });
 
-    //     // Add the assistant message
-    //     self.conversation.messages.push(Message {
-    //         role: "assistant".into(),
-    //         content: system_reply,
-    //     });
-    // }
+        ui.horizontal(|ui| {
+            ui.label("llama.cpp server URL:");
+            ui.text_edit_singleline(&mut self.settings.llamacpp_url);
+        });
 
-    fn draw_conversation_ui(&mut self, ui: &mut Ui) {
-        ScrollArea::vertical()
-            // .auto_shrink([false; 2])
-            .show(ui, |ui| {
-                for msg in &self.conversation.messages {
-                    ui.group(|ui| {
+        ui.horizontal(|ui| {
+            ui.label("Anthropic model:");
+            ui.text_edit_singleline(&mut self.settings.anthropic_model);
+        });
 
-                        // egui_extras::MarkdownViewer::new("").show(ui);
-                        ui.label(format!("{}:\n {}", msg.role, msg.content));
-                    });
-                    ui.separator();
-                }
-            });
+        ui.horizontal(|ui| {
+            ui.label("Anthropic API key:");
+            ui.add(egui::TextEdit::singleline(&mut self.settings.anthropic_api_key).password(true));
+        });
 
         ui.horizontal(|ui| {
-            ui.label("Your message:");
-            ui.text_edit_singleline(&mut self.current_input);
+            ui.label("Price per million tokens ($):");
+            ui.label("prompt");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.prompt_price_per_million)
+                    .speed(0.01)
+                    .clamp_range(0.0..=1000.0),
+            );
+            ui.label("completion");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.completion_price_per_million)
+                    .speed(0.01)
+                    .clamp_range(0.0..=1000.0),
+            );
+        })
+        .response
+        .on_hover_text("Used to estimate the cost of OpenAI and Anthropic replies");
 
-            if ui.button("Send").clicked() {
-                let user_msg = Message {
-                    role: "user".to_string(),
-                    content: self.current_input.clone(),
-                };
-                self.conversation.messages.push(user_msg);
-                let input_clone = self.current_input.clone();
+        // Neither has an embeddings model; see `llm::hashed_embedding`.
+        let local_embeddings = matches!(
+            self.settings.backend,
+            LlmBackend::Stub | LlmBackend::Anthropic
+        );
+        ui.horizontal(|ui| {
+            ui.label("Embedding model:");
+            ui.add_enabled(
+                !local_embeddings,
+                egui::TextEdit::singleline(&mut self.settings.embedding_model),
+            );
+        });
+        if self.settings.backend == LlmBackend::Anthropic {
+            ui.colored_label(
+                ui.visuals().warn_fg_color,
+                "Anthropic offers no embeddings, so documents and questions are embedded \
+                 locally by hashing their words. Retrieval then only finds passages that \
+                 share words with the question.",
+            );
+        }
 
-                let result_clone = Arc::clone(&self.result);
-                thread::spawn(move || {
-                    // thread::sleep(Duration::from_secs(5));
-                    *result_clone.lock().unwrap() = Some("# Jelly
+        ui.horizontal(|ui| {
+            ui.label("Embeddings per minute:");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.embeddings_per_minute)
+                    .clamp_range(0..=100_000),
+            )
+            .on_hover_text("Indexing waits to stay under this; 0 means no limit");
+            ui.label("Price per million tokens ($):");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.embedding_price_per_million)
+                    .speed(0.001)
+                    .clamp_range(0.0..=1000.0),
+            )
+            .on_hover_text("Used to estimate the cost of indexing with OpenAI embeddings");
+        });
 
-[![MIT License](https://img.shields.io/github/license/cs-au-dk/jelly)](LICENSE)
-[![npm version](https://img.shields.io/npm/v/@cs-au-dk/jelly)](https://www.npmjs.com/package/@cs-au-dk/jelly)
+        ui.horizontal(|ui| {
+            ui.label("Retrieved chunks per question:");
+            ui.add(egui::DragValue::new(&mut self.settings.retrieval_top_k).clamp_range(0..=50));
+        });
 
-#### Copyright © 2023 Anders Møller
+        ui.horizontal(|ui| {
+            ui.label("Minimum similarity:");
+            ui.add(egui::Slider::new(
+                &mut self.settings.min_similarity,
+                0.0..=1.0,
+            ))
+            .on_hover_text("Retrieved chunks less similar to the question than this are left out");
+        });
 
-Jelly is a static analyzer for performing
+        ui.checkbox(
+            &mut self.settings.expand_queries,
+            "Rewrite questions with the model before retrieval",
+        )
+        .on_hover_text(
+            "The backend turns each question into a fuller search query first, which \
+             finds more for short follow-up questions but costs an extra request.",
+        );
 
-* *call graphs construction*,
-* *library usage pattern matching*, and
-* *vulnerability exposure analysis*
-".to_string());
-                });
+        ui.horizontal(|ui| {
+            ui.label("Temperature:");
+            ui.add(egui::Slider::new(
+                &mut self.settings.temperature,
+                llm::SamplingOptions::TEMPERATURE_RANGE,
+            ));
+        });
 
-                // self.call_llm_api_stub(&input_clone);
-            }
+        ui.horizontal(|ui| {
+            ui.label("Max tokens:");
+            ui.add(egui::DragValue::new(&mut self.settings.max_tokens).clamp_range(1..=128_000));
+        });
 
-            let mut result = self.result.lock().unwrap();
-            match &*result {
-                Some(value) => {
-                    // Add the assistant message
-                    self.conversation.messages.push(Message {
-                        role: "assistant".into(),
-                        content: value.to_string(),
-                    });
-                    *result = None;
-                    self.current_input.clear();
-                    self.save_conversation();
+        ui.horizontal(|ui| {
+            ui.label("Confirm prompts over:");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.max_prompt_tokens)
+                    .clamp_range(0..=1_000_000)
+                    .suffix(" tokens"),
+            )
+            .on_hover_text("Sending a larger request asks first; 0 never asks");
+        });
 
-                    // ui.label("Ready!")
-                }
-                None => {
-                    // ui.label("Computing...")
-                }
-            };
-            // let result_clone = Arc::clone(&self.result);
+        ui.horizontal(|ui| {
+            ui.label("Retries on network errors:");
+            ui.add(egui::DragValue::new(&mut self.settings.max_retries).clamp_range(0..=10));
         });
-    }
 
-    fn draw_settings_ui(&mut self, ui: &mut Ui) {
-        ui.heading("Application Settings");
-        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Request timeout (seconds):");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.request_timeout_seconds)
+                    .clamp_range(1..=3600),
+            );
+        });
 
-        ui.label("Indexed Root Paths:");
-        let mut remove_indices = Vec::new();
-        for (i, path) in self.settings.root_paths.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ui.label("Proxy:");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.settings.proxy_url)
+                    .hint_text("from HTTP_PROXY / HTTPS_PROXY"),
+            )
+            .on_hover_text(
+                "Proxy URL for requests to the backend, e.g. http://proxy.corp:3128. Leave \
+                 empty to use the proxy environment variables.",
+            );
+        });
+        if !self.settings.proxy_url.trim().is_empty() {
             ui.horizontal(|ui| {
-                ui.text_edit_singleline(path);
-                if ui.button("Remove").clicked() {
-                    remove_indices.push(i);
-                }
+                ui.label("Bypass proxy for:");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.settings.no_proxy)
+                        .hint_text("from NO_PROXY"),
+                )
+                .on_hover_text(
+                    "Comma-separated hosts, domains (.corp.example) and IP ranges \
+                     (10.0.0.0/8) reached directly",
+                );
             });
         }
 
-        for i in remove_indices.iter().rev() {
-            self.settings.root_paths.remove(*i);
-        }
+        ui.checkbox(
+            &mut self.settings.redact_secrets,
+            "Redact API keys and passwords in messages before sending",
+        )
+        .on_hover_text(
+            "Words that look like API keys, values assigned to names such as password \
+             or token, and private key blocks are replaced with [REDACTED] in what you \
+             type and attach, before it is stored or sent.",
+        );
 
-        if ui.button("Add Another Path").clicked() {
-            self.settings.root_paths.push("".to_string());
-        }
+        ui.horizontal(|ui| {
+            ui.label("Context limit (tokens):");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.context_limit_tokens)
+                    .clamp_range(1..=2_000_000),
+            );
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Messages sent with a question:");
+            ui.add(
+                egui::DragValue::new(&mut self.settings.history_window)
+                    .clamp_range(0..=1_000)
+                    .custom_formatter(|n, _| {
+                        if n == 0.0 {
+                            "all".to_string()
+                        } else {
+                            format!("last {}", n)
+                        }
+                    }),
+            )
+            .on_hover_text(
+                "Send only the latest messages, counting the question, plus the system \
+                 prompt; 0 sends the whole history that fits the context limit",
+            );
+        });
+
+        ui.label("System prompt:");
+        ui.add(
+            egui::TextEdit::multiline(&mut self.settings.system_prompt)
+                .hint_text("e.g. You are a terse code assistant.")
+                .desired_rows(3)
+                .desired_width(f32::INFINITY),
+        );
 
         ui.separator();
 
@@ -316,14 +5416,44 @@ Jelly is a static analyzer for performing
 
         ui.separator();
 
+        let invalid_paths = self
+            .settings
+            .root_paths
+            .iter()
+            .filter(|p| !std::path::Path::new(p.as_str()).is_dir())
+            .count();
+        if invalid_paths > 0 {
+            ui.colored_label(
+                egui::Color32::RED,
+                format!(
+                    "{} root path(s) are not existing directories and will be skipped \
+                     when indexing.",
+                    invalid_paths
+                ),
+            );
+        }
+
+        let chunking_valid = self.settings.chunk_overlap_chars < self.settings.chunk_size_chars;
         ui.horizontal(|ui| {
-            if ui.button("Save Settings").clicked() {
-                self.save_settings();
-                self.settings_open = false;
+            if ui
+                .add_enabled(chunking_valid, egui::Button::new("Save Settings"))
+                .clicked()
+            {
+                match self.save_settings() {
+                    Ok(()) => self.settings_open = false,
+                    Err(e) => self.report_error(e),
+                }
             }
 
             if ui.button("Cancel").clicked() {
-                self.settings = Self::load_or_create_default_settings(&self.conn);
+                let mut damaged = Vec::new();
+                match Self::load_or_create_default_settings(&self.conn, &mut damaged) {
+                    Ok(settings) => self.settings = settings,
+                    Err(e) => self.report_error(e),
+                }
+                for e in damaged {
+                    self.report_error(e);
+                }
                 self.settings_open = false;
             }
         });
@@ -334,24 +5464,197 @@ Jelly is a static analyzer for performing
 // Implement eframe::App
 // =====================
 impl App for IndexedragApp {
+    fn on_close_event(&mut self) -> bool {
+        if let Err(e) = self.save_window_geometry() {
+            eprintln!("Failed to save the window geometry: {}", e);
+        }
+        true
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        // Settings edits still open in the settings window were never confirmed.
+        let saved = self.save_all().and_then(|()| {
+            if self.settings_open {
+                Ok(())
+            } else {
+                self.save_settings()
+            }
+        });
+        if let Err(e) = saved {
+            eprintln!("Failed to save before exiting: {}", e);
+        }
+        self.flush_db_writes();
+    }
+
     fn update(&mut self, ctx: &Context, frame: &mut Frame) {
         ctx.set_visuals(egui::Visuals::dark());
+        self.apply_ui_scale(ctx, frame);
+        self.track_window_geometry(frame);
+        self.drain_stream();
+        if let Some(wait) = self.poll_outbox() {
+            // Wake up for the next attempt, and meanwhile keep the countdown current.
+            ctx.request_repaint_after(wait.min(Duration::from_secs(1)));
+        }
+        self.autosave_draft(ctx);
+        self.autosave(ctx);
+        for file in ctx.input(|i| i.raw.dropped_files.clone()) {
+            self.attach_dropped_file(&file);
+        }
+        self.drain_index_events(ctx);
+        self.drain_summary_jobs(ctx);
+        self.drain_db_errors();
+        if self.stream_rx.is_some() {
+            // Keep polling the channel while a reply is streaming in.
+            ctx.request_repaint();
+        }
         // You can set a window title dynamically if you want:
         // frame.set_window_title("Indexedrag LLM Frontend");
         TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
+                ui.menu_button("Conversation", |ui| {
+                    if ui.button("Export Markdown…").clicked() {
+                        ui.close_menu();
+                        let path = rfd::FileDialog::new()
+                            .add_filter("Markdown", &["md"])
+                            .set_file_name(format!("{}.md", self.conversation.title))
+                            .save_file();
+                        if let Some(path) = path {
+                            if let Err(e) = self.export_conversation_markdown(&path) {
+                                self.report_error(e);
+                            }
+                        }
+                    }
+                    if ui.button("Export JSON…").clicked() {
+                        ui.close_menu();
+                        let path = rfd::FileDialog::new()
+                            .add_filter("JSON", &["json"])
+                            .set_file_name(format!("{}.json", self.conversation.title))
+                            .save_file();
+                        if let Some(path) = path {
+                            if let Err(e) = self.export_conversation_json(&path) {
+                                self.report_error(e);
+                            }
+                        }
+                    }
+                    let streaming = self.stream_rx.is_some();
+                    if ui
+                        .add_enabled(!streaming, egui::Button::new("Import JSON…"))
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let path = rfd::FileDialog::new()
+                            .add_filter("JSON", &["json"])
+                            .pick_file();
+                        if let Some(path) = path {
+                            if let Err(e) = self.import_conversation_json(&path) {
+                                self.report_error(e);
+                            }
+                        }
+                    }
+                    if ui
+                        .add_enabled(!streaming, egui::Button::new("Import ChatGPT export…"))
+                        .on_hover_text(
+                            "Import every conversation in the conversations.json of a \
+                             ChatGPT data export",
+                        )
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let path = rfd::FileDialog::new()
+                            .add_filter("ChatGPT export", &["json"])
+                            .pick_file();
+                        if let Some(path) = path {
+                            if let Err(e) = self.import_chatgpt_export(&path) {
+                                self.report_error(e);
+                            }
+                        }
+                    }
+                });
+                ui.menu_button("Backup", |ui| {
+                    let exporting = self.backup_export_rx.is_some();
+                    if ui
+                        .add_enabled(!exporting, egui::Button::new("Export backup…"))
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let path = rfd::FileDialog::new()
+                            .add_filter("indexedRAG backup", &["age"])
+                            .set_file_name("indexedRAG-backup.age")
+                            .save_file();
+                        if let Some(path) = path {
+                            self.backup_dialog = Some(BackupDialog {
+                                path,
+                                restore: false,
+                                passphrase: String::new(),
+                                confirmation: String::new(),
+                                include_keys: false,
+                            });
+                        }
+                    }
+                    // A reply or index run in flight would write into the replaced data, and
+                    // an export would copy it halfway through.
+                    let busy = self.stream_rx.is_some() || self.index_rx.is_some() || exporting;
+                    if ui
+                        .add_enabled(!busy, egui::Button::new("Restore backup…"))
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let path = rfd::FileDialog::new()
+                            .add_filter("indexedRAG backup", &["age"])
+                            .pick_file();
+                        if let Some(path) = path {
+                            self.backup_dialog = Some(BackupDialog {
+                                path,
+                                restore: true,
+                                passphrase: String::new(),
+                                confirmation: String::new(),
+                                include_keys: false,
+                            });
+                        }
+                    }
+                });
                 if ui.button("Settings").clicked() {
                     self.settings_open = !self.settings_open;
                 }
+                if ui.button("Logs").clicked() {
+                    if self.error_log.is_some() {
+                        self.error_log = None;
+                        self.error_log_refresh = None;
+                    } else {
+                        self.refresh_error_log();
+                    }
+                }
+                ui.separator();
+                self.draw_ui_scale(ui);
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    let last_indexed = match *self.last_indexed_at.lock().unwrap() {
+                        Some(at) => format_timestamp(at),
+                        None => "never".to_string(),
+                    };
+                    ui.small(format!("Last indexed: {}", last_indexed));
+                    self.draw_index_progress(ui);
+                });
             });
         });
+        self.handle_conversation_shortcuts(ctx);
+        self.draw_status_bar(ctx);
         SidePanel::left("side_panel").show(ctx, |ui| {
+            self.draw_document_search(ui);
+            ui.separator();
             ui.heading("Conversations");
+            self.draw_conversation_search(ui);
+            self.draw_starred(ui);
             ui.separator();
-            ui.label("Placeholder for threads list, etc.");
+            self.draw_conversation_list(ui);
         });
         CentralPanel::default().show(ctx, |ui| {
             ui.heading("Indexedrag");
+            self.draw_conversation_parent(ui);
+            self.draw_conversation_model(ui);
+            self.draw_conversation_scope(ui);
+            self.draw_conversation_tags(ui);
+            self.draw_conversation_summary(ui);
+            self.draw_conversation_size(ui);
             ui.separator();
             self.draw_conversation_ui(ui);
         });
@@ -363,18 +5666,432 @@ impl App for IndexedragApp {
                     self.draw_settings_ui(ui);
                 });
         }
+        self.draw_index_stats(ctx);
+        self.draw_clear_index_confirmation(ctx);
+        self.draw_large_index_run_confirmation(ctx);
+        self.draw_bulk_delete_confirmation(ctx);
+        self.draw_prompt_preview(ctx);
+        self.draw_large_prompt_confirmation(ctx);
+        self.draw_backup_dialog(ctx);
+        self.draw_error_log(ctx);
+        if let Some(message) = &self.error_message {
+            let mut dismissed = false;
+            egui::Window::new("Error")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label(message);
+                    dismissed = ui.button("Dismiss").clicked();
+                });
+            if dismissed {
+                self.error_message = None;
+            }
+        }
+    }
+}
+
+/// How the system note left by `prune_messages` ends, after the number of messages.
+const TRUNCATION_NOTE_SUFFIX: &str = " earlier message(s) were removed to fit the context limit.";
+
+/// The number of removed messages `message` reports, if it is a note left by
+/// `prune_messages`.
+fn truncation_note_count(message: &Message) -> Option<usize> {
+    if message.role != "system" {
+        return None;
+    }
+    message
+        .content
+        .strip_suffix(TRUNCATION_NOTE_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+/// Drop the oldest non-system messages until `messages` plus `pending_input` fit in
+/// `limit` tokens. System messages (including the system prompt) are kept, as is the
+/// message about to be sent. A single system note marks where history was removed;
+/// notes from earlier pruning are folded into it, so they don't pile up.
+fn prune_messages(messages: &mut Vec<Message>, limit: usize, pending_input: &str) {
+    let mut dropped = 0;
+    let mut earlier_note = None;
+    let mut i = 0;
+    while i < messages.len() {
+        match truncation_note_count(&messages[i]) {
+            Some(count) => {
+                dropped += count;
+                earlier_note.get_or_insert(i);
+                messages.remove(i);
+            }
+            None => i += 1,
+        }
+    }
+
+    let mut total = llm::estimate_tokens(pending_input)
+        + messages
+            .iter()
+            .map(|m| llm::estimate_tokens(&m.request_content()))
+            .sum::<usize>();
+    let mut truncation_point = None;
+    let mut i = 0;
+    while total > limit && i < messages.len() {
+        if messages[i].role == "system" {
+            i += 1;
+            continue;
+        }
+        total -= llm::estimate_tokens(&messages[i].request_content());
+        messages.remove(i);
+        truncation_point.get_or_insert(i);
+        dropped += 1;
+    }
+
+    if let Some(at) = truncation_point.or(earlier_note) {
+        messages.insert(
+            at,
+            Message::new("system", format!("{}{}", dropped, TRUNCATION_NOTE_SUFFIX)),
+        );
+    }
+}
+
+/// The messages left out of a request that sends only the last `window` messages,
+/// `pending` of which are not in `messages` yet; 0 leaves nothing out. A leading
+/// system message is always kept and not counted.
+fn outside_history_window(messages: &[Message], window: usize, pending: usize) -> Range<usize> {
+    let start = usize::from(messages.first().is_some_and(|m| m.role == "system"));
+    if window == 0 {
+        return start..start;
+    }
+    let end = (messages.len() + pending)
+        .saturating_sub(window)
+        .clamp(start, messages.len().max(start));
+    start..end
+}
+
+/// Add the retrieved `passages` to a request payload, just before the question.
+fn insert_context(messages: &mut Vec<Message>, passages: &[ScoredChunk]) {
+    if let Some(context) = retrieval::context_message(passages) {
+        messages.insert(messages.len().saturating_sub(1), context);
+    }
+}
+
+/// Make `system_prompt` the leading system message of a request payload, replacing
+/// whatever opening system message the conversation was created with.
+fn apply_system_prompt(messages: &mut Vec<Message>, system_prompt: &str) {
+    if system_prompt.trim().is_empty() {
+        return;
+    }
+    match messages.first_mut() {
+        Some(first) if first.role == "system" => first.content = system_prompt.to_string(),
+        _ => messages.insert(0, Message::new("system", system_prompt)),
+    }
+}
+
+/// The part of `text` around the first case-insensitive occurrence of `query`, or
+/// `None` if it doesn't occur.
+fn match_snippet(text: &str, query: &str) -> Option<String> {
+    const BEFORE: usize = 30;
+    const AFTER: usize = 60;
+    let query = query.to_lowercase();
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let start = chars
+        .iter()
+        .position(|(i, _)| text[*i..].to_lowercase().starts_with(&query))?;
+    let from = start.saturating_sub(BEFORE);
+    let to = (start + query.chars().count() + AFTER).min(chars.len());
+    let byte = |idx: usize| chars.get(idx).map_or(text.len(), |(i, _)| *i);
+    let mut snippet = text[byte(from)..byte(to)].replace('\n', " ");
+    if from > 0 {
+        snippet.insert(0, '…');
+    }
+    if to < chars.len() {
+        snippet.push('…');
+    }
+    Some(snippet)
+}
+
+/// A conversation title from the backend's reply: its first line without quotes or
+/// trailing punctuation, cut to `GENERATED_TITLE_MAX_CHARS`.
+fn generated_title(reply: &str) -> String {
+    let line = reply
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("");
+    let line = line
+        .trim_start_matches(['#', '*'])
+        .trim_matches(|c: char| matches!(c, '"' | '\'' | '*' | '.' | ' '));
+    line.chars().take(GENERATED_TITLE_MAX_CHARS).collect()
+}
+
+/// The first non-blank line of `text`, shortened to fit a side panel entry.
+fn message_preview(text: &str) -> String {
+    first_line(text, 80)
+}
+
+/// The first non-blank line of `text`, cut to `max_chars` with an ellipsis.
+fn first_line(text: &str, max_chars: usize) -> String {
+    let line = text
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("");
+    if line.chars().count() > max_chars {
+        let mut preview: String = line.chars().take(max_chars).collect();
+        preview.push('…');
+        preview
+    } else {
+        line.to_string()
+    }
+}
+
+/// Render a unix timestamp (seconds) in the local time zone.
+fn format_timestamp(unix_secs: i64) -> String {
+    chrono::DateTime::from_timestamp(unix_secs, 0)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// Normalise the line endings of pasted text, keeping tabs and newlines as they are,
+/// and take out the first paste longer than `LARGE_PASTE_CHARS` so it isn't inserted.
+fn intercept_paste(events: &mut Vec<egui::Event>) -> Option<String> {
+    let mut held = None;
+    events.retain_mut(|event| {
+        let egui::Event::Paste(text) = event else {
+            return true;
+        };
+        if text.contains('\r') {
+            *text = text.replace("\r\n", "\n").replace('\r', "\n");
+        }
+        if held.is_none() && text.chars().count() > LARGE_PASTE_CHARS {
+            held = Some(std::mem::take(text));
+            return false;
+        }
+        true
+    });
+    held
+}
+
+/// The box a message is drawn in, tinted by who wrote it so the transcript can be
+/// scanned at a glance: blue for the user, the plain panel colour for the assistant,
+/// amber for system messages.
+fn role_frame(role: &str, style: &egui::Style) -> egui::Frame {
+    let dark = style.visuals.dark_mode;
+    let (fill, stroke) = match (role, dark) {
+        ("user", true) => (
+            egui::Color32::from_rgb(30, 42, 60),
+            egui::Color32::from_rgb(60, 90, 130),
+        ),
+        ("user", false) => (
+            egui::Color32::from_rgb(225, 236, 250),
+            egui::Color32::from_rgb(150, 180, 220),
+        ),
+        ("system", true) => (
+            egui::Color32::from_rgb(50, 42, 26),
+            egui::Color32::from_rgb(110, 90, 50),
+        ),
+        ("system", false) => (
+            egui::Color32::from_rgb(250, 242, 222),
+            egui::Color32::from_rgb(210, 180, 120),
+        ),
+        _ => (
+            style.visuals.faint_bg_color,
+            style.visuals.widgets.noninteractive.bg_stroke.color,
+        ),
+    };
+    egui::Frame::group(style)
+        .fill(fill)
+        .stroke(egui::Stroke::new(1.0, stroke))
+}
+
+/// A function call the assistant asked for, in a box of its own below the reply text:
+/// the function's name and its arguments, pretty-printed when they are valid JSON.
+fn draw_tool_call(ui: &mut Ui, call: &llm::ToolCall) {
+    let arguments = serde_json::from_str::<serde_json::Value>(&call.arguments)
+        .and_then(|value| serde_json::to_string_pretty(&value))
+        .unwrap_or_else(|_| call.arguments.clone());
+    egui::Frame::group(ui.style())
+        .fill(ui.visuals().extreme_bg_color)
+        .show(ui, |ui| {
+            ui.set_width(ui.available_width());
+            ui.horizontal(|ui| {
+                ui.label(
+                    egui::RichText::new(format!("🔧 {}", call.name))
+                        .monospace()
+                        .strong(),
+                );
+                ui.label(
+                    egui::RichText::new("tool call, not executed")
+                        .small()
+                        .weak(),
+                );
+                if ui.small_button("Copy arguments").clicked() {
+                    ui.output_mut(|o| o.copied_text = arguments.clone());
+                }
+            });
+            if !arguments.trim().is_empty() {
+                ui.label(egui::RichText::new(&arguments).monospace());
+            }
+        });
+}
+
+/// A dollar amount with enough decimals to tell small per-reply costs apart.
+fn format_cost(usd: f64) -> String {
+    if usd < 1.0 {
+        format!("${:.4}", usd)
+    } else {
+        format!("${:.2}", usd)
+    }
+}
+
+/// Unix time of the newest message in `messages` that records when it was created.
+fn last_activity(messages: &[Message]) -> Option<i64> {
+    messages.iter().filter_map(|m| m.created_at).max()
+}
+
+/// Heading a conversation last active at `activity` is listed under, relative to
+/// `today`. Conversations without a known activity count as old.
+fn activity_group(activity: Option<i64>, today: chrono::NaiveDate) -> &'static str {
+    let Some(date) = activity
+        .and_then(|at| chrono::DateTime::from_timestamp(at, 0))
+        .map(|t| t.with_timezone(&chrono::Local).date_naive())
+    else {
+        return "Older";
+    };
+    match (today - date).num_days() {
+        ..=0 => "Today",
+        1 => "Yesterday",
+        2..=6 => "This week",
+        _ => "Older",
     }
 }
 
-fn main() {
-    let app = IndexedragApp::new();
-    let mut native_options = NativeOptions::default();
-    native_options.initial_window_size = Some(egui::vec2(1000.0, 800.0));
+/// Minimal app shown instead of the main window when startup fails, so users see
+/// why rather than a silent crash.
+struct StartupErrorApp {
+    message: String,
+}
+
+impl App for StartupErrorApp {
+    fn update(&mut self, ctx: &Context, frame: &mut Frame) {
+        CentralPanel::default().show(ctx, |ui| {
+            ui.heading("indexedRAG could not start");
+            ui.separator();
+            ui.label(&self.message);
+            ui.separator();
+            if ui.button("Quit").clicked() {
+                frame.close();
+            }
+        });
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = cli::Cli::parse();
+    let app = IndexedragApp::new(cli.db_path.clone()).map(|mut app| {
+        if let Some(backend) = cli.backend {
+            app.settings.backend = backend;
+        }
+        app
+    });
+    if cli.has_action() {
+        return match app {
+            Ok(mut app) => cli::run(&cli, &mut app),
+            Err(e) => {
+                eprintln!("Failed to initialize indexedRAG: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut native_options = NativeOptions {
+        initial_window_size: Some(egui::vec2(1000.0, 800.0)),
+        ..Default::default()
+    };
+
+    let app_creator: eframe::AppCreator = match app {
+        Ok(app) => {
+            if let Some(geometry) = app.window_geometry {
+                native_options.initial_window_size = Some(geometry.size);
+                native_options.initial_window_pos = geometry.position;
+            }
+            Box::new(|_cc| Box::new(app))
+        }
+        Err(e) => {
+            eprintln!("Failed to initialize indexedRAG: {}", e);
+            let message = e.to_string();
+            Box::new(|_cc| Box::new(StartupErrorApp { message }))
+        }
+    };
 
-    eframe::run_native(
+    if let Err(e) = eframe::run_native(
         // window title:
         "indexedRAG",
         native_options,
-        Box::new(|_cc| Box::new(app)),
-    );
+        app_creator,
+    ) {
+        eprintln!("Failed to start indexedRAG: {}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message of about `tokens` estimated tokens.
+    fn message(role: &str, tokens: usize) -> Message {
+        Message::new(role, "abcd".repeat(tokens))
+    }
+
+    #[test]
+    fn prune_messages_keeps_system_messages_and_marks_the_cut() {
+        let mut messages = vec![
+            message("system", 10),
+            message("user", 10),
+            message("assistant", 10),
+            message("user", 10),
+        ];
+        prune_messages(&mut messages, 35, "");
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "system", "assistant", "user"]);
+        assert_eq!(truncation_note_count(&messages[1]), Some(1));
+    }
+
+    #[test]
+    fn prune_messages_folds_earlier_notes_into_one() {
+        let mut messages = vec![
+            message("system", 10),
+            message("user", 10),
+            message("assistant", 10),
+            message("user", 10),
+        ];
+        prune_messages(&mut messages, 35, "");
+        messages.push(message("assistant", 10));
+        prune_messages(&mut messages, 35, "");
+        let notes: Vec<usize> = messages.iter().filter_map(truncation_note_count).collect();
+        assert_eq!(notes, [2]);
+        assert_eq!(messages.len(), 4);
+
+        // Nothing new to drop: the note stays where it was, with the same count.
+        prune_messages(&mut messages, 1000, "");
+        assert_eq!(truncation_note_count(&messages[1]), Some(2));
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn prune_messages_counts_attachments() {
+        let mut question = message("user", 1);
+        question.attachments.push(Attachment {
+            name: "notes.txt".to_string(),
+            content: "abcd".repeat(100),
+            truncated: false,
+        });
+        let mut messages = vec![question, message("assistant", 1), message("user", 1)];
+        prune_messages(&mut messages, 50, "");
+        assert_eq!(messages[0].role, "system");
+        assert!(messages.iter().all(|m| m.attachments.is_empty()));
+    }
 }
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::Engine;
+use eframe::egui::{self, text::LayoutJob, RichText, TextureHandle, Ui};
+use egui_commonmark::{CommonMarkCache, CommonMarkViewer};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+/// Largest image file loaded for display; bigger ones are shown as a link.
+const IMAGE_MAX_BYTES: usize = 20 * 1024 * 1024;
+/// Longest side of an image texture; larger images are scaled down.
+const IMAGE_MAX_SIDE: u32 = 2048;
+const IMAGE_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// File name endings of image links that are shown as images when they stand on a line
+/// of their own.
+const IMAGE_EXTENSIONS: [&str; 6] = [".png", ".jpg", ".jpeg", ".gif", ".webp", ".bmp"];
+
+/// A piece of a Markdown document: prose, a fenced code block or an image.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Text(String),
+    Code {
+        lang: String,
+        code: String,
+    },
+    /// `![alt](url)`, or an image URL on a line of its own. The URL may also be a
+    /// `data:` URI or a local path.
+    Image {
+        alt: String,
+        url: String,
+    },
+}
+
+/// Split `text` on fenced code blocks (``` or ~~~). An unterminated fence runs to
+/// the end of the text, which is what a half-streamed reply looks like.
+pub fn split_code_blocks(text: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut prose = String::new();
+    let mut fence: Option<(&str, String, String)> = None; // (marker, lang, code)
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        match &mut fence {
+            None => {
+                let marker = ["```", "~~~"].into_iter().find(|m| trimmed.starts_with(m));
+                if let Some(marker) = marker {
+                    if !prose.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut prose)));
+                    }
+                    let lang = trimmed.trim_start_matches(marker).trim().to_string();
+                    fence = Some((marker, lang, String::new()));
+                } else {
+                    prose.push_str(line);
+                }
+            }
+            Some((marker, _, code)) => {
+                if trimmed.trim_end() == *marker {
+                    let (_, lang, code) = fence.take().unwrap();
+                    segments.push(Segment::Code { lang, code });
+                } else {
+                    code.push_str(line);
+                }
+            }
+        }
+    }
+    if let Some((_, lang, code)) = fence {
+        segments.push(Segment::Code { lang, code });
+    }
+    if !prose.is_empty() {
+        segments.push(Segment::Text(prose));
+    }
+    segments
+}
+
+/// The contents of every fenced code block in `text`, separated by blank lines.
+pub fn extract_code(text: &str) -> String {
+    split_code_blocks(text)
+        .into_iter()
+        .filter_map(|segment| match segment {
+            Segment::Code { code, .. } => Some(code),
+            Segment::Text(_) | Segment::Image { .. } => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Split images out of `prose`: Markdown image syntax anywhere, and image URLs that
+/// make up a whole line. Everything else stays text.
+pub fn split_images(prose: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    for line in prose.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if is_image_url(trimmed) {
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            segments.push(Segment::Image {
+                alt: String::new(),
+                url: trimmed.to_string(),
+            });
+            continue;
+        }
+        let mut rest = line;
+        while let Some((before, alt, url, after)) = find_markdown_image(rest) {
+            text.push_str(before);
+            if !text.trim().is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            text.clear();
+            segments.push(Segment::Image {
+                alt: alt.to_string(),
+                url: url.to_string(),
+            });
+            rest = after;
+        }
+        text.push_str(rest);
+    }
+    if !text.trim().is_empty() {
+        segments.push(Segment::Text(text));
+    }
+    segments
+}
+
+/// Whether `text` shows any image outside its code blocks.
+pub fn contains_images(text: &str) -> bool {
+    split_code_blocks(text)
+        .into_iter()
+        .any(|segment| match segment {
+            Segment::Text(prose) => split_images(&prose)
+                .iter()
+                .any(|s| matches!(s, Segment::Image { .. })),
+            _ => false,
+        })
+}
+
+/// The first `![alt](url)` in `text` as (text before it, alt, url, text after it).
+/// A title after the URL is dropped.
+fn find_markdown_image(text: &str) -> Option<(&str, &str, &str, &str)> {
+    let start = text.find("![")?;
+    let alt_end = start + 2 + text[start + 2..].find("](")?;
+    let url_end = alt_end + 2 + text[alt_end + 2..].find(')')?;
+    let alt = &text[start + 2..alt_end];
+    let target = text[alt_end + 2..url_end].trim();
+    let url = target.split_whitespace().next()?;
+    let url = url.trim_start_matches('<').trim_end_matches('>');
+    Some((&text[..start], alt, url, &text[url_end + 1..]))
+}
+
+fn is_image_url(text: &str) -> bool {
+    if text.contains(char::is_whitespace) {
+        return false;
+    }
+    if text.starts_with("data:image/") {
+        return true;
+    }
+    let path = text.split(['?', '#']).next().unwrap_or(text).to_lowercase();
+    (path.starts_with("http://") || path.starts_with("https://"))
+        && IMAGE_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+/// Render `text` as Markdown. Fenced code blocks are drawn separately in monospace,
+/// highlighted when their language is recognised, with their own copy button. With
+/// `images`, the images it links to are loaded and shown inline.
+pub fn show(
+    ui: &mut Ui,
+    cache: &mut CommonMarkCache,
+    id: impl std::hash::Hash,
+    text: &str,
+    images: bool,
+) {
+    let id = egui::Id::new(id);
+    for (i, segment) in split_code_blocks(text).into_iter().enumerate() {
+        match segment {
+            Segment::Text(prose) if images => {
+                for (j, segment) in split_images(&prose).into_iter().enumerate() {
+                    match segment {
+                        Segment::Image { alt, url } => show_image(ui, &alt, &url),
+                        Segment::Text(prose) => {
+                            CommonMarkViewer::new(id.with((i, j))).show(ui, cache, &prose);
+                        }
+                        Segment::Code { .. } => {}
+                    }
+                }
+            }
+            Segment::Text(prose) => {
+                CommonMarkViewer::new(id.with(i)).show(ui, cache, &prose);
+            }
+            Segment::Image { alt, url } => show_image(ui, &alt, &url),
+            Segment::Code { lang, code } => {
+                egui::Frame::group(ui.style())
+                    .fill(ui.visuals().extreme_bg_color)
+                    .show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            if !lang.is_empty() {
+                                ui.label(RichText::new(&lang).small().weak());
+                            }
+                            if ui.small_button("Copy").clicked() {
+                                ui.output_mut(|o| o.copied_text = code.clone());
+                            }
+                        });
+                        let mut layouter = |ui: &Ui, text: &str, wrap_width: f32| {
+                            let font_size = egui::TextStyle::Monospace.resolve(ui.style()).size;
+                            let mut job = ui.memory_mut(|m| {
+                                m.caches.cache::<HighlightCache>().get((
+                                    lang.as_str(),
+                                    text,
+                                    font_size.to_bits(),
+                                    ui.visuals().dark_mode,
+                                ))
+                            });
+                            job.wrap.max_width = wrap_width;
+                            ui.fonts(|f| f.layout_job(job))
+                        };
+                        // A read-only text edit, so part of the code can be selected.
+                        ui.add(
+                            egui::TextEdit::multiline(&mut code.trim_end_matches('\n'))
+                                .code_editor()
+                                .frame(false)
+                                .desired_rows(1)
+                                .desired_width(f32::INFINITY)
+                                .layouter(&mut layouter),
+                        );
+                    });
+            }
+        }
+    }
+}
+
+/// Draw the image at `url`, fitted to the available width. It is loaded in the
+/// background the first time; until then, or if that fails, a placeholder is shown.
+fn show_image(ui: &mut Ui, alt: &str, url: &str) {
+    let images = ui.memory_mut(|m| {
+        m.data
+            .get_temp_mut_or_default::<ImageCache>(egui::Id::new("markdown_images"))
+            .clone()
+    });
+    let state = images.get_or_load(ui.ctx(), url);
+    let label = if alt.is_empty() { url } else { alt };
+    match state {
+        ImageState::Loaded(texture) => {
+            let size = texture.size_vec2();
+            let scale = (ui.available_width() / size.x).min(1.0);
+            let response = ui.image(&texture, size * scale);
+            if !alt.is_empty() {
+                response.on_hover_text(alt);
+            }
+        }
+        ImageState::Loading => {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.weak(format!("Loading image {}", label));
+            });
+        }
+        ImageState::Failed(error) => {
+            ui.weak(format!("🖼 {}", label))
+                .on_hover_text(format!("Could not load the image: {}", error));
+        }
+    }
+}
+
+#[derive(Clone)]
+enum ImageState {
+    Loading,
+    Loaded(TextureHandle),
+    Failed(String),
+}
+
+/// Images shown in messages, by URL. Kept in egui's memory for the whole session, so
+/// each one is loaded only once.
+#[derive(Clone, Default)]
+struct ImageCache {
+    images: Arc<Mutex<HashMap<String, ImageState>>>,
+}
+
+impl ImageCache {
+    /// The state of the image at `url`, starting to load it if it's new.
+    fn get_or_load(&self, ctx: &egui::Context, url: &str) -> ImageState {
+        let mut images = self.images.lock().unwrap();
+        if let Some(state) = images.get(url) {
+            return state.clone();
+        }
+        images.insert(url.to_string(), ImageState::Loading);
+        let (images, ctx, url) = (Arc::clone(&self.images), ctx.clone(), url.to_string());
+        std::thread::spawn(move || {
+            let state = match load_image(&url) {
+                Ok(image) => ImageState::Loaded(ctx.load_texture(
+                    url.as_str(),
+                    image,
+                    egui::TextureOptions::LINEAR,
+                )),
+                Err(e) => ImageState::Failed(e),
+            };
+            images.lock().unwrap().insert(url, state);
+            ctx.request_repaint();
+        });
+        ImageState::Loading
+    }
+}
+
+/// Read and decode the image at `url`: a `data:` URI, an http(s) URL or a local file.
+fn load_image(url: &str) -> Result<egui::ColorImage, String> {
+    let bytes = if let Some(data) = url.strip_prefix("data:") {
+        let (header, payload) = data.split_once(',').ok_or("malformed data URI")?;
+        if !header.ends_with(";base64") {
+            return Err("only base64 data URIs are supported".to_string());
+        }
+        base64::engine::general_purpose::STANDARD
+            .decode(payload.trim())
+            .map_err(|e| e.to_string())?
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        let response = reqwest::blocking::Client::builder()
+            .timeout(IMAGE_FETCH_TIMEOUT)
+            .build()
+            .and_then(|client| client.get(url).send())
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?;
+        if response
+            .content_length()
+            .is_some_and(|len| len > IMAGE_MAX_BYTES as u64)
+        {
+            return Err("the image is too large".to_string());
+        }
+        response.bytes().map_err(|e| e.to_string())?.to_vec()
+    } else {
+        let path = url.strip_prefix("file://").unwrap_or(url);
+        std::fs::read(path).map_err(|e| e.to_string())?
+    };
+    if bytes.len() > IMAGE_MAX_BYTES {
+        return Err("the image is too large".to_string());
+    }
+    let mut image = image::load_from_memory(&bytes).map_err(|e| e.to_string())?;
+    if image.width() > IMAGE_MAX_SIDE || image.height() > IMAGE_MAX_SIDE {
+        image = image.thumbnail(IMAGE_MAX_SIDE, IMAGE_MAX_SIDE);
+    }
+    let size = [image.width() as usize, image.height() as usize];
+    Ok(egui::ColorImage::from_rgba_unmultiplied(
+        size,
+        image.to_rgba8().as_flat_samples().as_slice(),
+    ))
+}
+
+type HighlightCache = egui::util::cache::FrameCache<LayoutJob, Highlighter>;
+
+/// Colours code with syntect, picking the syntax from a fence's info string.
+struct Highlighter {
+    syntaxes: SyntaxSet,
+    themes: ThemeSet,
+}
+
+impl Default for Highlighter {
+    fn default() -> Self {
+        Highlighter {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            themes: ThemeSet::load_defaults(),
+        }
+    }
+}
+
+/// Key: (fence info string, code, monospace font size as bits, dark mode).
+impl egui::util::cache::ComputerMut<(&str, &str, u32, bool), LayoutJob> for Highlighter {
+    fn compute(
+        &mut self,
+        (lang, code, font_size, dark_mode): (&str, &str, u32, bool),
+    ) -> LayoutJob {
+        let font_id = egui::FontId::monospace(f32::from_bits(font_size));
+        self.highlight(lang, code, &font_id, dark_mode)
+            .unwrap_or_else(|| {
+                // Unknown language: plain monospace.
+                let color = if dark_mode {
+                    egui::Color32::LIGHT_GRAY
+                } else {
+                    egui::Color32::DARK_GRAY
+                };
+                LayoutJob::simple(code.to_string(), font_id, color, f32::INFINITY)
+            })
+    }
+}
+
+impl Highlighter {
+    fn highlight(
+        &self,
+        lang: &str,
+        code: &str,
+        font_id: &egui::FontId,
+        dark_mode: bool,
+    ) -> Option<LayoutJob> {
+        // Info strings may carry more than the language, e.g. "rust,ignore".
+        let token = lang
+            .split(|c: char| c == ',' || c == '{' || c.is_whitespace())
+            .next()?;
+        let syntax = self.syntaxes.find_syntax_by_token(token)?;
+        let theme = if dark_mode {
+            "base16-mocha.dark"
+        } else {
+            "InspiredGitHub"
+        };
+        let mut highlighter = HighlightLines::new(syntax, &self.themes.themes[theme]);
+        let mut job = LayoutJob::default();
+        for line in LinesWithEndings::from(code) {
+            for (style, range) in highlighter.highlight_line(line, &self.syntaxes).ok()? {
+                let fg = style.foreground;
+                job.append(
+                    range,
+                    0.0,
+                    egui::TextFormat {
+                        font_id: font_id.clone(),
+                        color: egui::Color32::from_rgb(fg.r, fg.g, fg.b),
+                        italics: style.font_style.contains(FontStyle::ITALIC),
+                        underline: if style.font_style.contains(FontStyle::UNDERLINE) {
+                            egui::Stroke::new(1.0, egui::Color32::from_rgb(fg.r, fg.g, fg.b))
+                        } else {
+                            egui::Stroke::NONE
+                        },
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+        Some(job)
+    }
+}
@@ -0,0 +1,350 @@
+use std::path::{Path, PathBuf};
+
+use rusqlite::{Connection, DatabaseName};
+
+use crate::error::AppError;
+
+/// How many copies taken before migrating are kept next to the database; older ones
+/// are deleted.
+const KEPT_MIGRATION_BACKUPS: usize = 3;
+
+/// Ordered schema migrations. Entry `i` upgrades a database from version `i` to
+/// `i + 1`; the current version is kept in `PRAGMA user_version`. Never edit a
+/// migration that has shipped - append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE IF NOT EXISTS settings (
+        id INTEGER PRIMARY KEY,
+        root_paths TEXT NOT NULL,
+        index_interval_minutes INTEGER NOT NULL,
+        model TEXT NOT NULL DEFAULT 'gpt-4o-mini',
+        api_key TEXT NOT NULL DEFAULT '',
+        ollama_url TEXT NOT NULL DEFAULT 'http://localhost:11434',
+        ollama_model TEXT NOT NULL DEFAULT 'llama3',
+        backend TEXT NOT NULL DEFAULT 'ollama',
+        embedding_model TEXT NOT NULL DEFAULT 'nomic-embed-text',
+        retrieval_top_k INTEGER NOT NULL DEFAULT 4
+    );
+    CREATE TABLE IF NOT EXISTS conversation (
+        id INTEGER PRIMARY KEY,
+        title TEXT NOT NULL DEFAULT 'New conversation',
+        messages TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS documents (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL,
+        chunk_index INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        embedding BLOB,
+        embedding_dim INTEGER
+    );",
+    // 2: unsent input per conversation
+    "ALTER TABLE conversation ADD COLUMN draft TEXT NOT NULL DEFAULT '';",
+    // 3: history of completed index runs
+    "CREATE TABLE index_runs (
+        id INTEGER PRIMARY KEY,
+        finished_at INTEGER NOT NULL,
+        files_indexed INTEGER NOT NULL,
+        chunks INTEGER NOT NULL
+    );",
+    // 4: full-text index over document chunks, kept in sync by triggers
+    "CREATE VIRTUAL TABLE documents_fts USING fts5(
+        content,
+        content='documents',
+        content_rowid='id'
+    );
+    CREATE TRIGGER documents_fts_insert AFTER INSERT ON documents BEGIN
+        INSERT INTO documents_fts(rowid, content) VALUES (new.id, new.content);
+    END;
+    CREATE TRIGGER documents_fts_delete AFTER DELETE ON documents BEGIN
+        INSERT INTO documents_fts(documents_fts, rowid, content)
+        VALUES ('delete', old.id, old.content);
+    END;
+    CREATE TRIGGER documents_fts_update AFTER UPDATE OF content ON documents BEGIN
+        INSERT INTO documents_fts(documents_fts, rowid, content)
+        VALUES ('delete', old.id, old.content);
+        INSERT INTO documents_fts(rowid, content) VALUES (new.id, new.content);
+    END;
+    INSERT INTO documents_fts(documents_fts) VALUES ('rebuild');",
+    // 5: user-configurable system prompt
+    "ALTER TABLE settings ADD COLUMN system_prompt TEXT NOT NULL DEFAULT '';",
+    // 6: sampling controls
+    "ALTER TABLE settings ADD COLUMN temperature REAL NOT NULL DEFAULT 0.7;
+    ALTER TABLE settings ADD COLUMN max_tokens INTEGER NOT NULL DEFAULT 1024;",
+    // 7: model context window, in tokens
+    "ALTER TABLE settings ADD COLUMN context_limit_tokens INTEGER NOT NULL DEFAULT 8192;",
+    // 8: main window size and position from the last session
+    "CREATE TABLE window_geometry (
+        id INTEGER PRIMARY KEY,
+        x REAL,
+        y REAL,
+        width REAL NOT NULL,
+        height REAL NOT NULL
+    );",
+    // 9: retries for transient request failures
+    "ALTER TABLE settings ADD COLUMN max_retries INTEGER NOT NULL DEFAULT 3;",
+    // 10: which files the indexer picks up
+    "ALTER TABLE settings ADD COLUMN allowed_extensions TEXT NOT NULL DEFAULT
+        '[\"txt\",\"md\",\"markdown\",\"rst\",\"org\",\"rs\",\"py\",\"js\",\"ts\",\"java\",\"kt\",\"go\",\"c\",\"h\",
+          \"cpp\",\"hpp\",\"cs\",\"rb\",\"php\",\"sh\",\"toml\",\"yaml\",\"yml\",\"json\",\"html\",\"css\",\"sql\"]';
+    ALTER TABLE settings ADD COLUMN max_file_size_bytes INTEGER NOT NULL DEFAULT 1048576;",
+    // 11: skip files excluded by .gitignore and friends
+    "ALTER TABLE settings ADD COLUMN respect_gitignore INTEGER NOT NULL DEFAULT 1;",
+    // 12: PDF support - page numbers for chunks, and PDFs indexed by default
+    "ALTER TABLE documents ADD COLUMN page INTEGER;
+    UPDATE settings SET allowed_extensions = json_insert(allowed_extensions, '$[#]', 'pdf')
+        WHERE json_valid(allowed_extensions) AND json_array_length(allowed_extensions) > 0;",
+    // 13: chunk hashes and file modification times, so unchanged chunks can be reused
+    "ALTER TABLE documents ADD COLUMN content_hash TEXT;
+    ALTER TABLE documents ADD COLUMN file_mtime INTEGER;
+    CREATE INDEX documents_content_hash ON documents(content_hash);",
+    // 14: files seen by the last index run, for incremental re-indexing
+    "CREATE TABLE files (
+        path TEXT PRIMARY KEY,
+        mtime INTEGER NOT NULL
+    );
+    CREATE INDEX documents_path ON documents(path);",
+    // 15: conversation to reopen on the next start
+    "ALTER TABLE settings ADD COLUMN last_opened_conversation_id INTEGER;",
+    // 16: configurable chunking; earlier runs used fixed 1000-character chunks
+    "ALTER TABLE settings ADD COLUMN chunk_size_chars INTEGER NOT NULL DEFAULT 1000;
+    ALTER TABLE settings ADD COLUMN chunk_overlap_chars INTEGER NOT NULL DEFAULT 200;
+    ALTER TABLE index_runs ADD COLUMN chunk_size_chars INTEGER NOT NULL DEFAULT 1000;
+    ALTER TABLE index_runs ADD COLUMN chunk_overlap_chars INTEGER NOT NULL DEFAULT 0;",
+    // 17: llama.cpp server backend
+    "ALTER TABLE settings ADD COLUMN llamacpp_url TEXT NOT NULL DEFAULT 'http://localhost:8080';",
+    // 18: Anthropic backend; its API key lives in the OS keyring
+    "ALTER TABLE settings ADD COLUMN anthropic_model TEXT NOT NULL DEFAULT 'claude-3-5-sonnet-latest';",
+    // 19: per-attempt timeout for backend requests
+    "ALTER TABLE settings ADD COLUMN request_timeout_seconds INTEGER NOT NULL DEFAULT 120;",
+    // 20: per-conversation model override
+    "ALTER TABLE conversation ADD COLUMN model TEXT;",
+    // 21: failures shown in the error log window
+    "CREATE TABLE error_log (
+        id INTEGER PRIMARY KEY,
+        logged_at INTEGER NOT NULL,
+        context TEXT NOT NULL,
+        message TEXT NOT NULL
+    );",
+    // 22: when a conversation last got a message, for grouping the side panel
+    "ALTER TABLE conversation ADD COLUMN last_activity INTEGER;",
+    // 23: similarity a retrieved chunk needs to be passed to the model
+    "ALTER TABLE settings ADD COLUMN min_similarity REAL NOT NULL DEFAULT 0.0;",
+    // 24: conversation a fork was copied from
+    "ALTER TABLE conversation ADD COLUMN parent_id INTEGER;",
+    // 25: prices for estimating what replies from paid backends cost
+    "ALTER TABLE settings ADD COLUMN prompt_price_per_million REAL NOT NULL DEFAULT 0.15;
+    ALTER TABLE settings ADD COLUMN completion_price_per_million REAL NOT NULL DEFAULT 0.6;",
+    // 26: prompt size above which sending asks for confirmation
+    "ALTER TABLE settings ADD COLUMN max_prompt_tokens INTEGER NOT NULL DEFAULT 16000;",
+    // 27: OpenAI-compatible providers other than OpenAI
+    "ALTER TABLE settings ADD COLUMN openai_base_url TEXT NOT NULL DEFAULT 'https://api.openai.com/v1';",
+    // 28: zoom level of the interface
+    "ALTER TABLE settings ADD COLUMN ui_scale REAL NOT NULL DEFAULT 1.0;",
+    // 29: labels for organizing conversations, as a JSON array
+    "ALTER TABLE conversation ADD COLUMN tags TEXT NOT NULL DEFAULT '[]';",
+    // 30: backend-written summary of a conversation
+    "ALTER TABLE conversation ADD COLUMN summary TEXT;",
+    // 31: titles the user chose, which automatic titles must not replace
+    "ALTER TABLE conversation ADD COLUMN title_manual INTEGER NOT NULL DEFAULT 0;",
+    // 32: root paths a conversation retrieves from, as a JSON array; empty means all
+    "ALTER TABLE conversation ADD COLUMN scope TEXT NOT NULL DEFAULT '[]';",
+    // 33: how many of the latest messages are sent with a question; 0 sends all
+    "ALTER TABLE settings ADD COLUMN history_window INTEGER NOT NULL DEFAULT 0;",
+    // 34: files the last index run could not index, and why
+    "CREATE TABLE index_errors (
+        path TEXT PRIMARY KEY,
+        reason TEXT NOT NULL,
+        failed_at INTEGER NOT NULL
+    );",
+    // 35: encoding a non-UTF-8 file was decoded with when it had to be guessed
+    "ALTER TABLE documents ADD COLUMN guessed_encoding TEXT;",
+    // 36: proxy for backend requests; empty uses the proxy environment variables
+    "ALTER TABLE settings ADD COLUMN proxy_url TEXT NOT NULL DEFAULT '';
+    ALTER TABLE settings ADD COLUMN no_proxy TEXT NOT NULL DEFAULT 'localhost, 127.0.0.1, ::1';",
+    // 37: index the stored conversations along with the files
+    "ALTER TABLE settings ADD COLUMN index_conversations INTEGER NOT NULL DEFAULT 0;",
+    // 38: have the backend rewrite questions before they are embedded for retrieval
+    "ALTER TABLE settings ADD COLUMN expand_queries INTEGER NOT NULL DEFAULT 0;",
+    // 39: replace likely secrets in typed messages before they are stored or sent
+    "ALTER TABLE settings ADD COLUMN redact_secrets INTEGER NOT NULL DEFAULT 0;",
+    // 40: embedding rate limit and price, for keeping large index runs affordable
+    "ALTER TABLE settings ADD COLUMN embeddings_per_minute INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE settings ADD COLUMN embedding_price_per_million REAL NOT NULL DEFAULT 0.02;",
+    // 41: local embeddings used to hash words differently from one build to the next;
+    // drop the stored ones so the next index run embeds those chunks again
+    "UPDATE documents SET embedding = NULL, embedding_dim = NULL WHERE embedding_dim = 256;",
+    // 42: the embedding model that computed each chunk's vector, so a chunk's text is
+    // only embedded again when the model changes
+    "ALTER TABLE documents ADD COLUMN embedding_model TEXT;",
+];
+
+/// Columns that were added to unversioned databases before migrations existed.
+/// Such databases report version 0 but may already contain some of the tables.
+const LEGACY_COLUMNS: &[(&str, &str, &str)] = &[
+    ("settings", "model", "TEXT NOT NULL DEFAULT 'gpt-4o-mini'"),
+    ("settings", "api_key", "TEXT NOT NULL DEFAULT ''"),
+    (
+        "settings",
+        "ollama_url",
+        "TEXT NOT NULL DEFAULT 'http://localhost:11434'",
+    ),
+    ("settings", "ollama_model", "TEXT NOT NULL DEFAULT 'llama3'"),
+    ("settings", "backend", "TEXT NOT NULL DEFAULT 'ollama'"),
+    (
+        "settings",
+        "embedding_model",
+        "TEXT NOT NULL DEFAULT 'nomic-embed-text'",
+    ),
+    ("settings", "retrieval_top_k", "INTEGER NOT NULL DEFAULT 4"),
+    (
+        "conversation",
+        "title",
+        "TEXT NOT NULL DEFAULT 'New conversation'",
+    ),
+    ("documents", "embedding", "BLOB"),
+    ("documents", "embedding_dim", "INTEGER"),
+];
+
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// The schema version this build of the application expects.
+pub fn latest_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Bring the database schema up to date, applying each pending migration in its
+/// own transaction.
+pub fn migrate(conn: &Connection) -> Result<(), AppError> {
+    let version = schema_version(conn)?;
+    if version > latest_version() {
+        return Err(AppError::SchemaTooNew {
+            found: version,
+            supported: latest_version(),
+        });
+    }
+    if version == 0 {
+        adopt_legacy_schema(conn)?;
+    }
+
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// `migrate` the database behind `conn`, stored at `db_path`, after copying it to
+/// `<db_path>.bak-<version>` when there is anything to migrate. If a migration fails,
+/// the copy is restored and the error returned as `AppError::MigrationFailed`. Only
+/// the newest `KEPT_MIGRATION_BACKUPS` copies are kept. A new, empty database isn't
+/// copied.
+pub fn migrate_with_backup(conn: &mut Connection, db_path: &Path) -> Result<(), AppError> {
+    let version = schema_version(conn)?;
+    let is_empty = conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| {
+        row.get::<_, i64>(0)
+    })? == 0;
+    if version >= latest_version() || is_empty {
+        return migrate(conn);
+    }
+
+    let backup = backup_path(db_path, version);
+    // VACUUM INTO won't overwrite, and unlike copying the file it includes the WAL.
+    match std::fs::remove_file(&backup) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    conn.execute("VACUUM INTO ?1", [backup.to_string_lossy().into_owned()])?;
+    prune_backups(db_path);
+
+    migrate(conn).map_err(|source| {
+        let restore_error = conn
+            .restore(
+                DatabaseName::Main,
+                &backup,
+                None::<fn(rusqlite::backup::Progress)>,
+            )
+            .err()
+            .map(|e| e.to_string());
+        AppError::MigrationFailed {
+            backup,
+            source: Box::new(source),
+            restore_error,
+        }
+    })
+}
+
+/// Where the copy of `db_path` taken before migrating from `version` goes.
+fn backup_path(db_path: &Path, version: usize) -> PathBuf {
+    let mut name = db_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".bak-{}", version));
+    db_path.with_file_name(name)
+}
+
+/// Delete all but the newest `KEPT_MIGRATION_BACKUPS` pre-migration copies of
+/// `db_path`. Copies that can't be listed or removed are left alone.
+fn prune_backups(db_path: &Path) {
+    let Some(dir) = db_path.parent() else {
+        return;
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let prefix = format!(
+        "{}.bak-",
+        db_path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut backups: Vec<(usize, PathBuf)> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let version = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix(&prefix)?
+                .parse()
+                .ok()?;
+            Some((version, path))
+        })
+        .collect();
+    backups.sort_by_key(|(version, _)| std::cmp::Reverse(*version));
+    for (_, path) in backups.into_iter().skip(KEPT_MIGRATION_BACKUPS) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+fn table_exists(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count > 0)
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> bool {
+    conn.prepare(&format!("SELECT {} FROM {} LIMIT 0", column, table))
+        .is_ok()
+}
+
+/// Patch tables created by pre-migration builds so that they match the shape the
+/// first migration would have created.
+fn adopt_legacy_schema(conn: &Connection) -> rusqlite::Result<()> {
+    for (table, column, definition) in LEGACY_COLUMNS {
+        if table_exists(conn, table)? && !column_exists(conn, table, column) {
+            conn.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::path::Path;
+
+use rusqlite::{Connection, OptionalExtension, Row};
+
+use crate::ann::AnnIndex;
+use crate::indexer::blob_to_embedding;
+use crate::{Message, Source};
+
+/// How many more candidates the nearest-neighbour graph is asked for when retrieval
+/// is limited to some folders or leaves a document out, as most of its hits may be
+/// dropped.
+const SCOPED_ANN_OVERFETCH: usize = 10;
+
+/// A stored chunk together with its similarity to the query.
+#[derive(Debug, Clone)]
+pub struct ScoredChunk {
+    pub content: String,
+    pub score: f32,
+    pub source: Source,
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Brute-force search: compare `query` against every stored chunk embedding of the
+/// same dimension and return the `k` most similar chunks, best first. Chunks scoring
+/// below `min_score` are left out even if that returns fewer than `k`, and so are
+/// chunks from files outside `scope`, unless it is empty, and chunks of `skip_path`.
+pub fn top_k_chunks(
+    conn: &Connection,
+    query: &[f32],
+    k: usize,
+    min_score: f32,
+    scope: &[String],
+    skip_path: Option<&str>,
+) -> rusqlite::Result<Vec<ScoredChunk>> {
+    if query.is_empty() || k == 0 {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT content, embedding, embedding_dim, path, chunk_index, page FROM documents
+         WHERE embedding IS NOT NULL AND embedding_dim = ?1",
+    )?;
+    let rows = stmt.query_map([query.len() as i64], chunk_row)?;
+
+    let mut scored = Vec::new();
+    for row in rows {
+        let row = row?;
+        if wanted(&row.3.path, scope, skip_path) {
+            scored.extend(score_chunk(query, row, min_score));
+        }
+    }
+    scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+    scored.truncate(k);
+    Ok(scored)
+}
+
+/// Like `top_k_chunks`, but only the candidates found by `ann` are scored. This stays
+/// fast on large indexes at the cost of occasionally missing one of the best chunks.
+pub fn ann_top_k_chunks(
+    conn: &Connection,
+    ann: &AnnIndex,
+    query: &[f32],
+    k: usize,
+    min_score: f32,
+    scope: &[String],
+    skip_path: Option<&str>,
+) -> rusqlite::Result<Vec<ScoredChunk>> {
+    if query.len() != ann.dim() || k == 0 {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT content, embedding, embedding_dim, path, chunk_index, page FROM documents
+         WHERE id = ?1 AND embedding IS NOT NULL",
+    )?;
+    let candidates = if scope.is_empty() && skip_path.is_none() {
+        k
+    } else {
+        k * SCOPED_ANN_OVERFETCH
+    };
+    let mut scored = Vec::new();
+    for id in ann.search(query, candidates) {
+        // Chunks removed by a later index run are no longer in the table.
+        if let Some(row) = stmt.query_row([id], chunk_row).optional()? {
+            if wanted(&row.3.path, scope, skip_path) {
+                scored.extend(score_chunk(query, row, min_score));
+            }
+        }
+    }
+    scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+    scored.truncate(k);
+    Ok(scored)
+}
+
+/// Whether the file at `path` lies under one of the folders in `scope`, where
+/// everything does when `scope` is empty, and isn't `skip_path`.
+fn wanted(path: &str, scope: &[String], skip_path: Option<&str>) -> bool {
+    skip_path != Some(path)
+        && (scope.is_empty() || scope.iter().any(|root| Path::new(path).starts_with(root)))
+}
+
+type ChunkRow = (String, Vec<u8>, i64, Source);
+
+/// Read (content, embedding, embedding_dim, source) from a `documents` row selected as
+/// in `top_k_chunks`.
+fn chunk_row(row: &Row) -> rusqlite::Result<ChunkRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        Source {
+            path: row.get(3)?,
+            chunk_index: row.get(4)?,
+            page: row.get(5)?,
+            score: None,
+            content: None,
+        },
+    ))
+}
+
+fn score_chunk(query: &[f32], row: ChunkRow, min_score: f32) -> Option<ScoredChunk> {
+    let (content, blob, dim, source) = row;
+    let embedding = blob_to_embedding(&blob, dim as usize)?;
+    let score = cosine_similarity(query, &embedding);
+    (score >= min_score).then_some(ScoredChunk {
+        content,
+        score,
+        source,
+    })
+}
+
+/// Turn free-form user input into an FTS5 query: every word is quoted so that
+/// punctuation can't be misread as query syntax, and all words must match.
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Keyword search over the indexed chunks. Returns (path, snippet) pairs, best match
+/// first, with matched terms wrapped in `**`.
+pub fn full_text_search(
+    conn: &Connection,
+    query: &str,
+    limit: usize,
+) -> rusqlite::Result<Vec<(String, String)>> {
+    let query = fts_query(query);
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT d.path, snippet(documents_fts, 0, '**', '**', '…', 12)
+         FROM documents_fts
+         JOIN documents d ON d.id = documents_fts.rowid
+         WHERE documents_fts MATCH ?1
+         ORDER BY rank
+         LIMIT ?2",
+    )?;
+    let rows = stmt.query_map(rusqlite::params![query, limit as i64], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    rows.collect()
+}
+
+/// Build the system message that carries retrieved passages, or `None` when there is
+/// nothing to inject. Passages are explicitly delimited so the model can tell them
+/// apart from the user's question.
+pub fn context_message(passages: &[ScoredChunk]) -> Option<Message> {
+    if passages.is_empty() {
+        return None;
+    }
+    let mut content = String::from(
+        "Use the following passages retrieved from the user's indexed files to answer \
+         the question that follows. Ignore them if they are not relevant.\n",
+    );
+    for (i, passage) in passages.iter().enumerate() {
+        content.push_str(&format!(
+            "\n--- Retrieved passage {} ---\n{}\n",
+            i + 1,
+            passage.content.trim()
+        ));
+    }
+    content.push_str("\n--- End of retrieved context ---");
+    Some(Message::new("system", content))
+}